@@ -0,0 +1,309 @@
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::request::Request;
+
+// An address or a network like 10.0.0.0/8 and fd00::/8
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8
+}
+
+impl Cidr {
+
+    pub fn parse(text: &str) -> Option<Cidr> {
+
+        let (address, prefix) = match text.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (text, None)
+        };
+        let network = address.parse::<IpAddr>().ok()?.to_canonical();
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= max)?,
+            None => max
+        };
+        Some(Cidr {
+            network,
+            prefix
+        })
+
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false
+        }
+    }
+
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+// Clients of a server or location by their address
+#[derive(Debug, Clone, Default)]
+pub struct Access {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>
+}
+
+impl Access {
+
+    // The rule refusing `ip`: a matching "deny" comes first,
+    // then with an "allow" list the address has to be in it
+    pub fn refuse(&self, ip: Option<IpAddr>) -> Option<String> {
+
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return None;
+        }
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return Some(String::from("unknown client address"))
+        };
+        if let Some(rule) = self.deny.iter().find(|rule| rule.contains(ip)) {
+            return Some(format!("deny {} for {}", rule, ip));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|rule| rule.contains(ip)) {
+            return Some(format!("not in allow for {}", ip));
+        }
+        None
+
+    }
+
+}
+
+// The address of the client. Behind a trusted proxy it's the last address
+// of X-Forwarded-For that isn't one of the proxies
+pub fn client(request: &Request, proxies: &[Cidr], proxy_protocol: bool) -> Option<IpAddr> {
+
+    let peer = request.peer?.to_canonical();
+    let trusted = |ip: IpAddr| proxies.iter().any(|proxy| proxy.contains(ip));
+    if proxy_protocol || !trusted(peer) {
+        return Some(peer);
+    }
+    let forwarded = match request.headers.get("x-forwarded-for") {
+        Some(forwarded) => forwarded,
+        None => return Some(peer)
+    };
+
+    let mut client = peer;
+    for address in forwarded.rsplit(',') {
+        match address.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                client = ip.to_canonical();
+                if !trusted(client) {
+                    break;
+                }
+            },
+            // Whatever is in front of it can't be told apart from a forgery
+            Err(_) => break
+        }
+    }
+    Some(client)
+
+}
+
+const V2_SIGNATURE: [u8; 12] = [13, 10, 13, 10, 0, 13, 10, 81, 85, 73, 84, 10];
+// "PROXY " and the longest line of version 1
+const V1_START: &[u8] = b"PROXY ";
+const V1_MAX: usize = 107;
+
+#[derive(Debug, PartialEq)]
+pub enum Header {
+    // At least this many bytes in total are needed
+    Incomplete(usize),
+    // Its length and the address of the client, None for a health check of the proxy
+    Done(usize, Option<IpAddr>)
+}
+
+// The PROXY protocol header at the start of a connection, version 1 or 2
+pub fn parse(buf: &[u8]) -> Result<Header, ()> {
+
+    let start = buf.len().min(V1_START.len());
+    if buf[..start] == V1_START[..start] {
+        return parse_v1(buf);
+    }
+    let start = buf.len().min(V2_SIGNATURE.len());
+    if buf[..start] == V2_SIGNATURE[..start] {
+        return parse_v2(buf);
+    }
+    Err(())
+
+}
+
+// "PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n"
+fn parse_v1(buf: &[u8]) -> Result<Header, ()> {
+
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() < V1_MAX => return Ok(Header::Incomplete(buf.len() + 1)),
+        None => return Err(())
+    };
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| ())?;
+    let parts = line.split(' ').collect::<Vec<&str>>();
+    let ip = match parts.get(1) {
+        Some(&"UNKNOWN") => None,
+        Some(&"TCP4") | Some(&"TCP6") if parts.len() == 6 => {
+            Some(parts[2].parse::<IpAddr>().map_err(|_| ())?)
+        },
+        _ => return Err(())
+    };
+    Ok(Header::Done(end + 2, ip))
+
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Header, ()> {
+
+    if buf.len() < 16 {
+        return Ok(Header::Incomplete(16));
+    }
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(Header::Incomplete(len));
+    }
+    match buf[12] {
+        // Version 2 LOCAL, sent by the proxy itself
+        0x20 => return Ok(Header::Done(len, None)),
+        // Version 2 PROXY
+        0x21 => {},
+        _ => return Err(())
+    }
+    let address = &buf[16..len];
+    let ip = match buf[13] >> 4 {
+        // AF_INET, AF_INET6
+        1 if address.len() >= 12 => Some(IpAddr::V4(Ipv4Addr::new(address[0], address[1], address[2], address[3]))),
+        2 if address.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&address[..16]);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        },
+        // AF_UNSPEC, AF_UNIX
+        0 | 3 => None,
+        _ => return Err(())
+    };
+    Ok(Header::Done(len, ip))
+
+}
+
+// Read the header from a blocking connection, without going past it
+pub fn read<R: Read>(reader: &mut R) -> io::Result<Option<IpAddr>> {
+
+    let mut buf = vec![];
+    loop {
+        match parse(&buf) {
+            Ok(Header::Incomplete(len)) => {
+                let start = buf.len();
+                buf.resize(len, 0);
+                reader.read_exact(&mut buf[start..])?;
+            },
+            Ok(Header::Done(_, ip)) => return Ok(ip),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "wrong PROXY protocol header"))
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::access::{Cidr, Access, Header, client, parse, read};
+    use crate::request::Request;
+    use std::net::IpAddr;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("11.0.0.0")));
+        assert!(!cidr.contains(ip("::1")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("1.2.3.4")));
+        assert!(Cidr::parse("192.168.1.7").unwrap().contains(ip("192.168.1.7")));
+        assert!(!Cidr::parse("192.168.1.7").unwrap().contains(ip("192.168.1.8")));
+        assert!(Cidr::parse("fd00::/8").unwrap().contains(ip("fd12::1")));
+        assert!(!Cidr::parse("fd00::/8").unwrap().contains(ip("fe80::1")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("::1")));
+        assert_eq!(Cidr::parse("::1").unwrap().to_string(), "::1/128");
+        assert_eq!(Cidr::parse("10.0.0.0/33"), None);
+        assert_eq!(Cidr::parse("10.0.0/8"), None);
+        assert_eq!(Cidr::parse(".*"), None);
+    }
+
+    #[test]
+    fn test_access() {
+        let access = Access {
+            allow: vec![Cidr::parse("10.0.0.0/8").unwrap()],
+            deny: vec![Cidr::parse("10.0.0.5").unwrap()]
+        };
+        assert_eq!(access.refuse(Some(ip("10.0.0.4"))), None);
+        assert_eq!(access.refuse(Some(ip("10.0.0.5"))).unwrap(), "deny 10.0.0.5/32 for 10.0.0.5");
+        assert_eq!(access.refuse(Some(ip("8.8.8.8"))).unwrap(), "not in allow for 8.8.8.8");
+        assert!(access.refuse(None).is_some());
+        assert_eq!(Access::default().refuse(None), None);
+    }
+
+    #[test]
+    fn test_client() {
+        let proxies = vec![Cidr::parse("10.0.0.0/8").unwrap()];
+        let request = |peer: &str, forwarded: &str| {
+            let mut request = Request::new(format!("GET / HTTP/1.1\r\nX-Forwarded-For: {}\r\n\r\n", forwarded).as_bytes()).unwrap();
+            request.peer = Some(ip(peer));
+            request
+        };
+        assert_eq!(client(&request("10.0.0.1", "1.2.3.4"), &proxies, false), Some(ip("1.2.3.4")));
+        assert_eq!(client(&request("10.0.0.1", "6.6.6.6, 1.2.3.4, 10.0.0.2"), &proxies, false), Some(ip("1.2.3.4")));
+        assert_eq!(client(&request("10.0.0.1", "10.0.0.3, 10.0.0.2"), &proxies, false), Some(ip("10.0.0.3")));
+        assert_eq!(client(&request("10.0.0.1", "forged, 10.0.0.2"), &proxies, false), Some(ip("10.0.0.2")));
+        // Only a trusted proxy can say who the client is
+        assert_eq!(client(&request("1.2.3.4", "5.6.7.8"), &proxies, false), Some(ip("1.2.3.4")));
+        assert_eq!(client(&request("10.0.0.1", "1.2.3.4"), &proxies, true), Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_proxy_protocol() {
+        let v1 = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET";
+        assert_eq!(parse(v1), Ok(Header::Done(47, Some(ip("192.168.0.1")))));
+        assert_eq!(parse(b"PROXY TCP6 ::1 ::2 1 2\r\n"), Ok(Header::Done(24, Some(ip("::1")))));
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n"), Ok(Header::Done(15, None)));
+        assert_eq!(parse(b"PROXY TCP4 192"), Ok(Header::Incomplete(15)));
+        assert_eq!(parse(b"PRO"), Ok(Header::Incomplete(4)));
+        assert_eq!(parse(b"PROXY TCP4 a b 1 2\r\n"), Err(()));
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n"), Err(()));
+        assert_eq!(parse(&[b'P'; 200][..]), Err(()));
+
+        let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        v2.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 0, 80, 1, 187]);
+        assert_eq!(parse(&v2[..10]), Ok(Header::Incomplete(16)));
+        assert_eq!(parse(&v2[..16]), Ok(Header::Incomplete(28)));
+        assert_eq!(parse(&v2), Ok(Header::Done(28, Some(ip("1.2.3.4")))));
+        let local = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00";
+        assert_eq!(parse(local), Ok(Header::Done(16, None)));
+
+        // The request after it is left alone
+        let mut reader = &v1[..];
+        assert_eq!(read(&mut reader).unwrap(), Some(ip("192.168.0.1")));
+        assert_eq!(reader, b"GET");
+        let mut reader = &v2[..20];
+        assert!(read(&mut reader).is_err());
+    }
+
+}
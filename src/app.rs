@@ -18,10 +18,6 @@ impl App {
         self.get_arg_flag("-d") || self.get_arg_flag("detach")
     }
 
-    pub fn detach_args(&self) -> [&str; 2] {
-        ["-d", "detach"]
-    }
-
     pub fn help(&self) -> bool {
         self.get_arg_flag("-h") || self.get_arg_flag("help")
     }
@@ -30,6 +26,10 @@ impl App {
         self.get_arg_flag("-s") || self.get_arg_flag("stop")
     }
 
+    pub fn status(&self) -> bool {
+        self.get_arg_flag("status")
+    }
+
     pub fn test(&self) -> bool {
         self.get_arg_flag("-t") || self.get_arg_flag("test")
     }
@@ -42,26 +42,30 @@ impl App {
         self.get_arg_option("-c")
     }
 
+    pub fn engine(&self) -> Option<String> {
+        self.get_arg_option("-e")
+    }
+
+    pub fn pid_file(&self) -> Option<String> {
+        self.get_arg_option("-p")
+    }
+
     pub fn start(&self) -> bool {
         self.get_arg_flag("start")
     }
 
-    pub fn port(&self) -> Result<Option<i64>, String> {
+    pub fn port(&self) -> Result<Option<u16>, String> {
         if let Some(port) = self.get_arg_option("start") {
-            if let Ok(port) = port.parse::<i64>() {
+            if let Ok(port) = port.parse::<u16>() {
+                if port == 0 {
+                    return Err(port.to_string());
+                }
                 Ok(Some(port))
             }else {
-                match port.chars().nth(0) {
-                    Some(cha) => {
-                        if cha == '-' {
-                            Ok(None)
-                        }else {
-                            Err(port)
-                        }
-                    }
-                    None => {
-                        Err(port)
-                    }
+                if port.starts_with('-') {
+                    Ok(None)
+                }else {
+                    Err(port)
                 }
             }
         }else {
@@ -79,11 +83,14 @@ FLAGS:
     -d, detach          Running in the background
     -h, help            Print help information
     -s, stop            Stop the daemon
+    status              Whether the daemon is running
     -t, test            Test the config file for error
     -v, version         Print version number
 
 OPTIONS:
     -c    <FILE>        Specify a configuration file
+    -e    <ENGINE>      Serve with "thread" or "event" engine
+    -p    <FILE>        PID file of the daemon
     start <PORT?>       Quick Start
 "#,
             env!("CARGO_PKG_NAME"),
@@ -97,7 +104,7 @@ OPTIONS:
 
     fn get_arg_flag(&self, flag: &str) -> bool {
         for x in self.args.iter() {
-            if x == &flag {
+            if x == flag {
                 return true;
             }
         }
@@ -0,0 +1,314 @@
+use std::fs;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use subtle::ConstantTimeEq;
+
+pub const DEFAULT_REALM: &str = "User Visible Realm";
+
+// The htpasswd file is looked at again after this long
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Checked for unknown users, so they take as long as the others.
+// bcrypt with the cost of "htpasswd -B"
+const DUMMY_HASH: &str = "$2b$05$sIXvQJWtfyVjHzK8JZIF0.lo35FFnbpnYu/1UtPmSXVgTfG65Pqg.";
+
+const HASHES: [&str; 6] = ["$2a$", "$2b$", "$2y$", "$5$", "$6$", "$argon2"];
+
+// Basic authentication with users of the configuration file or an htpasswd file
+#[derive(Debug)]
+pub struct Auth {
+    pub realm: String,
+    users: HashMap<String, String>,
+    htpasswd: Option<Htpasswd>
+}
+
+// Read again when its modification time changes
+#[derive(Debug)]
+struct Htpasswd {
+    path: String,
+    state: RwLock<State>
+}
+
+#[derive(Debug)]
+struct State {
+    modified: Option<SystemTime>,
+    checked: Instant,
+    users: HashMap<String, String>
+}
+
+impl Auth {
+
+    // `users` map names to passwords or hashes
+    pub fn new(realm: String, users: HashMap<String, String>, htpasswd: Option<String>) -> Result<Auth, String> {
+
+        for (name, password) in users.iter() {
+            supported(password, true).map_err(|msg| format!("\"auth\" user \"{}\" {}", name, msg))?;
+        }
+        let htpasswd = match htpasswd {
+            Some(path) => {
+                let (modified, users) = read(&path)?;
+                Some(Htpasswd {
+                    path,
+                    state: RwLock::new(State {
+                        modified,
+                        checked: Instant::now(),
+                        users
+                    })
+                })
+            },
+            None => None
+        };
+
+        Ok(Auth {
+            realm,
+            users,
+            htpasswd
+        })
+
+    }
+
+    pub fn htpasswd(&self) -> Option<&str> {
+        self.htpasswd.as_ref().map(|htpasswd| htpasswd.path.as_str())
+    }
+
+    // The same users, with the htpasswd file found at `path` from now on
+    pub fn relocate(&self, path: String) -> Auth {
+
+        let htpasswd = self.htpasswd.as_ref().map(|htpasswd| {
+            let state = htpasswd.state.read().unwrap();
+            Htpasswd {
+                path,
+                state: RwLock::new(State {
+                    modified: state.modified,
+                    checked: state.checked,
+                    users: state.users.clone()
+                })
+            }
+        });
+        Auth {
+            realm: self.realm.clone(),
+            users: self.users.clone(),
+            htpasswd
+        }
+
+    }
+
+    // The value of the Authorization header
+    pub fn check(&self, authorization: &str) -> bool {
+
+        let encoded = match authorization.strip_prefix("Basic ") {
+            Some(encoded) => encoded.trim(),
+            None => return false
+        };
+        let decoded = match base64::decode(encoded) {
+            Ok(decoded) => decoded,
+            Err(_) => return false
+        };
+        let credentials = String::from_utf8_lossy(&decoded);
+        let (name, password) = match credentials.split_once(':') {
+            Some(credentials) => credentials,
+            None => return false
+        };
+
+        if let Some(stored) = self.users.get(name) {
+            return verify(password, stored);
+        }
+        match self.htpasswd.as_ref().and_then(|htpasswd| htpasswd.get(name)) {
+            Some(stored) => verify(password, &stored),
+            None => {
+                verify(password, DUMMY_HASH);
+                false
+            }
+        }
+
+    }
+
+}
+
+impl Htpasswd {
+
+    fn get(&self, name: &str) -> Option<String> {
+
+        let stale = self.state.read().unwrap().checked.elapsed() >= CHECK_INTERVAL;
+        if stale {
+            self.refresh();
+        }
+        self.state.read().unwrap().users.get(name).cloned()
+
+    }
+
+    // A file that can't be read keeps the users it had
+    fn refresh(&self) {
+
+        let mut state = self.state.write().unwrap();
+        if state.checked.elapsed() < CHECK_INTERVAL {
+            return;
+        }
+        state.checked = Instant::now();
+        // Gone, or out of reach after chroot
+        let modified = match fs::metadata(&self.path).and_then(|meta| meta.modified()) {
+            Ok(modified) => Some(modified),
+            Err(_) => return
+        };
+        if modified == state.modified {
+            return;
+        }
+        match read(&self.path) {
+            Ok((modified, users)) => {
+                state.modified = modified;
+                state.users = users;
+            },
+            Err(msg) => eprintln!("Reading htpasswd failed, {}", msg)
+        }
+
+    }
+
+}
+
+// "name:hash" lines, # starts a comment
+fn read(path: &str) -> Result<(Option<SystemTime>, HashMap<String, String>), String> {
+
+    let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    let text = fs::read_to_string(path).map_err(|err| format!("{} {}", path, err))?;
+
+    let mut users = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, hash) = match line.split_once(':') {
+            Some(user) => user,
+            None => {
+                return Err(format!("{} line {} should be \"name:hash\"", path, i + 1));
+            }
+        };
+        supported(hash, false).map_err(|msg| format!("{} line {} {}", path, i + 1, msg))?;
+        users.insert(name.to_string(), hash.to_string());
+    }
+    Ok((modified, users))
+
+}
+
+// bcrypt, SHA-crypt and argon2 hashes. Anything else is a plain password,
+// only allowed in the configuration file
+fn supported(password: &str, plain: bool) -> Result<(), String> {
+    let hash = HASHES.iter().any(|prefix| password.starts_with(prefix));
+    let other = password.starts_with("$apr1$") || password.starts_with("{SHA}") || password.starts_with("$1$");
+    if !hash && (other || !plain) {
+        return Err(String::from("uses an unsupported hash, use bcrypt, SHA-crypt or argon2"));
+    }
+    if password.starts_with("$argon2") {
+        PasswordHash::new(password).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+fn verify(password: &str, stored: &str) -> bool {
+
+    if stored.starts_with("$argon2") {
+        return match PasswordHash::new(stored) {
+            Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+            Err(_) => false
+        };
+    }
+    let computed = if HASHES.iter().any(|prefix| stored.starts_with(prefix)) {
+        match pwhash::unix::crypt(password, stored) {
+            Ok(computed) => computed,
+            Err(_) => return false
+        }
+    }else {
+        password.to_string()
+    };
+    computed.as_bytes().ct_eq(stored.as_bytes()).into()
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::auth::{Auth, DEFAULT_REALM, CHECK_INTERVAL, DUMMY_HASH, verify};
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use std::{env, fs, thread};
+
+    // "password" hashed with bcrypt, SHA-256-crypt, SHA-512-crypt and argon2id
+    const BCRYPT: &str = "$2y$05$bvIG6Nmid91Mu9RcmmWZfO5HJIMCT8riNW0hEp8f6/FuA2/mHZFpe";
+    const SHA256: &str = "$5$WH1ABM5sKhxbkgCK$ZCVZXmENtOuus9POLPLFXCwbmvV7VA0bC3IkMf78lX4";
+    const SHA512: &str = "$6$G/gkPn17kHYo0gTF$QB/incPokwe8ZXtcnr/6T5SimYnNqLB3OooPxZixHcEk5/cpQvJkB/eRQZVPskF8gKpgV/MxD5xB0vvLAvRxB1";
+    const ARGON2: &str = "$argon2id$v=19$m=16,t=2,p=1$c2FsdHNhbHQ$TsIHTcvap+XUROS0oUqyXZkXxMgQQ5dyc0PmE63P6/U";
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", base64::encode(credentials))
+    }
+
+    #[test]
+    fn test_auth() {
+        let mut users = HashMap::new();
+        users.insert(String::from("plain"), String::from("password"));
+        for (name, hash) in [("bcrypt", BCRYPT), ("sha256", SHA256), ("sha512", SHA512), ("argon2", ARGON2)] {
+            users.insert(name.to_string(), hash.to_string());
+        }
+        let auth = Auth::new(DEFAULT_REALM.to_string(), users, None).unwrap();
+
+        for name in ["plain", "bcrypt", "sha256", "sha512", "argon2"] {
+            assert!(auth.check(&basic(&format!("{}:password", name))), "{}", name);
+            assert!(!auth.check(&basic(&format!("{}:passwore", name))), "{}", name);
+            assert!(!auth.check(&basic(&format!("{}:", name))), "{}", name);
+        }
+        assert!(!auth.check(&basic("other:password")));
+        assert!(!auth.check(&basic("plain")));
+        assert!(!auth.check("Basic !!!"));
+        assert!(!auth.check(&format!("Bearer {}", base64::encode("plain:password"))));
+
+        let mut users = HashMap::new();
+        users.insert(String::from("md5"), String::from("$apr1$salt$hash"));
+        assert!(Auth::new(DEFAULT_REALM.to_string(), users, None).is_err());
+
+        // A real hash, or unknown users would be answered sooner
+        assert!(verify("see-dummy", DUMMY_HASH));
+    }
+
+    #[test]
+    fn test_htpasswd() {
+        let path = env::temp_dir().join("see_test_htpasswd");
+        let path = path.to_str().unwrap();
+        fs::write(path, format!("# users\nalice:{}\n\nbob:{}\n", BCRYPT, ARGON2)).unwrap();
+        let auth = Auth::new(DEFAULT_REALM.to_string(), HashMap::new(), Some(path.to_string())).unwrap();
+        assert!(auth.check(&basic("alice:password")));
+        assert!(auth.check(&basic("bob:password")));
+        assert!(!auth.check(&basic("carol:password")));
+
+        // Changes are picked up, a broken file is ignored
+        let interval = CHECK_INTERVAL + Duration::from_millis(100);
+        thread::sleep(interval);
+        fs::write(path, format!("carol:{}\n", SHA512)).unwrap();
+        assert!(auth.check(&basic("carol:password")));
+        assert!(!auth.check(&basic("alice:password")));
+        fs::write(path, "broken").unwrap();
+        thread::sleep(interval);
+        assert!(auth.check(&basic("carol:password")));
+
+        // Moved like under chroot
+        let moved = env::temp_dir().join("see_test_htpasswd_moved");
+        let moved = moved.to_str().unwrap();
+        fs::write(moved, format!("dave:{}\n", BCRYPT)).unwrap();
+        let relocated = auth.relocate(moved.to_string());
+        assert_eq!(relocated.htpasswd(), Some(moved));
+        thread::sleep(interval);
+        assert!(relocated.check(&basic("dave:password")));
+        assert!(!relocated.check(&basic("carol:password")));
+        let _ = fs::remove_file(moved);
+
+        // Plain passwords only in the configuration file
+        for line in ["alice\n", "alice:password\n", "alice:$apr1$salt$hash\n"] {
+            fs::write(path, line).unwrap();
+            assert!(Auth::new(DEFAULT_REALM.to_string(), HashMap::new(), Some(path.to_string())).is_err(), "{}", line);
+        }
+        let _ = fs::remove_file(path);
+    }
+
+}
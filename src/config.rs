@@ -8,15 +8,23 @@ use std::sync::Arc;
 use std::result::Result;
 use std::collections::HashMap;
 use crate::log::Log;
-use base64::encode;
 use crate::fill_path;
-use yaml_rust::{YamlLoader};
+use crate::resolve::normalize;
+use crate::auth::{Auth, DEFAULT_REALM};
+use crate::access::{Access, Cidr};
+use std::net::SocketAddr;
+use yaml_rust::{YamlLoader, Yaml};
+use glob::{Pattern, MatchOptions};
+use regex::Regex;
+use rustls::sign::CertifiedKey;
+use crate::tls;
+use crate::file_cache::{FileCache, DEFAULT_CACHE_SIZE, DEFAULT_MAX_FILE_SIZE};
 
 // Configuration of each service
-#[derive(Debug, Default)]
+#[derive(Debug, Clone)]
 pub struct ServerConfig {
     pub hosts: Option<Vec<String>>,
-    pub listen: i64,
+    pub listen: SocketAddr,
     pub root: String,
     pub compress: Option<Compress>,
     pub directory: Option<DirectoryOption>,
@@ -25,107 +33,586 @@ pub struct ServerConfig {
     pub rewrite: Option<HashMap<String, Rewrite>>,
     pub extensions: Option<Vec<String>>,
     pub methods: Vec<String>,
-    pub auth: Option<String>,
+    // Shared by the copies of the server, the htpasswd file is read again when it changes
+    pub auth: Option<Arc<Auth>>,
+    pub keep_alive: Option<KeepAlive>,
+    pub limit: Limit,
+    pub max_body_size: u64,
+    // Threads serving the connections of the listener
+    pub workers: usize,
+    // Connections served or waiting for a worker, 503 beyond that
+    pub max_connections: usize,
+    pub engine: Engine,
+    pub etag: ETagType,
+    pub symlinks: Symlinks,
+    // Files that are never served or listed
+    pub deny: Vec<Deny>,
+    // Clients by their address, checked before "auth"
+    pub access: Access,
+    // X-Forwarded-For is only believed from these
+    pub trusted_proxies: Vec<Cidr>,
+    // Connections start with a PROXY protocol header, shared by the servers on one address
+    pub proxy_protocol: bool,
+    pub cache: Vec<Cache>,
+    // Shared by the copies of the server on each address
+    pub file_cache: Option<Arc<FileCache>>,
+    pub tls: Option<Tls>,
+    // Only redirects to HTTPS on this port
+    pub https: Option<u16>,
     pub error: Error,
-    pub log: Recording
+    pub log: Recording,
+    // Parts of the site with their own rules
+    pub locations: Vec<Location>
 }
 
-#[derive(Debug, Default)]
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            hosts: None,
+            listen: SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)),
+            root: String::new(),
+            compress: None,
+            directory: None,
+            index: None,
+            headers: vec![],
+            rewrite: None,
+            extensions: None,
+            methods: vec![],
+            auth: None,
+            keep_alive: None,
+            limit: Limit::default(),
+            max_body_size: 0,
+            workers: DEFAULT_WORKERS,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            engine: Engine::default(),
+            etag: ETagType::default(),
+            symlinks: Symlinks::default(),
+            deny: vec![],
+            access: Access::default(),
+            trusted_proxies: vec![],
+            proxy_protocol: false,
+            cache: vec![],
+            file_cache: None,
+            tls: None,
+            https: None,
+            error: Error::default(),
+            log: Recording::default(),
+            locations: vec![]
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct DirectoryOption {
     pub time: bool,
     pub size: bool
 }
 
 // Header -> key: value
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Header {
     pub key: String,
     pub value: String
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Rewrite {
     pub url: String,
     pub status: RewriteType
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum RewriteType {
     _301,
     _302,
     Path
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Compress {
-    pub mode: CompressType,
-    pub extensions: Option<Vec<String>>
+    pub mode: Vec<CompressType>,
+    pub extensions: Option<Vec<String>>,
+    pub precompressed: bool
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub enum CompressType {
     Gzip,
+    Deflate,
     Br,
+    Zstd,
+    #[default]
     None
 }
 
-impl Default for CompressType {
+impl CompressType {
+
+    // Content coding token, case-insensitive
+    pub fn from_name(name: &str) -> Option<CompressType> {
+        match name.to_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(CompressType::Gzip),
+            "deflate" => Some(CompressType::Deflate),
+            "br" => Some(CompressType::Br),
+            "zstd" => Some(CompressType::Zstd),
+            _ => None
+        }
+    }
+
+    // Value of the Content-Encoding header
+    pub fn encoding(&self) -> Option<&'static str> {
+        match self {
+            CompressType::Gzip => Some("gzip"),
+            CompressType::Deflate => Some("deflate"),
+            CompressType::Br => Some("br"),
+            CompressType::Zstd => Some("zstd"),
+            CompressType::None => None
+        }
+    }
+
+    // Extension of the precompressed sibling file
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            CompressType::Gzip => Some("gz"),
+            CompressType::Br => Some("br"),
+            CompressType::Zstd => Some("zst"),
+            _ => None
+        }
+    }
+
+}
+
+// Persistent connections
+#[derive(Debug, Clone)]
+pub struct KeepAlive {
+    // Idle time in seconds before the connection is closed
+    pub timeout: u64,
+    // Maximum number of requests served on one connection
+    pub requests: usize
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive {
+            timeout: 5,
+            requests: 100
+        }
+    }
+}
+
+// Size and time limits on reading the request head
+#[derive(Debug, Clone)]
+pub struct Limit {
+    // Total size of the header lines in bytes
+    pub header_size: usize,
+    pub header_count: usize,
+    pub url_size: usize,
+    // Seconds allowed for a client to send the request head
+    pub timeout: u64
+}
+
+impl Default for Limit {
     fn default() -> Self {
-        CompressType::None
+        Limit {
+            header_size: 8192,
+            header_count: 100,
+            url_size: 8192,
+            timeout: 10
+        }
+    }
+}
+
+// How connections are served
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Engine {
+    // A blocking thread of the pool for each connection
+    #[default]
+    Thread,
+    // A few threads waiting on many connections for the ones that are ready
+    Event
+}
+
+impl Engine {
+
+    pub fn from_name(name: &str) -> Option<Engine> {
+        match name {
+            "thread" => Some(Engine::Thread),
+            "event" => Some(Engine::Event),
+            _ => None
+        }
+    }
+
+}
+
+// Entity tag of files
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ETagType {
+    #[default]
+    Strong,
+    Weak,
+    None
+}
+
+// Hidden files, a pattern with "/" matches the path from the root,
+// otherwise the name of any file or directory on the way
+#[derive(Debug, Clone)]
+pub struct Deny {
+    pub pattern: Pattern,
+    pub path: bool,
+    pub status: DenyType
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DenyType {
+    _403,
+    _404
+}
+
+// Dotfiles such as .git and .env, editor swap files and backups
+pub const DEFAULT_DENY: [&str; 4] = [".*", "*~", "*.swp", "*.bak"];
+
+impl Deny {
+
+    // "pattern" or "pattern 403", 404 by default
+    pub fn new(value: &str) -> Result<Deny, String> {
+
+        let mut parts = value.split_whitespace();
+        let glob = match parts.next() {
+            Some(glob) => glob,
+            None => {
+                return Err(String::from("Empty deny pattern"));
+            }
+        };
+        let status = match parts.next() {
+            Some("403") => DenyType::_403,
+            Some("404") | None => DenyType::_404,
+            Some(status) => {
+                return Err(format!("Wrong deny status \"{}\", optional value: 403 404", status));
+            }
+        };
+        let pattern = match Pattern::new(glob) {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                return Err(format!("Wrong deny pattern \"{}\", {}", glob, err));
+            }
+        };
+
+        Ok(Deny {
+            pattern,
+            path: glob.contains('/'),
+            status
+        })
+
+    }
+
+    // A file of the server itself under the root, e.g. the configuration file
+    fn file(root: &str, file: &str) -> Option<Deny> {
+        let root = fs::canonicalize(root).ok()?;
+        let file = fs::canonicalize(file).ok()?;
+        let relative = file.strip_prefix(&root).ok()?;
+        let path = format!("/{}", relative.to_str()?);
+        Some(Deny {
+            pattern: Pattern::new(&Pattern::escape(&path)).ok()?,
+            path: true,
+            status: DenyType::_404
+        })
+    }
+
+    // `path` starts with "/" and is relative to the root,
+    // the directories on the way are checked too
+    pub fn matches(&self, path: &str) -> bool {
+
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false
+        };
+        let path = path.trim_end_matches('/');
+        if self.path {
+            path.match_indices('/')
+                .map(|(i, _)| &path[..i])
+                .filter(|dir| !dir.is_empty())
+                .chain(std::iter::once(path))
+                .any(|path| self.pattern.matches_with(path, options))
+        }else {
+            // "/.well-known" (RFC 8615) is meant to be public, a name pattern such as ".*"
+            // leaves it alone and only looks at what is inside
+            let path = match path.strip_prefix("/.well-known") {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+                _ => path
+            };
+            path.split('/')
+                .filter(|name| !name.is_empty())
+                .any(|name| self.pattern.matches_with(name, options))
+        }
+
+    }
+
+}
+
+// "= /path" is an exact path, "~ regex" a regular expression, anything else a prefix
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    Exact(String),
+    Regex(Regex),
+    Prefix(String)
+}
+
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub matcher: Matcher,
+    // The server with the options of the location
+    pub config: ServerConfig
+}
+
+// Symbolic links below the root
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Symlinks {
+    #[default]
+    Follow,
+    // Only to files of the owner of the link
+    OwnerMatch,
+    Deny
+}
+
+// Cache-Control of the files matching a glob, the first matching rule is used
+#[derive(Debug, Clone)]
+pub struct Cache {
+    pub pattern: Pattern,
+    // Match the path from the root instead of the file name
+    pub path: bool,
+    pub control: String
+}
+
+impl Cache {
+
+    // "png" is short for "*.png", a pattern with "/" matches the path from the root
+    pub fn new(key: &str, control: &str) -> Result<Cache, String> {
+
+        let glob = if key.contains(|c| "*?[./".contains(c)) {
+            key.to_string()
+        }else {
+            format!("*.{}", key)
+        };
+        let pattern = match Pattern::new(&glob) {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                return Err(format!("Wrong cache pattern \"{}\", {}", key, err));
+            }
+        };
+
+        Ok(Cache {
+            pattern,
+            path: key.contains('/'),
+            control: control.to_string()
+        })
+
+    }
+
+    // `path` starts with "/" and is relative to the root
+    pub fn matches(&self, path: &str) -> bool {
+
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false
+        };
+        if self.path {
+            self.pattern.matches_with(path, options)
+        }else {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            self.pattern.matches_with(name, options)
+        }
+
+    }
+
+    // Seconds of the max-age directive
+    pub fn max_age(&self) -> Option<u64> {
+        self.control.split(',').find_map(|directive| {
+            let mut pair = directive.trim().splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) if key.eq_ignore_ascii_case("max-age") => {
+                    value.trim().trim_matches('"').parse::<u64>().ok()
+                },
+                _ => None
+            }
+        })
     }
+
+}
+
+// Certificate of the server, the port is served over TLS
+#[derive(Debug, Clone)]
+pub struct Tls {
+    pub certified_key: Arc<CertifiedKey>,
+    // Plain HTTP addresses that redirect to HTTPS
+    pub redirect: Vec<SocketAddr>
 }
 
 // Error page
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Error {
     pub _404: Option<String>,
     pub _500: Option<String>
 }
 
 // Log path
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Recording {
     pub success: Option<Log>,
     pub error: Option<Log>
 }
 
-pub const DEFAULT_METHODS: [&str; 2] = ["GET", "HEAD"];
+// Options of "see -d", an item "daemon" next to the servers
+#[derive(Debug, Default, Clone)]
+pub struct Daemon {
+    pub pid_file: Option<String>,
+    // Output of the process, /dev/null when not set
+    pub stdout: Option<String>,
+    pub stderr: Option<String>
+}
 
-impl ServerConfig {
+impl Daemon {
 
-    // The same port service is a group
-    pub fn new(path: &str) -> Result<Vec<Arc<Vec<ServerConfig>>>, String>  {
+    pub fn new(path: &str) -> Result<Daemon, String> {
 
-        let content = match fs::read_to_string(&path) {
-            Ok(content) => content,
-            Err(err) => {
-                return Err(format!("{} {}", path, err));
+        let mut daemon = Daemon::default();
+
+        for x in load(path)?.iter() {
+            if x["daemon"].as_hash().is_none() {
+                continue;
             }
-        };
+            for (key, value) in [
+                ("pid_file", &mut daemon.pid_file),
+                ("stdout", &mut daemon.stdout),
+                ("stderr", &mut daemon.stderr)
+            ] {
+                match &x["daemon"][key] {
+                    Yaml::BadValue => {},
+                    Yaml::String(file) => *value = Some(file.to_string()),
+                    _ => {
+                        return Err(format!("\"daemon\" \"{}\" should be a file path", key));
+                    }
+                }
+            }
+        }
+
+        Ok(daemon)
+
+    }
+
+}
+
+// Who serves once the listeners are bound, an item "privileges" next to the servers
+#[derive(Debug, Default, Clone)]
+pub struct Privileges {
+    pub user: Option<String>,
+    // The group of the user when not set
+    pub group: Option<String>,
+    pub chroot: Option<Chroot>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Chroot {
+    // The root shared by the servers
+    Root,
+    Dir(String)
+}
+
+impl Privileges {
+
+    pub fn new(path: &str) -> Result<Privileges, String> {
 
-        let docs = match YamlLoader::load_from_str(&content) {
-            Ok(yaml) => {
-                if yaml.len() == 0 {
-                    return Err(String::from("Server should be a list"));
+        let mut privileges = Privileges::default();
+
+        for x in load(path)?.iter() {
+            let item = &x["privileges"];
+            if item.as_hash().is_none() {
+                continue;
+            }
+            for (key, value) in [
+                ("user", &mut privileges.user),
+                ("group", &mut privileges.group)
+            ] {
+                match &item[key] {
+                    Yaml::BadValue => {},
+                    Yaml::String(name) => *value = Some(name.to_string()),
+                    Yaml::Integer(id) if *id >= 0 => *value = Some(id.to_string()),
+                    _ => {
+                        return Err(format!("\"privileges\" \"{}\" should be a name or an id", key));
+                    }
                 }
-                yaml
-            },
-            Err(err) => {
-                return Err(err.to_string());
             }
-        };
+            privileges.chroot = match &item["chroot"] {
+                Yaml::BadValue | Yaml::Boolean(false) => None,
+                Yaml::Boolean(true) => Some(Chroot::Root),
+                Yaml::String(dir) => Some(Chroot::Dir(dir.to_string())),
+                _ => {
+                    return Err(String::from("\"privileges\" \"chroot\" should be true or a directory"));
+                }
+            };
+        }
 
-        let mut configs: Vec<Vec<ServerConfig>> = vec![];
+        Ok(privileges)
 
-        let servers = match docs[0].as_vec() {
-            Some(servers) => servers,
-            None => {
+    }
+
+}
+
+// The items of the configuration file
+fn load(path: &str) -> Result<Vec<Yaml>, String> {
+
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => {
+            return Err(format!("{} {}", path, err));
+        }
+    };
+
+    let docs = match YamlLoader::load_from_str(&content) {
+        Ok(yaml) => {
+            if yaml.is_empty() {
                 return Err(String::from("Server should be a list"));
             }
-        };
+            yaml
+        },
+        Err(err) => {
+            return Err(err.to_string());
+        }
+    };
+
+    match docs[0].as_vec() {
+        Some(servers) => Ok(servers.clone()),
+        None => Err(String::from("Server should be a list"))
+    }
+
+}
+
+pub const DEFAULT_METHODS: [&str; 2] = ["GET", "HEAD"];
+
+pub const DEFAULT_PORT: u16 = 80;
+
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+pub const DEFAULT_WORKERS: usize = 128;
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+impl ServerConfig {
+
+    // The same port service is a group
+    pub fn new(path: &str) -> Result<Vec<Arc<Vec<ServerConfig>>>, String>  {
+
+        let servers = load(path)?;
+
+        let mut configs: Vec<Vec<ServerConfig>> = vec![];
 
         for x in servers.iter() {
 
+            // Not a server
+            if x["daemon"].as_hash().is_some() || x["privileges"].as_hash().is_some() {
+                continue;
+            }
+
             let server = &x["server"];
 
             let hosts = match server["host"].as_vec() {
@@ -139,19 +626,15 @@ impl ServerConfig {
                     Some(hosts)
                 },
                 None => {
-                    if let Some(d) = server["host"].as_str() {
-                        Some(vec![String::from(d)])
-                    }else {
-                        None
-                    }
+                    server["host"].as_str().map(|d| vec![String::from(d)])
                 }
             };
 
-            let listen = match server["listen"].as_i64() {
-                Some(d) => d,
-                None => {
+            let listen = match server["listen"] {
+                Yaml::BadValue => {
                     return Err(String::from("Must bind port"));
-                }
+                },
+                ref yaml => addresses(yaml)?
             };
 
             let root = match server["root"].as_str() {
@@ -163,18 +646,25 @@ impl ServerConfig {
 
             let compress = match server["compress"].as_hash() {
                 Some(_) => {
-                    let mode = match server["compress"]["mode"].as_str() {
-                        Some(mode) => match mode {
-                            "gzip" => CompressType::Gzip,
-                            "br" => CompressType::Br,
-                            _ => {
-                                return Err(format!("Wrong compression mode \"{}\", optional value: \"gzip\" \"br\"", mode));
+                    // A single mode or a list in order of preference
+                    let names = match server["compress"]["mode"].as_vec() {
+                        Some(vec) => vec.iter().map(|item| item.as_str()).collect(),
+                        None => vec![server["compress"]["mode"].as_str()]
+                    };
+                    let mut mode = vec![];
+                    for name in names {
+                        match name {
+                            Some(name) => match CompressType::from_name(name) {
+                                Some(m) => mode.push(m),
+                                None => {
+                                    return Err(format!("Wrong compression mode \"{}\", optional value: \"gzip\" \"deflate\" \"br\" \"zstd\"", name));
+                                }
+                            },
+                            None => {
+                                return Err(String::from("Can't parse \"compress\" \"mode\""));
                             }
-                        },
-                        None => {
-                            return Err(String::from("Can't parse \"compress\" \"mode\""));
                         }
-                    };
+                    }
                     let extensions = match server["compress"]["extension"].as_vec() {
                         Some(extensions) => {
                             let mut vec: Vec<String> = vec![];
@@ -187,65 +677,24 @@ impl ServerConfig {
                         },
                         None => None
                     };
+                    let precompressed = server["compress"]["precompressed"].as_bool().unwrap_or(false);
+                    if !precompressed && mode.contains(&CompressType::Zstd) {
+                        return Err(String::from("\"zstd\" is only supported with \"precompressed\""));
+                    }
                     Some(Compress {
                         mode,
-                        extensions
+                        extensions,
+                        precompressed
                     })
                 }
                 None => None
             };
 
-            let directory = match server["directory"].as_bool() {
-                // No option
-                Some(open) => {
-                    if open {
-                        Some(DirectoryOption::default())
-                    }else {
-                        None
-                    }
-                },
-                None => {
-                    match server["directory"].as_hash() {
-                        // read option
-                        Some(_) => {
-                            let time = match server["directory"]["time"].as_bool() {
-                                Some(time) => time,
-                                None => false
-                            };
-                            let size = match server["directory"]["size"].as_bool() {
-                                Some(size) => size,
-                                None => false
-                            };
-                            Some(DirectoryOption {
-                                time,
-                                size
-                            })
-                        },
-                        None => None
-                    }
-                }
-            };
+            let directory = directory(&server["directory"]);
 
-            let index = match server["index"].as_str() {
-                Some(d) => Some(d.to_string()),
-                None => None
-            };
+            let index = server["index"].as_str().map(|d| d.to_string());
 
-            let headers = match server["header"].as_hash() {
-                Some(header) => {
-                    let mut headers: Vec<Header> = vec![];
-                    for (key, value) in header.iter() {
-                        if let (Some(k), Some(v)) = (key.as_str(), value.as_str()){
-                            headers.push(Header {
-                                key: k.to_string(),
-                                value: v.to_string()
-                            })
-                        }
-                    }
-                    headers
-                },
-                None => vec![]
-            };
+            let headers = headers(&server["header"]);
 
             let rewrite = match server["rewrite"].as_hash() {
                 Some(rewrite) => {
@@ -270,6 +719,16 @@ impl ServerConfig {
                                 },
                                 None => RewriteType::_302
                             };
+                            // Served from the root like a request path
+                            let url = match status {
+                                RewriteType::Path => match normalize(&url) {
+                                    Some(url) => url,
+                                    None => {
+                                        return Err(format!("Rewrite path \"{}\" should be inside of the root", url));
+                                    }
+                                },
+                                _ => url
+                            };
                             hash.insert(
                                 k.to_string(),
                                 Rewrite {
@@ -309,50 +768,281 @@ impl ServerConfig {
                 }
             }
 
-            let _404 = match server["error"][404].as_str() {
-                Some(d) => Some(fill_path(&root, d)),
+            let not_found = server["error"][404].as_str().map(|d| fill_path(&root, d));
+
+            let server_error = server["error"][500].as_str().map(|d| fill_path(&root, d));
+
+            let success = server["log"]["success"].as_str().map(|d| Log::new(fill_path(&root, d)));
+
+            let error = server["log"]["error"].as_str().map(|d| Log::new(fill_path(&root, d)));
+
+            let auth = match server["auth"].as_hash() {
+                Some(_) => Some(Arc::new(auth(&server["auth"], &root)?)),
                 None => None
             };
 
-            let _500 = match server["error"][500].as_str() {
-                Some(d) => Some(fill_path(&root, d)),
-                None => None
+            let keep_alive = match server["keep_alive"].as_bool() {
+                Some(open) => {
+                    if open {
+                        Some(KeepAlive::default())
+                    }else {
+                        None
+                    }
+                },
+                None => {
+                    let default = KeepAlive::default();
+                    let timeout = match server["keep_alive"]["timeout"].as_i64() {
+                        Some(d) if d > 0 => d as u64,
+                        Some(_) => {
+                            return Err(String::from("\"keep_alive\" \"timeout\" must be greater than 0"));
+                        },
+                        None => default.timeout
+                    };
+                    let requests = match server["keep_alive"]["requests"].as_i64() {
+                        Some(d) if d > 0 => d as usize,
+                        Some(_) => {
+                            return Err(String::from("\"keep_alive\" \"requests\" must be greater than 0"));
+                        },
+                        None => default.requests
+                    };
+                    Some(KeepAlive {
+                        timeout,
+                        requests
+                    })
+                }
             };
 
-            let success = match server["log"]["success"].as_str() {
-                Some(d) => Some(Log::new(fill_path(&root, d))),
-                None => None
+            let mut limit = Limit::default();
+            for (key, value) in [
+                ("header_size", &mut limit.header_size),
+                ("header_count", &mut limit.header_count),
+                ("url_size", &mut limit.url_size)
+            ] {
+                match server["limit"][key].as_i64() {
+                    Some(d) if d > 0 => *value = d as usize,
+                    Some(_) => {
+                        return Err(format!("\"limit\" \"{}\" must be greater than 0", key));
+                    },
+                    None => {}
+                }
+            }
+            match server["limit"]["timeout"].as_i64() {
+                Some(d) if d > 0 => limit.timeout = d as u64,
+                Some(_) => {
+                    return Err(String::from("\"limit\" \"timeout\" must be greater than 0"));
+                },
+                None => {}
+            }
+
+            let max_body_size = match server["max_body_size"].as_i64() {
+                Some(d) if d >= 0 => d as u64,
+                Some(_) => {
+                    return Err(String::from("\"max_body_size\" can't be negative"));
+                },
+                None => DEFAULT_MAX_BODY_SIZE
             };
 
-            let error = match server["log"]["error"].as_str() {
-                Some(d) => Some(Log::new(fill_path(&root, d))),
-                None => None
+            let workers = match server["workers"].as_i64() {
+                Some(d) if d > 0 => d as usize,
+                Some(_) => {
+                    return Err(String::from("\"workers\" must be greater than 0"));
+                },
+                None => DEFAULT_WORKERS
             };
 
-            let auth = match server["auth"].as_hash() {
+            let max_connections = match server["max_connections"].as_i64() {
+                Some(d) if d > 0 => d as usize,
                 Some(_) => {
-                    let user = match server["auth"]["user"].as_str() {
-                        Some(d) => d,
+                    return Err(String::from("\"max_connections\" must be greater than 0"));
+                },
+                None => DEFAULT_MAX_CONNECTIONS
+            };
+
+            let engine = match server["engine"].as_str() {
+                Some(name) => match Engine::from_name(name) {
+                    Some(engine) => engine,
+                    None => {
+                        return Err(format!("Wrong engine \"{}\", optional value: \"thread\" \"event\"", name));
+                    }
+                },
+                None => Engine::default()
+            };
+
+            let etag = match server["etag"].as_bool() {
+                Some(true) => ETagType::Strong,
+                Some(false) => ETagType::None,
+                None => match server["etag"].as_str() {
+                    Some("strong") => ETagType::Strong,
+                    Some("weak") => ETagType::Weak,
+                    Some(etag) => {
+                        return Err(format!("Wrong etag \"{}\", optional value: \"strong\" \"weak\" false", etag));
+                    },
+                    None => ETagType::Strong
+                }
+            };
+
+            let symlinks = match server["symlinks"].as_str() {
+                Some("follow") => Symlinks::Follow,
+                Some("owner-match") => Symlinks::OwnerMatch,
+                Some("deny") => Symlinks::Deny,
+                Some(symlinks) => {
+                    return Err(format!("Wrong symlinks \"{}\", optional value: \"follow\" \"owner-match\" \"deny\"", symlinks));
+                },
+                None => match server["symlinks"] {
+                    Yaml::BadValue => Symlinks::Follow,
+                    _ => return Err(String::from("Wrong symlinks, optional value: \"follow\" \"owner-match\" \"deny\""))
+                }
+            };
+
+            let mut deny = vec![];
+            match &server["deny"] {
+                Yaml::BadValue => {
+                    for value in DEFAULT_DENY.iter() {
+                        deny.push(Deny::new(value)?);
+                    }
+                },
+                Yaml::Boolean(false) => {},
+                Yaml::Array(items) => {
+                    for item in items {
+                        match item.as_str() {
+                            Some(value) => deny.push(Deny::new(value)?),
+                            None => {
+                                return Err(format!("Wrong deny pattern {:?}", item));
+                            }
+                        }
+                    }
+                },
+                Yaml::String(value) => deny.push(Deny::new(value)?),
+                _ => {
+                    return Err(String::from("\"deny\" should be a list of patterns or false"));
+                }
+            }
+
+            if !server["allow"].is_badvalue() {
+                return Err(String::from("Wrong \"allow\", client addresses go in \"access\""));
+            }
+            let access = access(&server["access"], &Access::default())?;
+
+            let trusted_proxies = match server["trusted_proxies"] {
+                Yaml::BadValue => vec![],
+                ref yaml => cidrs(yaml, "trusted_proxies")?
+            };
+            let proxy_protocol = match server["proxy_protocol"] {
+                Yaml::BadValue => false,
+                Yaml::Boolean(proxy_protocol) => proxy_protocol,
+                _ => {
+                    return Err(String::from("\"proxy_protocol\" should be true or false"));
+                }
+            };
+            if proxy_protocol && trusted_proxies.is_empty() {
+                return Err(String::from("\"proxy_protocol\" needs the addresses of the proxies in \"trusted_proxies\""));
+            }
+
+            let mut cache = vec![];
+            if let Some(hash) = server["cache"].as_hash() {
+                for (key, value) in hash.iter() {
+                    match (key.as_str(), value.as_str()) {
+                        (Some(k), Some(v)) => cache.push(Cache::new(k, v)?),
+                        _ => {
+                            return Err(String::from("\"cache\" should map a pattern to a Cache-Control value"));
+                        }
+                    }
+                }
+            }
+
+            let file_cache = match server["file_cache"].as_bool() {
+                Some(true) => Some(Arc::new(FileCache::new(DEFAULT_CACHE_SIZE, DEFAULT_MAX_FILE_SIZE, None))),
+                Some(false) => None,
+                None => match server["file_cache"].as_hash() {
+                    Some(_) => {
+                        let size = match server["file_cache"]["size"].as_i64() {
+                            Some(d) if d > 0 => d as usize,
+                            Some(_) => {
+                                return Err(String::from("\"file_cache\" \"size\" must be greater than 0"));
+                            },
+                            None => DEFAULT_CACHE_SIZE
+                        };
+                        let max_file_size = match server["file_cache"]["max_file_size"].as_i64() {
+                            Some(d) if d > 0 => d as u64,
+                            Some(_) => {
+                                return Err(String::from("\"file_cache\" \"max_file_size\" must be greater than 0"));
+                            },
+                            None => DEFAULT_MAX_FILE_SIZE
+                        };
+                        let status = match server["file_cache"]["status"].as_str() {
+                            Some(path) if path.starts_with('/') => Some(path.to_string()),
+                            Some(_) => {
+                                return Err(String::from("\"file_cache\" \"status\" should be a path starting with \"/\""));
+                            },
+                            None => None
+                        };
+                        Some(Arc::new(FileCache::new(size, max_file_size, status)))
+                    },
+                    None => None
+                }
+            };
+
+            let tls = match server["tls"].as_hash() {
+                Some(_) => {
+                    let cert = match server["tls"]["cert"].as_str() {
+                        Some(d) => fill_path(&root, d),
                         None => {
-                            return Err(String::from("Missing 'user' in auth"));
+                            return Err(String::from("Missing 'cert' in tls"));
                         }
                     };
-                    let password = match server["auth"]["password"].as_str() {
-                        Some(d) => d,
+                    let key = match server["tls"]["key"].as_str() {
+                        Some(d) => fill_path(&root, d),
                         None => {
-                            return Err(String::from("Missing 'password' in auth"));
+                            return Err(String::from("Missing 'key' in tls"));
                         }
                     };
-                    let s = format!("{}:{}", user, password);
-                    let base64 = format!("Basic {}", encode(&s));
-                    Some(base64)
+                    let redirect = match server["tls"]["redirect"] {
+                        Yaml::BadValue => vec![],
+                        ref yaml => addresses(yaml)?
+                    };
+                    if let Some(address) = redirect.iter().find(|address| listen.contains(address)) {
+                        return Err(format!("\"tls\" \"redirect\" can't be the same address as \"listen\" {}", address));
+                    }
+                    let certified_key = tls::certified_key(&cert, &key)?;
+                    Some(Tls {
+                        certified_key,
+                        redirect
+                    })
                 },
                 None => None
             };
 
-            let config = ServerConfig {
+            // The redirect listener is a plain server of its own on that address
+            if let Some(tls) = &tls {
+                for address in tls.redirect.iter() {
+                    let redirect = ServerConfig {
+                        hosts: hosts.clone(),
+                        listen: *address,
+                        root: root.clone(),
+                        methods: methods.clone(),
+                        https: Some(listen[0].port()),
+                        ..ServerConfig::default()
+                    };
+                    group(&mut configs, redirect)?;
+                }
+            }
+
+            // Whatever "deny" says, the configuration, log and htpasswd files aren't served
+            let files = [
+                Some(path),
+                success.as_ref().and_then(|log| log.path()),
+                error.as_ref().and_then(|log| log.path()),
+                auth.as_ref().and_then(|auth| auth.htpasswd())
+            ];
+            for file in files.iter().flatten() {
+                if let Some(file) = Deny::file(&root, file) {
+                    deny.push(file);
+                }
+            }
+
+            let mut config = ServerConfig {
                 hosts,
-                listen,
+                listen: listen[0],
                 root,
                 compress,
                 directory,
@@ -362,28 +1052,51 @@ impl ServerConfig {
                 extensions,
                 methods,
                 error: Error {
-                    _404,
-                    _500
+                    _404: not_found,
+                    _500: server_error
                 },
                 log: Recording {
                     success,
                     error
                 },
-                auth
+                auth,
+                keep_alive,
+                limit,
+                max_body_size,
+                workers,
+                max_connections,
+                engine,
+                etag,
+                symlinks,
+                deny,
+                access,
+                trusted_proxies,
+                proxy_protocol,
+                cache,
+                file_cache,
+                tls,
+                https: None,
+                locations: vec![]
             };
-
-            let (mut has, mut n) = (false, 0);
-            for (i, items) in configs.iter().enumerate() {
-                if items[0].listen == listen {
-                    has = true;
-                    n = i;
-                    break;
+            config.locations = locations(&server["location"], &config)?;
+            // Nor the htpasswd files of the locations
+            let htpasswd = config.locations.iter()
+                .filter_map(|location| location.config.auth.as_ref()?.htpasswd())
+                .filter_map(|file| Deny::file(&config.root, file))
+                .collect::<Vec<Deny>>();
+            config.deny.extend(htpasswd.iter().cloned());
+            for location in config.locations.iter_mut() {
+                location.config.deny.extend(htpasswd.iter().cloned());
+                for regex in location.config.locations.iter_mut() {
+                    regex.config.deny.extend(htpasswd.iter().cloned());
                 }
             }
-            if has {
-                configs[n].push(config);
-            }else {
-                configs.push(vec![config]);
+
+            // A copy for each address
+            for address in listen.iter() {
+                let mut copy = config.clone();
+                copy.listen = *address;
+                group(&mut configs, copy)?;
             }
 
         }
@@ -397,6 +1110,418 @@ impl ServerConfig {
 
     }
 
+    // The rules for a request path: an exact location first, then the first
+    // matching regex, then the longest prefix, otherwise the server's own.
+    // A regex is the copy made for the prefix the path is in
+    pub fn location(&self, path: &str) -> &ServerConfig {
+
+        let exact = self.locations.iter()
+            .find(|location| matches!(&location.matcher, Matcher::Exact(exact) if exact == path));
+        if let Some(location) = exact {
+            return &location.config;
+        }
+
+        let prefix = self.locations.iter()
+            .filter_map(|location| match &location.matcher {
+                Matcher::Prefix(prefix) if path.starts_with(prefix.as_str()) => Some((prefix.len(), location)),
+                _ => None
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, location)| &location.config)
+            .unwrap_or(self);
+        let regex = prefix.locations.iter()
+            .find(|location| matches!(&location.matcher, Matcher::Regex(regex) if regex.is_match(path)));
+
+        match regex {
+            Some(location) => &location.config,
+            None => prefix
+        }
+
+    }
+
+}
+
+// One "user" and "password", a map of "users" and/or an "htpasswd" file
+fn auth(yaml: &Yaml, root: &str) -> Result<Auth, String> {
+
+    let realm = match &yaml["realm"] {
+        Yaml::BadValue => DEFAULT_REALM.to_string(),
+        Yaml::String(realm) if !realm.contains('"') => realm.to_string(),
+        _ => {
+            return Err(String::from("\"auth\" \"realm\" should be text without quotes"));
+        }
+    };
+
+    let mut users = HashMap::new();
+    match (yaml["user"].as_str(), yaml["password"].as_str()) {
+        (Some(user), Some(password)) => {
+            users.insert(user.to_string(), password.to_string());
+        },
+        (Some(_), None) => {
+            return Err(String::from("Missing 'password' in auth"));
+        },
+        (None, Some(_)) => {
+            return Err(String::from("Missing 'user' in auth"));
+        },
+        (None, None) => {}
+    }
+    match &yaml["users"] {
+        Yaml::BadValue => {},
+        Yaml::Hash(hash) => {
+            for (key, value) in hash.iter() {
+                match (key.as_str(), value.as_str()) {
+                    (Some(user), Some(password)) => {
+                        users.insert(user.to_string(), password.to_string());
+                    },
+                    _ => {
+                        return Err(String::from("\"auth\" \"users\" should map a name to a password or hash"));
+                    }
+                }
+            }
+        },
+        _ => {
+            return Err(String::from("\"auth\" \"users\" should map a name to a password or hash"));
+        }
+    }
+
+    let htpasswd = match &yaml["htpasswd"] {
+        Yaml::BadValue => None,
+        Yaml::String(path) => Some(fill_path(root, path)),
+        _ => {
+            return Err(String::from("\"auth\" \"htpasswd\" should be a file path"));
+        }
+    };
+    if users.is_empty() && htpasswd.is_none() {
+        return Err(String::from("Missing 'user' in auth"));
+    }
+
+    Auth::new(realm, users, htpasswd)
+
+}
+
+// A port or an address written as text
+pub fn listen_address(text: &str) -> Option<SocketAddr> {
+    match text.parse::<u16>() {
+        Ok(port) if port > 0 => Some(SocketAddr::from(([0, 0, 0, 0], port))),
+        _ => text.parse::<SocketAddr>().ok()
+    }
+}
+
+// A port, an address or a list of them, a bare port listens on all IPv4 interfaces
+fn addresses(yaml: &Yaml) -> Result<Vec<SocketAddr>, String> {
+
+    let items = match yaml.as_vec() {
+        Some(items) => items.iter().collect(),
+        None => vec![yaml]
+    };
+    if items.is_empty() {
+        return Err(String::from("Must bind port"));
+    }
+
+    let mut addresses = vec![];
+    for item in items {
+        let address = match item {
+            Yaml::Integer(port) if *port > 0 && *port <= u16::MAX as i64 => {
+                SocketAddr::from(([0, 0, 0, 0], *port as u16))
+            },
+            Yaml::String(text) => match listen_address(text) {
+                Some(address) => address,
+                None => {
+                    return Err(format!("Wrong listen address \"{}\", e.g. 8080, \"127.0.0.1:8080\" or \"[::]:8080\"", text));
+                }
+            },
+            _ => {
+                return Err(format!("Wrong listen address {:?}", item));
+            }
+        };
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    Ok(addresses)
+
+}
+
+// "allow" and "deny" replace the lists of `base` they are given for
+fn access(yaml: &Yaml, base: &Access) -> Result<Access, String> {
+
+    let mut access = base.clone();
+    match yaml {
+        Yaml::BadValue => return Ok(access),
+        Yaml::Hash(hash) => {
+            for key in hash.keys() {
+                match key.as_str() {
+                    Some("allow") | Some("deny") => {},
+                    _ => {
+                        return Err(format!("Wrong access {:?}, optional value: \"allow\" \"deny\"", key));
+                    }
+                }
+            }
+        },
+        _ => {
+            return Err(String::from("\"access\" should have the addresses in \"allow\" and \"deny\""));
+        }
+    }
+    match yaml["allow"] {
+        Yaml::BadValue => {},
+        ref yaml => access.allow = cidrs(yaml, "allow")?
+    }
+    match yaml["deny"] {
+        Yaml::BadValue => {},
+        ref yaml => access.deny = cidrs(yaml, "deny")?
+    }
+    Ok(access)
+
+}
+
+// An address, a network like "10.0.0.0/8" or a list of them, false for none
+fn cidrs(yaml: &Yaml, name: &str) -> Result<Vec<Cidr>, String> {
+
+    let items = match yaml {
+        Yaml::Boolean(false) => return Ok(vec![]),
+        Yaml::Array(items) => items.iter().collect(),
+        _ => vec![yaml]
+    };
+
+    let mut cidrs = vec![];
+    for item in items {
+        match item.as_str().and_then(Cidr::parse) {
+            Some(cidr) => cidrs.push(cidr),
+            None => {
+                return Err(format!("Wrong {} address {:?}, e.g. \"10.0.0.0/8\", \"192.168.1.7\" or \"fd00::/8\"", name, item));
+            }
+        }
+    }
+    Ok(cidrs)
+
 }
 
+// "true", or "time" and "size" of the listing
+fn directory(yaml: &Yaml) -> Option<DirectoryOption> {
+    match yaml {
+        Yaml::Boolean(true) => Some(DirectoryOption::default()),
+        Yaml::Hash(_) => Some(DirectoryOption {
+            time: yaml["time"].as_bool().unwrap_or(false),
+            size: yaml["size"].as_bool().unwrap_or(false)
+        }),
+        _ => None
+    }
+}
+
+fn headers(yaml: &Yaml) -> Vec<Header> {
+    let mut headers = vec![];
+    if let Some(hash) = yaml.as_hash() {
+        for (key, value) in hash.iter() {
+            if let (Some(k), Some(v)) = (key.as_str(), value.as_str()) {
+                headers.push(Header {
+                    key: k.to_string(),
+                    value: v.to_string()
+                });
+            }
+        }
+    }
+    headers
+}
 
+// Each location is a copy of the location it is in with its own options: a prefix
+// of the longest prefix containing it, an exact path of the longest prefix matching it,
+// otherwise the server. A regex is copied for every prefix too, it takes the options
+// of the prefix the path is in
+fn locations(yaml: &Yaml, server: &ServerConfig) -> Result<Vec<Location>, String> {
+
+    let hash = match yaml {
+        Yaml::BadValue => return Ok(vec![]),
+        Yaml::Hash(hash) => hash,
+        _ => {
+            return Err(String::from("\"location\" should map a path to its options"));
+        }
+    };
+
+    let mut entries = vec![];
+    for (key, options) in hash.iter() {
+        let key = match key.as_str() {
+            Some(key) => key,
+            None => {
+                return Err(format!("Wrong location {:?}", key));
+            }
+        };
+        let matcher = if let Some(path) = key.strip_prefix('=') {
+            Matcher::Exact(path.trim().to_string())
+        }else if let Some(regex) = key.strip_prefix('~') {
+            match Regex::new(regex.trim()) {
+                Ok(regex) => Matcher::Regex(regex),
+                Err(err) => {
+                    return Err(format!("Wrong location regex \"{}\", {}", regex.trim(), err));
+                }
+            }
+        }else if key.starts_with('/') {
+            Matcher::Prefix(key.to_string())
+        }else {
+            return Err(format!("Wrong location \"{}\", e.g. /admin/, \"= /health\" or \"~ \\.map$\"", key));
+        };
+        if options.as_hash().is_none() {
+            return Err(format!("Location \"{}\" should have options", key));
+        }
+
+        // Read once, the copies of a regex share it
+        let auth = match &options["auth"] {
+            Yaml::BadValue => None,
+            Yaml::Boolean(false) => Some(None),
+            Yaml::Hash(_) => Some(Some(Arc::new(auth(&options["auth"], &server.root)?))),
+            _ => {
+                return Err(format!("Location \"{}\" \"auth\" should be false or its users", key));
+            }
+        };
+        entries.push((key, matcher, options, auth));
+    }
+
+    // The longest prefix location containing `path`
+    let within = |prefixes: &[Location], path: &str| -> Option<usize> {
+        prefixes.iter()
+            .enumerate()
+            .filter_map(|(i, location)| match &location.matcher {
+                Matcher::Prefix(prefix) if path.starts_with(prefix.as_str()) => Some((prefix.len(), i)),
+                _ => None
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, i)| i)
+    };
+
+    // Shorter prefixes first, they are copied by those inside them
+    let mut prefixes: Vec<Location> = vec![];
+    let mut sorted = entries.iter()
+        .filter_map(|(key, matcher, options, auth)| match matcher {
+            Matcher::Prefix(prefix) => Some((prefix, (key, options, auth))),
+            _ => None
+        })
+        .collect::<Vec<_>>();
+    sorted.sort_by_key(|(prefix, _)| prefix.len());
+    for (prefix, (key, options, auth)) in sorted {
+        let base = match within(&prefixes, prefix) {
+            Some(i) => &prefixes[i].config,
+            None => server
+        };
+        let config = location(key, options, auth, base)?;
+        prefixes.push(Location {
+            matcher: Matcher::Prefix(prefix.to_string()),
+            config
+        });
+    }
+
+    let mut locations = vec![];
+    for (key, matcher, options, auth) in entries.iter() {
+        match matcher {
+            Matcher::Exact(path) => {
+                let base = match within(&prefixes, path) {
+                    Some(i) => &prefixes[i].config,
+                    None => server
+                };
+                locations.push(Location {
+                    matcher: matcher.clone(),
+                    config: location(key, options, auth, base)?
+                });
+            },
+            Matcher::Regex(_) => {
+                for prefix in prefixes.iter_mut() {
+                    let config = location(key, options, auth, &prefix.config)?;
+                    prefix.config.locations.push(Location {
+                        matcher: matcher.clone(),
+                        config
+                    });
+                }
+                locations.push(Location {
+                    matcher: matcher.clone(),
+                    config: location(key, options, auth, server)?
+                });
+            },
+            Matcher::Prefix(_) => {}
+        }
+    }
+    locations.extend(prefixes);
+    Ok(locations)
+
+}
+
+// A copy of `base` with the options of a location
+fn location(key: &str, options: &Yaml, auth: &Option<Option<Arc<Auth>>>, base: &ServerConfig) -> Result<ServerConfig, String> {
+
+    let mut config = base.clone();
+    config.locations = vec![];
+
+    if let Some(auth) = auth {
+        config.auth = auth.clone();
+    }
+
+    if !options["allow"].is_badvalue() || !options["deny"].is_badvalue() {
+        return Err(format!("Wrong location \"{}\", client addresses go in \"access\"", key));
+    }
+    config.access = access(&options["access"], &base.access)?;
+
+    // The complete list, the defaults aren't added
+    match &options["method"] {
+        Yaml::BadValue => {},
+        Yaml::Array(items) => {
+            config.methods = items.iter()
+                .filter_map(|item| item.as_str())
+                .map(|method| method.to_string())
+                .collect();
+        },
+        _ => {
+            return Err(format!("Location \"{}\" \"method\" should be a list", key));
+        }
+    }
+
+    // Replace the inherited headers with the same name
+    for header in headers(&options["header"]) {
+        config.headers.retain(|item| !item.key.eq_ignore_ascii_case(&header.key));
+        config.headers.push(header);
+    }
+
+    match &options["directory"] {
+        Yaml::BadValue => {},
+        yaml => config.directory = directory(yaml)
+    }
+
+    match &options["index"] {
+        Yaml::BadValue => {},
+        Yaml::Boolean(false) => config.index = None,
+        Yaml::String(index) => config.index = Some(index.to_string()),
+        _ => {
+            return Err(format!("Location \"{}\" \"index\" should be a file name or false", key));
+        }
+    }
+
+    if let Some(d) = options["error"][404].as_str() {
+        config.error._404 = Some(fill_path(&base.root, d));
+    }
+    if let Some(d) = options["error"][500].as_str() {
+        config.error._500 = Some(fill_path(&base.root, d));
+    }
+
+    Ok(config)
+
+}
+
+// Servers on the same address are a group, either all of them use TLS or none
+fn group(configs: &mut Vec<Vec<ServerConfig>>, config: ServerConfig) -> Result<(), String> {
+
+    for items in configs.iter_mut() {
+        let listen = items[0].listen;
+        if listen == config.listen {
+            if items[0].tls.is_some() != config.tls.is_some() {
+                return Err(format!("Servers on {} should all use \"tls\" or none", config.listen));
+            }
+            items.push(config);
+            return Ok(());
+        }
+        // 0.0.0.0 already takes the port on every IPv4 address, [::] on every IPv6 address
+        if listen.port() == config.listen.port()
+            && listen.is_ipv4() == config.listen.is_ipv4()
+            && (listen.ip().is_unspecified() || config.listen.ip().is_unspecified()) {
+            return Err(format!("Listen addresses {} and {} overlap", listen, config.listen));
+        }
+    }
+    configs.push(vec![config]);
+    Ok(())
+
+}
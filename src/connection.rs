@@ -0,0 +1,471 @@
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::fs::File;
+use std::net::TcpStream;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+use rustls::{ServerConnection, StreamOwned};
+use crate::response::CompressedFile;
+
+const HEAD_END: [u8; 4] = [13, 10, 13, 10];    // '\r\n\r\n'
+
+
+// Where `output` writes a response. The file in a body is handed over as a range,
+// so a writer can send it later on its own instead of copying it right away
+pub trait Sink: Write {
+
+    fn send_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+        copy_file(file, offset, len, self)
+    }
+
+    fn send_compressed(&mut self, file: CompressedFile) -> io::Result<()> {
+        write_compressed(file, self)
+    }
+
+}
+
+// Copy `len` bytes of the file from `offset`, failing if the file has become shorter
+pub fn copy_file<W: Write + ?Sized>(file: &File, offset: u64, len: u64, writer: &mut W) -> io::Result<()> {
+
+    let mut file = file;
+    file.seek(SeekFrom::Start(offset))?;
+    if io::copy(&mut file.take(len), writer)? < len {
+        return Err(truncated());
+    }
+    Ok(())
+
+}
+
+// Write all the chunks of a compressed file
+pub fn write_compressed<W: Write + ?Sized>(mut file: CompressedFile, writer: &mut W) -> io::Result<()> {
+
+    while !file.finished() {
+        writer.write_all(&file.next_chunk()?)?;
+    }
+    Ok(())
+
+}
+
+pub fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "file has been truncated")
+}
+
+// At most this much in one sendfile call
+#[cfg(target_os = "linux")]
+const SENDFILE_SIZE: u64 = 0x7fff_f000;
+
+// The kernel copies the file to the socket without going through userspace.
+// Moves `offset` past what has been sent, 0 if the file ends before it
+#[cfg(target_os = "linux")]
+pub fn sendfile<S: AsRawFd>(socket: &S, file: &File, offset: &mut u64, len: u64) -> io::Result<usize> {
+
+    let mut off = *offset as libc::off_t;
+    let count = len.min(SENDFILE_SIZE) as usize;
+    let sent = unsafe {
+        libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut off, count)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    *offset = off as u64;
+    Ok(sent as usize)
+
+}
+
+// Files or sockets sendfile can't work with, they are copied instead
+#[cfg(target_os = "linux")]
+pub fn sendfile_unsupported(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP))
+}
+
+// Send all of it with sendfile on a blocking socket
+#[cfg(target_os = "linux")]
+fn sendfile_all(socket: &TcpStream, file: &File, mut offset: u64, mut len: u64) -> io::Result<()> {
+
+    while len != 0 {
+        match sendfile(socket, file, &mut offset, len) {
+            Ok(0) => return Err(truncated()),
+            Ok(sent) => len -= sent as u64,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) if sendfile_unsupported(&err) => {
+                let mut socket = socket;
+                return copy_file(file, offset, len, &mut socket);
+            },
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+
+}
+
+impl<W: Sink + ?Sized> Sink for &mut W {
+
+    fn send_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+        (**self).send_file(file, offset, len)
+    }
+
+    fn send_compressed(&mut self, file: CompressedFile) -> io::Result<()> {
+        (**self).send_compressed(file)
+    }
+
+}
+
+impl Sink for Vec<u8> {}
+
+impl Sink for &Stream {
+
+    fn send_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+        match self {
+            #[cfg(target_os = "linux")]
+            Stream::Tcp(stream) => sendfile_all(stream, file, offset, len),
+            // TLS has to encrypt it in userspace
+            _ => copy_file(file, offset, len, self)
+        }
+    }
+
+}
+
+
+// Sits between `output` and the socket, every response passes through here.
+// Adds the connection headers to the response head and drops the body of HEAD requests
+pub struct ResponseWriter<W: Write> {
+    inner: W,
+    head: Vec<u8>,
+    sent: bool,
+    headers: Vec<(String, String)>,
+    body: bool,
+    failed: bool
+}
+
+impl<W: Write> ResponseWriter<W> {
+
+    pub fn new(inner: W) -> ResponseWriter<W> {
+        ResponseWriter {
+            inner,
+            head: vec![],
+            sent: false,
+            headers: vec![],
+            body: true,
+            failed: false
+        }
+    }
+
+    // Add a header to the response
+    pub fn header(mut self, key: &str, value: &str) -> ResponseWriter<W> {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    // Whether to send the body of the response
+    pub fn body(mut self, body: bool) -> ResponseWriter<W> {
+        self.body = body;
+        self
+    }
+
+    // The connection ends after the response, unless its head has been sent already
+    pub fn close(&mut self) {
+        self.headers.retain(|(key, _)| key != "Connection" && key != "Keep-Alive");
+        self.headers.push((String::from("Connection"), String::from("close")));
+    }
+
+    // The connection can't be reused if the response was not sent completely
+    pub fn complete(&self) -> bool {
+        self.sent && !self.failed
+    }
+
+    fn send_head(&mut self, end: usize) -> io::Result<()> {
+
+        let mut head = self.head[..end - 2].to_vec();
+        for (key, value) in self.headers.iter() {
+            head.extend_from_slice(format!("{}: {}\r\n", key, value).as_bytes());
+        }
+        head.extend_from_slice(b"\r\n");
+        self.inner.write_all(&head)?;
+        self.sent = true;
+
+        let rest = self.head.split_off(end);
+        self.head.clear();
+        if self.body && !rest.is_empty() {
+            self.inner.write_all(&rest)?;
+        }
+        Ok(())
+
+    }
+
+}
+
+impl<W: Write> Write for ResponseWriter<W> {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+
+        let result = if self.sent {
+            if self.body {
+                self.inner.write_all(buf)
+            }else {
+                Ok(())
+            }
+        }else {
+            self.head.extend_from_slice(buf);
+            match self.head.windows(4).position(|w| w == HEAD_END) {
+                Some(i) => self.send_head(i + 4),
+                None => Ok(())
+            }
+        };
+
+        match result {
+            Ok(_) => Ok(buf.len()),
+            Err(err) => {
+                self.failed = true;
+                Err(err)
+            }
+        }
+
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.inner.flush();
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
+    }
+
+}
+
+impl<W: Sink> Sink for ResponseWriter<W> {
+
+    fn send_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+
+        // The head has to go first
+        if !self.sent {
+            return copy_file(file, offset, len, self);
+        }
+        if !self.body {
+            return Ok(());
+        }
+        let result = self.inner.send_file(file, offset, len);
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
+
+    }
+
+    fn send_compressed(&mut self, file: CompressedFile) -> io::Result<()> {
+
+        if !self.sent {
+            return write_compressed(file, self);
+        }
+        if !self.body {
+            return Ok(());
+        }
+        let result = self.inner.send_compressed(file);
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
+
+    }
+
+}
+
+
+// A client connection, plain or TLS.
+// Like TcpStream, it is read and written through shared references
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<RefCell<StreamOwned<ServerConnection, TcpStream>>>)
+}
+
+impl Stream {
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Tls(stream) => stream.borrow().sock.set_read_timeout(timeout)
+        }
+    }
+
+    // Protocol chosen in the TLS handshake
+    pub fn alpn(&self) -> Option<Vec<u8>> {
+        match self {
+            Stream::Tcp(_) => None,
+            Stream::Tls(stream) => stream.borrow().conn.alpn_protocol().map(|p| p.to_vec())
+        }
+    }
+
+}
+
+impl Read for &Stream {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            Stream::Tls(stream) => stream.borrow_mut().read(buf)
+        }
+    }
+
+}
+
+impl Write for &Stream {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            Stream::Tls(stream) => stream.borrow_mut().write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            Stream::Tls(stream) => stream.borrow_mut().flush()
+        }
+    }
+
+}
+
+impl Drop for Stream {
+
+    // Tell the client the TLS session ends here, not that the connection broke
+    fn drop(&mut self) {
+        if let Stream::Tls(stream) = self {
+            let stream = stream.get_mut();
+            stream.conn.send_close_notify();
+            let _ = stream.conn.complete_io(&mut stream.sock);
+        }
+    }
+
+}
+
+
+// Reads from the socket with a time limit.
+// With a deadline, the client has to finish before it no matter how slowly it trickles bytes in,
+// otherwise each read may wait for the idle timeout
+pub struct Deadline<'a> {
+    stream: &'a Stream,
+    idle: Duration,
+    deadline: Option<Instant>
+}
+
+impl<'a> Deadline<'a> {
+
+    pub fn new(stream: &'a Stream, idle: Duration) -> Deadline<'a> {
+        Deadline {
+            stream,
+            idle,
+            deadline: None
+        }
+    }
+
+    pub fn set_idle(&mut self, idle: Duration) {
+        self.idle = idle;
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+}
+
+impl<'a> Read for Deadline<'a> {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+
+        let timeout = match self.deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+                }
+                deadline - now
+            },
+            None => self.idle
+        };
+        self.stream.set_read_timeout(Some(timeout))?;
+        let mut stream = self.stream;
+        stream.read(buf)
+
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::connection::{ResponseWriter, Sink, Stream};
+    use std::io::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::fs::{self, File};
+    use std::env;
+
+    #[test]
+    fn test_response_writer() {
+        let mut out = vec![];
+        {
+            let mut writer = ResponseWriter::new(&mut out)
+                .header("Connection", "keep-alive");
+            writer.write_all(b"HTTP/1.1 200\r\nContent-Len").unwrap();
+            writer.write_all(b"gth: 5\r\n\r\nhel").unwrap();
+            writer.write_all(b"lo").unwrap();
+            assert!(writer.complete());
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200\r\nContent-Length: 5\r\nConnection: keep-alive\r\n\r\nhello"
+        );
+    }
+
+    #[test]
+    fn test_response_writer_head() {
+        let mut out = vec![];
+        {
+            let mut writer = ResponseWriter::new(&mut out)
+                .body(false);
+            writer.write_all(b"HTTP/1.1 200\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+            writer.write_all(b"world").unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200\r\nContent-Length: 5\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_send_file() {
+        let path = env::temp_dir().join("see_test_send_file");
+        let content = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        fs::write(&path, &content).unwrap();
+        let file = File::open(&path).unwrap();
+
+        // Copied into memory
+        let mut out = vec![];
+        out.send_file(&file, 10, 1000).unwrap();
+        assert_eq!(out, &content[10..1010]);
+        assert!(out.send_file(&file, 99_990, 100).is_err());
+
+        // Straight to the socket
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = Stream::Tcp(listener.accept().unwrap().0);
+        let reader = std::thread::spawn(move || {
+            let mut buf = vec![];
+            client.read_to_end(&mut buf).unwrap();
+            buf
+        });
+        (&stream).send_file(&file, 100, 90_000).unwrap();
+        (&stream).send_file(&file, 0, 5).unwrap();
+        assert_eq!((&stream).send_file(&file, 99_000, 2_000).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        drop(stream);
+
+        let buf = reader.join().unwrap();
+        assert_eq!(&buf[..90_000], &content[100..90_100]);
+        assert_eq!(&buf[90_000..90_005], &content[..5]);
+        assert_eq!(&buf[90_005..], &content[99_000..]);
+    }
+
+}
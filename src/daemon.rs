@@ -0,0 +1,243 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use crate::config::Daemon;
+use crate::signal::DRAIN_TIMEOUT;
+
+const NAME: &str = env!("CARGO_PKG_NAME");
+
+#[cfg(target_os = "macos")]
+pub static PID_PATH: &str = "/usr/local/var/run/see.pid";
+#[cfg(target_os = "linux")]
+pub static PID_PATH: &str = "/var/run/see.pid";
+#[cfg(target_os = "windows")]
+pub static PID_PATH: &str = "./see.pid";
+
+// The PID file of the running daemon, locked until the process ends
+static PID_FILE: OnceLock<(String, File)> = OnceLock::new();
+
+pub enum Status {
+    Running(u32),
+    Stopped,
+    // Left behind by a daemon that didn't stop normally
+    Stale
+}
+
+// Running as long as some process holds the lock of the PID file
+pub fn status(path: &str) -> Status {
+
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Status::Stopped
+    };
+    if lock(&file) {
+        return Status::Stale;
+    }
+    match read_pid(&mut file) {
+        Some(pid) => Status::Running(pid),
+        None => Status::Stale
+    }
+
+}
+
+// Detach from the terminal. Only the daemon returns, after `prepare`.
+// The command exits once it has started or failed to
+#[cfg(unix)]
+pub fn start<F: FnOnce() -> Result<(), String>>(daemon: &Daemon, prepare: F) -> Result<(), String> {
+
+    let pid_file = daemon.pid_file.as_deref().unwrap_or(PID_PATH);
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(pid_file)
+        .map_err(|err| format!("{} {}", pid_file, err))?;
+    if !lock(&file) {
+        return Err(match read_pid(&mut file) {
+            Some(pid) => format!("{} is already running, pid {}", NAME, pid),
+            None => format!("{} is already running", NAME)
+        });
+    }
+
+    // Errors about these still go to the terminal
+    let stdin = File::open("/dev/null").map_err(|err| err.to_string())?;
+    let stdout = output(&daemon.stdout)?;
+    let stderr = output(&daemon.stderr)?;
+
+    // The daemon writes its PID here when it's ready, or why it isn't
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().to_string());
+    }
+    let (mut ready, mut started) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error().to_string()),
+        0 => {},
+        _ => {
+            drop(started);
+            let mut pid = String::new();
+            let _ = ready.read_to_string(&mut pid);
+            if let Ok(pid) = pid.parse::<u32>() {
+                println!("{} is running, pid {}", NAME, pid);
+                std::process::exit(0);
+            }
+            if pid.is_empty() {
+                eprintln!("{} failed to start", NAME);
+            }else {
+                eprintln!("{}", pid);
+            }
+            std::process::exit(1);
+        }
+    }
+    drop(ready);
+
+    // A new session without a terminal, then a child that can't get one back
+    unsafe {
+        if libc::setsid() == -1 {
+            libc::_exit(1);
+        }
+        match libc::fork() {
+            -1 => libc::_exit(1),
+            0 => {},
+            _ => libc::_exit(0)
+        }
+        libc::dup2(stdin.as_raw_fd(), 0);
+        libc::dup2(stdout.as_raw_fd(), 1);
+        libc::dup2(stderr.as_raw_fd(), 2);
+    }
+
+    let pid = std::process::id().to_string();
+    let written = file.set_len(0)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| file.write_all(pid.as_bytes()));
+    if let Err(err) = written {
+        eprintln!("{} {}", pid_file, err);
+        std::process::exit(1);
+    }
+    let _ = PID_FILE.set((pid_file.to_string(), file));
+
+    if let Err(msg) = prepare() {
+        let _ = started.write_all(msg.as_bytes());
+        remove_pid_file();
+        std::process::exit(1);
+    }
+    let _ = started.write_all(pid.as_bytes());
+    Ok(())
+
+}
+
+#[cfg(not(unix))]
+pub fn start<F: FnOnce() -> Result<(), String>>(_: &Daemon, _: F) -> Result<(), String> {
+    Err(String::from("Running in the background is not supported on this system"))
+}
+
+// The daemon is stopping normally
+pub fn remove_pid_file() {
+    if let Some((path, _)) = PID_FILE.get() {
+        let _ = fs::remove_file(path);
+    }
+}
+
+// SIGTERM, then wait for the requests in progress
+#[cfg(unix)]
+pub fn stop(path: &str) -> Result<(), String> {
+
+    let pid = match status(path) {
+        Status::Running(pid) => pid,
+        Status::Stopped => return Err(format!("{} is not running", NAME)),
+        Status::Stale => {
+            let _ = fs::remove_file(path);
+            return Err(format!("{} is not running, removed the stale {}", NAME, path));
+        }
+    };
+
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(format!("kill {} failed, {}", pid, io::Error::last_os_error()));
+    }
+
+    let deadline = Instant::now() + DRAIN_TIMEOUT + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if let Status::Running(_) = status(path) {
+            std::thread::sleep(Duration::from_millis(100));
+            continue;
+        }
+        return Ok(());
+    }
+    Err(format!("{} is still running, pid {}", NAME, pid))
+
+}
+
+#[cfg(not(unix))]
+pub fn stop(_: &str) -> Result<(), String> {
+    Err(String::from("Running in the background is not supported on this system"))
+}
+
+#[cfg(unix)]
+fn lock(file: &File) -> bool {
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) == 0 }
+}
+
+#[cfg(not(unix))]
+fn lock(_: &File) -> bool {
+    true
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut pid = String::new();
+    if file.read_to_string(&mut pid).is_err() {
+        return None;
+    }
+    pid.trim().parse().ok()
+}
+
+#[cfg(unix)]
+fn output(path: &Option<String>) -> Result<File, String> {
+    let path = match path {
+        Some(path) => path.as_str(),
+        None => "/dev/null"
+    };
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(|err| format!("{} {}", path, err))
+}
+
+
+#[cfg(all(test, unix))]
+mod tests {
+
+    use crate::daemon::{status, lock, Status};
+    use std::fs;
+    use std::fs::File;
+    use std::env;
+
+    #[test]
+    fn test_status() {
+        let path = env::temp_dir().join("see_test_status.pid");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        assert!(matches!(status(path), Status::Stopped));
+
+        // Nobody holds the lock, the process is gone
+        fs::write(path, "12345").unwrap();
+        assert!(matches!(status(path), Status::Stale));
+
+        let running = File::open(path).unwrap();
+        assert!(lock(&running));
+        assert!(matches!(status(path), Status::Running(12345)));
+        drop(running);
+        assert!(matches!(status(path), Status::Stale));
+        let _ = fs::remove_file(path);
+    }
+
+}
@@ -0,0 +1,926 @@
+extern crate mio;
+
+use std::io;
+use std::io::prelude::*;
+use std::io::{Cursor, SeekFrom};
+use std::fs::File;
+use std::net;
+use std::net::IpAddr;
+use std::thread;
+use std::cell::RefCell;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use mio::{Events, Interest, Poll, Token};
+use mio::net::{TcpListener, TcpStream};
+use rustls::{ServerConnection, StreamOwned};
+use crate::config::Header;
+use crate::connection::{Sink, Stream, truncated};
+#[cfg(target_os = "linux")]
+use crate::connection::{sendfile, sendfile_unsupported};
+use crate::request::{Request, Decoder, body_status};
+use crate::response::{StatusCode, CompressedFile};
+use crate::log::Log;
+use crate::pool::Pool;
+use crate::http2;
+use crate::access;
+use crate::site::{Site, Address};
+use crate::signal;
+use crate::{find_config, output, output_close, output_unavailable, response_writer};
+
+// The event engine.
+// Each thread waits on many nonblocking connections (epoll, kqueue) and only works on the ready ones,
+// so a slow client costs a little memory instead of a thread. Requests are answered by `output`
+// like on the thread engine, the response is queued and sent as fast as the client takes it
+
+const LISTENER: Token = Token(usize::MAX);
+
+// How often the deadlines are checked
+const TICK: Duration = Duration::from_millis(100);
+
+// Read from the socket at a time
+const READ_SIZE: usize = 16 * 1024;
+
+// Read from a file at a time while sending it
+const FILE_CHUNK: usize = 64 * 1024;
+
+// Shared by the threads of a listener
+struct Shared {
+    address: Arc<Address>,
+    // Open connections of all threads
+    active: AtomicUsize,
+    // HTTP/2 is served by blocking threads, started with the first one
+    pool: OnceLock<Pool>
+}
+
+impl Shared {
+
+    fn pool(&self) -> &Pool {
+        self.pool.get_or_init(|| {
+            let configs = &self.address.site().configs;
+            Pool::new(configs[0].workers, configs[0].max_connections)
+        })
+    }
+
+}
+
+pub fn serve(listener: net::TcpListener, address: Arc<Address>) {
+
+    // More threads than CPUs don't help
+    let threads = match thread::available_parallelism() {
+        Ok(n) => n.get(),
+        Err(_) => 1
+    }.min(address.site().configs[0].workers);
+
+    if let Err(err) = listener.set_nonblocking(true) {
+        return eprintln!("{:?}", err);
+    }
+
+    let shared = Arc::new(Shared {
+        pool: OnceLock::new(),
+        address,
+        active: AtomicUsize::new(0)
+    });
+
+    // Every thread waits on the listener, the first to wake up takes the connection
+    let mut tasks = vec![];
+    for _ in 0..threads {
+        let listener = match listener.try_clone() {
+            Ok(listener) => TcpListener::from_std(listener),
+            Err(err) => {
+                eprintln!("{:?}", err);
+                continue;
+            }
+        };
+        let shared = shared.clone();
+        tasks.push(thread::spawn(move || {
+            match EventLoop::new(listener, shared) {
+                Ok(mut event_loop) => event_loop.run(),
+                Err(err) => eprintln!("{:?}", err)
+            }
+        }));
+    }
+
+    // The port is closed once every thread has dropped its own
+    drop(listener);
+    for task in tasks {
+        let _ = task.join();
+    }
+
+}
+
+
+struct EventLoop {
+    poll: Poll,
+    // Dropped when the address is closed
+    listener: Option<TcpListener>,
+    connections: HashMap<Token, Connection>,
+    next: usize,
+    shared: Arc<Shared>
+}
+
+impl EventLoop {
+
+    fn new(mut listener: TcpListener, shared: Arc<Shared>) -> io::Result<EventLoop> {
+
+        let poll = Poll::new()?;
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+
+        Ok(EventLoop {
+            poll,
+            listener: Some(listener),
+            connections: HashMap::new(),
+            next: 0,
+            shared
+        })
+
+    }
+
+    fn run(&mut self) {
+
+        let mut events = Events::with_capacity(1024);
+        let mut check = Instant::now() + TICK;
+
+        loop {
+            if let Err(err) = self.poll.poll(&mut events, Some(TICK)) {
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return eprintln!("{:?}", err);
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    token => self.ready(token)
+                }
+            }
+
+            let now = Instant::now();
+            if now >= check {
+                self.expire(now);
+                self.stop();
+                check = now + TICK;
+            }
+
+            if self.listener.is_none() && self.connections.is_empty() {
+                return;
+            }
+        }
+
+    }
+
+    // No new connections once the address is closed,
+    // when the server stops the idle ones are closed as well
+    fn stop(&mut self) {
+
+        if self.shared.address.closed() || signal::stopping() {
+            if let Some(mut listener) = self.listener.take() {
+                let _ = self.poll.registry().deregister(&mut listener);
+            }
+        }
+
+        if signal::stopping() {
+            let idle = self.connections
+                .iter()
+                .filter(|(_, connection)| connection.idle())
+                .map(|(token, _)| *token)
+                .collect::<Vec<Token>>();
+            for token in idle {
+                self.next_step(token, Next::Close);
+            }
+        }
+
+    }
+
+    fn accept(&mut self) {
+
+        let listener = match &self.listener {
+            Some(listener) => listener,
+            None => return
+        };
+
+        loop {
+            let (socket, peer) = match listener.accept() {
+                Ok((socket, addr)) => (socket, addr.ip()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                // Nothing more to accept for now
+                Err(_) => return
+            };
+
+            let shared = &self.shared;
+            let site = shared.address.site();
+            if shared.active.fetch_add(1, Ordering::SeqCst) >= site.configs[0].max_connections {
+                shared.active.fetch_sub(1, Ordering::SeqCst);
+                output_unavailable(socket.into(), &site.configs[0], site.tls.is_some());
+                continue;
+            }
+
+            let tls = match &site.tls {
+                Some(tls) => match ServerConnection::new(tls.clone()) {
+                    Ok(conn) => Some(Box::new(conn)),
+                    Err(_) => {
+                        shared.active.fetch_sub(1, Ordering::SeqCst);
+                        continue;
+                    }
+                },
+                None => None
+            };
+
+            let token = Token(self.next);
+            self.next += 1;
+            let mut connection = Connection::new(socket, tls, site, peer);
+            let interest = Interest::READABLE | Interest::WRITABLE;
+            if self.poll.registry().register(&mut connection.socket, token, interest).is_err() {
+                shared.active.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            self.connections.insert(token, connection);
+        }
+
+    }
+
+    fn ready(&mut self, token: Token) {
+
+        let next = match self.connections.get_mut(&token) {
+            Some(connection) => connection.advance(),
+            None => return
+        };
+        self.next_step(token, next);
+
+    }
+
+    fn expire(&mut self, now: Instant) {
+
+        let expired = self.connections
+            .iter()
+            .filter(|(_, connection)| connection.deadline <= now)
+            .map(|(token, _)| *token)
+            .collect::<Vec<Token>>();
+
+        for token in expired {
+            let next = match self.connections.get_mut(&token) {
+                Some(connection) => connection.expire(),
+                None => continue
+            };
+            self.next_step(token, next);
+        }
+
+    }
+
+    fn next_step(&mut self, token: Token, next: Next) {
+
+        let mut connection = match next {
+            Next::Close | Next::Upgrade => match self.connections.remove(&token) {
+                Some(connection) => connection,
+                None => return
+            },
+            _ => return
+        };
+        let _ = self.poll.registry().deregister(&mut connection.socket);
+        self.shared.active.fetch_sub(1, Ordering::SeqCst);
+
+        if let Next::Close = next {
+            return connection.close();
+        }
+
+        // HTTP/2 goes on in a blocking thread with what has been read so far
+        if self.shared.pool().full() {
+            return connection.close();
+        }
+        let Connection { socket, tls, input, site, peer, .. } = connection;
+        let socket: net::TcpStream = socket.into();
+        if socket.set_nonblocking(false).is_err() {
+            return;
+        }
+        let stream = match tls {
+            Some(tls) => Stream::Tls(Box::new(RefCell::new(StreamOwned::new(*tls, socket)))),
+            None => Stream::Tcp(socket)
+        };
+        self.shared.pool().execute(move || {
+            http2::serve_buffered(input, &stream, &site.configs, peer);
+        });
+
+    }
+
+}
+
+
+// What the connection is doing
+enum State {
+    // Waiting for a request head
+    Head,
+    // Receiving the body of a request, it's answered once all of it is there
+    Body(Box<Pending>),
+    // Sending a response, then the next request or the connection is closed
+    Write(bool)
+}
+
+// A request waiting for its body
+struct Pending {
+    request: Request,
+    decoder: Decoder,
+    // What has been decoded of the body
+    body: Vec<u8>,
+    log: Option<Log>,
+    headers: Vec<Header>
+}
+
+enum Next {
+    // Go on with the new state
+    Again,
+    // Until the socket is ready
+    Wait,
+    Close,
+    // To HTTP/2
+    Upgrade
+}
+
+enum Received {
+    Data,
+    Nothing,
+    Eof
+}
+
+struct Connection {
+    socket: TcpStream,
+    // Servers of the address when it was accepted
+    site: Arc<Site>,
+    tls: Option<Box<ServerConnection>>,
+    // The client, told by the PROXY protocol header of a trusted proxy
+    peer: Option<IpAddr>,
+    // What has arrived of that header while it's expected
+    proxy: Option<Vec<u8>>,
+    // Read but not parsed yet, pipelined requests wait here
+    input: Vec<u8>,
+    output: Outgoing,
+    state: State,
+    // Requests answered
+    count: usize,
+    // A request head or a stalled response is given up after it
+    deadline: Instant,
+    timeout: Duration,
+    eof: bool,
+    // Answering a request
+    busy: Option<signal::Busy>
+}
+
+impl Connection {
+
+    fn new(socket: TcpStream, tls: Option<Box<ServerConnection>>, site: Arc<Site>, peer: IpAddr) -> Connection {
+
+        let config = &site.configs[0];
+        let timeout = Duration::from_secs(config.limit.timeout);
+        let proxy = config.proxy_protocol && config.trusted_proxies.iter().any(|proxy| proxy.contains(peer));
+        Connection {
+            socket,
+            site,
+            tls,
+            peer: Some(peer),
+            proxy: if proxy { Some(vec![]) } else { None },
+            input: vec![],
+            output: Outgoing::default(),
+            state: State::Head,
+            count: 0,
+            deadline: Instant::now() + timeout,
+            timeout,
+            eof: false,
+            busy: None
+        }
+
+    }
+
+    // Do everything that can be done without waiting
+    fn advance(&mut self) -> Next {
+
+        loop {
+            let next = match self.state {
+                State::Head => self.head(),
+                State::Body(_) => self.body(),
+                State::Write(_) => self.write()
+            };
+            match next {
+                Next::Again => continue,
+                next => return next
+            }
+        }
+
+    }
+
+    // The deadline has passed
+    fn expire(&mut self) -> Next {
+
+        match self.state {
+            // In the middle of a request head or body
+            State::Head if !self.input.is_empty() => {
+                self.refuse(&[], StatusCode::_408);
+                self.advance()
+            },
+            State::Body(_) => {
+                self.refuse(&[], StatusCode::_408);
+                self.advance()
+            },
+            _ => Next::Close
+        }
+
+    }
+
+    // Waiting for a request that hasn't started
+    fn idle(&self) -> bool {
+        match self.state {
+            State::Head => self.input.is_empty(),
+            _ => false
+        }
+    }
+
+    fn head(&mut self) -> Next {
+
+        if self.proxy.is_some() {
+            return self.proxy();
+        }
+
+        let site = self.site.clone();
+        let limit = &site.configs[0].limit;
+
+        if self.count == 0 && self.h2() {
+            return Next::Upgrade;
+        }
+
+        if !self.input.is_empty() {
+            // h2c with prior knowledge
+            if self.count == 0 && self.input.starts_with(&http2::PREFACE[..4]) {
+                return Next::Upgrade;
+            }
+
+            let mut cursor = Cursor::new(&self.input[..]);
+            match Request::read(&mut cursor, limit) {
+                Ok(req) => {
+                    let end = cursor.position() as usize;
+                    self.input.drain(..end);
+                    return self.respond(req);
+                },
+                Err(status) => {
+                    // Request::read stops at the end of what has arrived,
+                    // that's only an error once the empty line is there
+                    let end = cursor.position() as usize == self.input.len();
+                    let whole = self.input.ends_with(b"\n\n") || self.input.ends_with(b"\n\r\n");
+                    if status != StatusCode::_400 || !end || whole {
+                        self.refuse(&[], status);
+                        return Next::Again;
+                    }
+                }
+            }
+        }
+
+        let empty = self.input.is_empty();
+        match self.receive(limit.url_size + limit.header_size + READ_SIZE) {
+            Ok(Received::Data) => {
+                // Once it starts, the whole request head has to arrive in time
+                if empty {
+                    self.deadline = Instant::now() + self.timeout;
+                }
+                Next::Again
+            },
+            Ok(Received::Nothing) => {
+                if self.count == 0 && self.h2() {
+                    return Next::Upgrade;
+                }
+                Next::Wait
+            },
+            Ok(Received::Eof) => {
+                if empty {
+                    return Next::Close;
+                }
+                self.refuse(&[], StatusCode::_400);
+                Next::Again
+            },
+            Err(_) => Next::Close
+        }
+
+    }
+
+    // The PROXY protocol header, read without going past it
+    // since TLS or the request comes right after
+    fn proxy(&mut self) -> Next {
+
+        let header = match &mut self.proxy {
+            Some(header) => header,
+            None => return Next::Again
+        };
+
+        loop {
+            match access::parse(header) {
+                Ok(access::Header::Incomplete(len)) => {
+                    let start = header.len();
+                    header.resize(len, 0);
+                    match self.socket.read(&mut header[start..]) {
+                        Ok(0) => return Next::Close,
+                        Ok(size) => header.truncate(start + size),
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                            header.truncate(start);
+                            return Next::Wait;
+                        },
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => header.truncate(start),
+                        Err(_) => return Next::Close
+                    }
+                },
+                Ok(access::Header::Done(_, ip)) => {
+                    if let Some(ip) = ip {
+                        self.peer = Some(ip);
+                    }
+                    self.proxy = None;
+                    return Next::Again;
+                },
+                Err(_) => return Next::Close
+            }
+        }
+
+    }
+
+    // Like `handle_connection` of the thread engine, up to the body
+    fn respond(&mut self, mut req: Request) -> Next {
+
+        let site = self.site.clone();
+        req.peer = self.peer;
+        self.count += 1;
+        self.busy = Some(signal::busy());
+
+        let config = match find_config(&req, &site.configs) {
+            Ok(config) => config,
+            Err(status) => {
+                self.refuse(&[], status);
+                return Next::Again;
+            }
+        };
+
+        // The body is decoded as it arrives
+        let decoder = match req.decoder(config.max_body_size) {
+            Ok(decoder) => decoder,
+            Err(status) => {
+                if let Some(log) = &config.log.error {
+                    log.write(&req.method, status.code(), &req.path);
+                }
+                self.refuse(&config.headers, status);
+                return Next::Again;
+            }
+        };
+        if decoder.expect() {
+            let _ = self.output.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+        }
+
+        self.state = State::Body(Box::new(Pending {
+            request: req,
+            decoder,
+            body: vec![],
+            log: config.log.error.clone(),
+            headers: config.headers.clone()
+        }));
+        self.deadline = Instant::now() + self.timeout;
+        Next::Again
+
+    }
+
+    fn body(&mut self) -> Next {
+
+        let pending = match &mut self.state {
+            State::Body(pending) => pending,
+            _ => return Next::Close
+        };
+
+        // What has arrived is decoded and dropped, only the body is kept
+        let start = pending.body.len();
+        pending.body.resize(start + self.input.len(), 0);
+        match pending.decoder.decode(&self.input, &mut pending.body[start..]) {
+            Ok((used, written)) => {
+                pending.body.truncate(start + written);
+                self.input.drain(..used);
+            },
+            Err(err) => {
+                let status = body_status(&err);
+                if let (StatusCode::_413, Some(log)) = (status, &pending.log) {
+                    log.write(&pending.request.method, 413, &pending.request.path);
+                }
+                let headers = pending.headers.clone();
+                self.refuse(&headers, status);
+                return Next::Again;
+            }
+        }
+
+        if pending.decoder.done() {
+            return match std::mem::replace(&mut self.state, State::Head) {
+                State::Body(pending) => self.answer(pending.request, pending.body),
+                _ => Next::Close
+            };
+        }
+
+        // "100 Continue" goes out first
+        if self.send().is_err() {
+            return Next::Close;
+        }
+        match self.receive(READ_SIZE) {
+            Ok(Received::Data) => Next::Again,
+            Ok(Received::Nothing) => Next::Wait,
+            _ => Next::Close
+        }
+
+    }
+
+    // The whole request is there
+    fn answer(&mut self, req: Request, body: Vec<u8>) -> Next {
+
+        let site = self.site.clone();
+        let configs = &site.configs;
+
+        let keep = match &configs[0].keep_alive {
+            Some(keep_alive) => req.keep_alive() && self.count < keep_alive.requests && !signal::stopping(),
+            None => false
+        };
+
+        let config = match find_config(&req, configs) {
+            Ok(config) => config,
+            Err(status) => {
+                self.refuse(&[], status);
+                return Next::Again;
+            }
+        };
+
+        let mut writer = response_writer(&mut self.output, &req, &configs[0].keep_alive, keep);
+        let res = output(req, &mut &body[..], config, &mut writer);
+        if !res.is_empty() {
+            let _ = writer.write_all(&res);
+        }
+        let _ = writer.flush();
+
+        self.state = State::Write(keep && writer.complete());
+        self.deadline = Instant::now() + self.timeout;
+        Next::Again
+
+    }
+
+    // Respond with an error and close the connection
+    fn refuse(&mut self, headers: &[Header], status: StatusCode) {
+
+        output_close(&mut self.output, headers, status);
+        self.state = State::Write(false);
+        self.deadline = Instant::now() + self.timeout;
+
+    }
+
+    fn write(&mut self) -> Next {
+
+        match self.send() {
+            Ok(true) => {},
+            Ok(false) => return Next::Wait,
+            Err(_) => return Next::Close
+        }
+
+        match std::mem::replace(&mut self.state, State::Head) {
+            State::Write(true) => {
+                self.busy = None;
+                if let Some(keep_alive) = &self.site.configs[0].keep_alive {
+                    self.deadline = Instant::now() + Duration::from_secs(keep_alive.timeout);
+                }
+                Next::Again
+            },
+            _ => Next::Close
+        }
+
+    }
+
+    // Negotiated HTTP/2 with ALPN
+    fn h2(&self) -> bool {
+        match &self.tls {
+            Some(tls) => !tls.is_handshaking() && tls.alpn_protocol() == Some(b"h2"),
+            None => false
+        }
+    }
+
+    // Read what has arrived, until `input` holds `cap` bytes
+    fn receive(&mut self, cap: usize) -> io::Result<Received> {
+
+        let mut received = Received::Nothing;
+        let mut buf = [0; READ_SIZE];
+
+        while !self.eof && self.input.len() < cap {
+            let tls = match &mut self.tls {
+                Some(tls) => tls,
+                None => {
+                    match self.socket.read(&mut buf) {
+                        Ok(0) => self.eof = true,
+                        Ok(size) => {
+                            self.input.extend_from_slice(&buf[..size]);
+                            received = Received::Data;
+                        },
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+                        Err(err) => return Err(err)
+                    }
+                    continue;
+                }
+            };
+
+            // What has been decrypted comes first
+            match tls.reader().read(&mut buf) {
+                // The client has sent close_notify
+                Ok(0) => {
+                    self.eof = true;
+                    continue;
+                },
+                Ok(size) => {
+                    self.input.extend_from_slice(&buf[..size]);
+                    received = Received::Data;
+                    continue;
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                Err(err) => return Err(err)
+            }
+
+            match tls.read_tls(&mut self.socket) {
+                Ok(0) => self.eof = true,
+                Ok(_) => {
+                    if let Err(err) = tls.process_new_packets() {
+                        // Let the client know about it with an alert
+                        let _ = self.send_tls();
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                    }
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err)
+            }
+        }
+
+        // The handshake answers the client while reading
+        self.send_tls()?;
+
+        match received {
+            Received::Nothing if self.eof => Ok(Received::Eof),
+            received => Ok(received)
+        }
+
+    }
+
+    // Send the queued response as far as the socket takes it, true once it's all out
+    fn send(&mut self) -> io::Result<bool> {
+
+        loop {
+            if !self.send_tls()? {
+                return Ok(false);
+            }
+
+            // Plain connections send files with sendfile
+            #[cfg(target_os = "linux")]
+            if let (None, Some(Part::File(file, offset, len))) = (&self.tls, self.output.parts.front_mut()) {
+                match sendfile(&self.socket, file, offset, *len) {
+                    Ok(0) => return Err(truncated()),
+                    Ok(sent) => {
+                        *len -= sent as u64;
+                        if *len == 0 {
+                            self.output.parts.pop_front();
+                        }
+                        self.deadline = Instant::now() + self.timeout;
+                        continue;
+                    },
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) if sendfile_unsupported(&err) => {},
+                    Err(err) => return Err(err)
+                }
+            }
+
+            // Otherwise the next piece of it is read and sent like other bytes
+            if let Some(Part::File(file, offset, len)) = self.output.parts.front_mut() {
+                let mut chunk = vec![0; (*len).min(FILE_CHUNK as u64) as usize];
+                file.seek(SeekFrom::Start(*offset))?;
+                file.read_exact(&mut chunk)?;
+                *offset += chunk.len() as u64;
+                *len -= chunk.len() as u64;
+                if *len == 0 {
+                    self.output.parts.pop_front();
+                }
+                self.output.parts.push_front(Part::Bytes(chunk, 0));
+                continue;
+            }
+
+            if let Some(Part::Compressed(file)) = self.output.parts.front_mut() {
+                let chunk = file.next_chunk()?;
+                if file.finished() {
+                    self.output.parts.pop_front();
+                }
+                if !chunk.is_empty() {
+                    self.output.parts.push_front(Part::Bytes(chunk, 0));
+                }
+                continue;
+            }
+
+            let (bytes, sent) = match self.output.parts.front_mut() {
+                Some(Part::Bytes(bytes, sent)) => (bytes, sent),
+                _ => return Ok(true)
+            };
+
+            let size = match &mut self.tls {
+                // Taken until the TLS buffer is full
+                Some(tls) => {
+                    let size = tls.writer().write(&bytes[*sent..])?;
+                    if size == 0 && !tls.wants_write() {
+                        return Err(io::Error::new(io::ErrorKind::WriteZero, "TLS buffer is full"));
+                    }
+                    size
+                },
+                None => match self.socket.write(&bytes[*sent..]) {
+                    Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+                    Ok(size) => size,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err)
+                }
+            };
+
+            *sent += size;
+            if *sent == bytes.len() {
+                self.output.parts.pop_front();
+            }
+            // A client that keeps reading can take as long as it needs
+            if size != 0 {
+                self.deadline = Instant::now() + self.timeout;
+            }
+        }
+
+    }
+
+    // Write out the pending TLS records, false if the socket is full
+    fn send_tls(&mut self) -> io::Result<bool> {
+
+        if let Some(tls) = &mut self.tls {
+            while tls.wants_write() {
+                match tls.write_tls(&mut self.socket) {
+                    Ok(_) => {},
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+                    Err(err) => return Err(err)
+                }
+            }
+        }
+        Ok(true)
+
+    }
+
+    // Tell a TLS client the session ends here, not that the connection broke
+    fn close(mut self) {
+
+        if let Some(tls) = &mut self.tls {
+            tls.send_close_notify();
+        }
+        let _ = self.send_tls();
+
+    }
+
+}
+
+// A response waiting to be sent, files are read as the socket takes them
+#[derive(Default)]
+struct Outgoing {
+    parts: VecDeque<Part>
+}
+
+enum Part {
+    // The bytes and how many of them have been sent
+    Bytes(Vec<u8>, usize),
+    // The file, where to go on and how much is left
+    File(File, u64, u64),
+    // Compressed as the socket takes it
+    Compressed(Box<CompressedFile>)
+}
+
+impl Write for Outgoing {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.parts.back_mut() {
+            Some(Part::Bytes(bytes, _)) => bytes.extend_from_slice(buf),
+            _ => self.parts.push_back(Part::Bytes(buf.to_vec(), 0))
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+}
+
+impl Sink for Outgoing {
+
+    fn send_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+        if len != 0 {
+            self.parts.push_back(Part::File(file.try_clone()?, offset, len));
+        }
+        Ok(())
+    }
+
+    fn send_compressed(&mut self, file: CompressedFile) -> io::Result<()> {
+        self.parts.push_back(Part::Compressed(Box::new(file)));
+        Ok(())
+    }
+
+}
@@ -0,0 +1,297 @@
+use std::fs::Metadata;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{HashMap, BTreeMap};
+use crate::config::{CompressType, ETagType, Symlinks};
+use crate::resolve;
+use crate::response::compress_bytes;
+use crate::validator::Validator;
+
+pub const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+// Small files kept in memory with their compressed variants,
+// the least recently used ones are dropped when it is full
+#[derive(Debug)]
+pub struct FileCache {
+    // Total bytes of the files and variants
+    pub size: usize,
+    // Larger files are always read from disk
+    pub max_file_size: u64,
+    // Path answering with the counters
+    pub status: Option<String>,
+    store: Mutex<Store>,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    entries: HashMap<String, Entry>,
+    // Least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    used: usize
+}
+
+#[derive(Debug)]
+struct Entry {
+    // Changes with the size, modification time or inode of the file
+    stamp: Option<String>,
+    validator: Validator,
+    // The file itself is stored as CompressType::None
+    variants: Vec<(CompressType, Arc<Vec<u8>>)>,
+    tick: u64
+}
+
+impl FileCache {
+
+    pub fn new(size: usize, max_file_size: u64, status: Option<String>) -> FileCache {
+        FileCache {
+            size,
+            max_file_size,
+            status,
+            store: Mutex::new(Store::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        }
+    }
+
+    pub fn fits(&self, len: u64) -> bool {
+        len <= self.max_file_size && len as usize <= self.size
+    }
+
+    // Bytes of the file encoded with `mode` and its validator,
+    // None when it has to be served from disk
+    pub fn get(&self, path: &str, meta: &Metadata, mode: CompressType, etag: ETagType, symlinks: Symlinks) -> Option<(Arc<Vec<u8>>, Validator)> {
+
+        if !self.fits(meta.len()) {
+            return None;
+        }
+        let stamp = stamp(meta);
+
+        let found = self.store.lock().unwrap().find(path, &stamp, mode);
+        let (data, validator) = match found {
+            Some((data, validator, true)) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Some((data, validator));
+            },
+            Some((data, validator, false)) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                (data, validator)
+            },
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                let mut data = vec![];
+                if resolve::open(path, symlinks).and_then(|mut file| file.read_to_end(&mut data)).is_err() {
+                    return None;
+                }
+                // Changed since the metadata was read
+                if data.len() as u64 != meta.len() {
+                    return None;
+                }
+                let data = Arc::new(data);
+                let validator = Validator::new(meta, etag);
+                let entry = Entry {
+                    stamp: stamp.clone(),
+                    validator: validator.clone(),
+                    variants: vec![(CompressType::None, data.clone())],
+                    tick: 0
+                };
+                self.store.lock().unwrap().insert(path, entry, self.size);
+                (data, validator)
+            }
+        };
+
+        if let CompressType::None = mode {
+            return Some((data, validator));
+        }
+        // Compressed once, outside of the lock
+        let min = match compress_bytes(mode, &data) {
+            Ok(min) => Arc::new(min),
+            Err(_) => return None
+        };
+        self.store.lock().unwrap().add(path, &stamp, mode, min.clone(), self.size);
+        Some((min, validator))
+
+    }
+
+    // Counters for monitoring
+    pub fn report(&self) -> String {
+        let store = self.store.lock().unwrap();
+        format!(
+            "hits: {}\nmisses: {}\nfiles: {}\nsize: {}\n",
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            store.entries.len(),
+            store.used
+        )
+    }
+
+}
+
+impl Store {
+
+    // The data of `mode` if it's there, otherwise the file itself to compress.
+    // A stale entry is dropped
+    fn find(&mut self, path: &str, stamp: &Option<String>, mode: CompressType) -> Option<(Arc<Vec<u8>>, Validator, bool)> {
+
+        let fresh = match self.entries.get(path) {
+            Some(entry) => entry.stamp == *stamp,
+            None => return None
+        };
+        if !fresh {
+            self.remove(path);
+            return None;
+        }
+
+        self.touch(path);
+        let entry = &self.entries[path];
+        let data = |mode| entry.variants.iter().find(|(m, _)| *m == mode).map(|(_, data)| data.clone());
+        match data(mode) {
+            Some(data) => Some((data, entry.validator.clone(), true)),
+            None => data(CompressType::None).map(|data| (data, entry.validator.clone(), false))
+        }
+
+    }
+
+    fn insert(&mut self, path: &str, mut entry: Entry, limit: usize) {
+
+        self.remove(path);
+        let size = entry.variants.iter().map(|(_, data)| data.len()).sum();
+        if !self.reserve(size, limit, path) {
+            return;
+        }
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.order.insert(entry.tick, path.to_string());
+        self.entries.insert(path.to_string(), entry);
+        self.used += size;
+
+    }
+
+    // A compressed variant of an entry that is still the same file
+    fn add(&mut self, path: &str, stamp: &Option<String>, mode: CompressType, data: Arc<Vec<u8>>, limit: usize) {
+
+        match self.entries.get(path) {
+            Some(entry) if entry.stamp == *stamp => {
+                if entry.variants.iter().any(|(m, _)| *m == mode) {
+                    return;
+                }
+            },
+            _ => return
+        }
+        self.touch(path);
+        if !self.reserve(data.len(), limit, path) {
+            return;
+        }
+        self.used += data.len();
+        if let Some(entry) = self.entries.get_mut(path) {
+            entry.variants.push((mode, data));
+        }
+
+    }
+
+    // Drops the least recently used entries, except `path`, until `size` more bytes fit
+    fn reserve(&mut self, size: usize, limit: usize, path: &str) -> bool {
+
+        while self.used + size > limit {
+            let oldest = match self.order.values().find(|p| *p != path) {
+                Some(oldest) => oldest.clone(),
+                None => return false
+            };
+            self.remove(&oldest);
+        }
+        true
+
+    }
+
+    fn touch(&mut self, path: &str) {
+
+        if let Some(entry) = self.entries.get_mut(path) {
+            self.order.remove(&entry.tick);
+            self.tick += 1;
+            entry.tick = self.tick;
+            self.order.insert(entry.tick, path.to_string());
+        }
+
+    }
+
+    fn remove(&mut self, path: &str) {
+
+        if let Some(entry) = self.entries.remove(path) {
+            self.order.remove(&entry.tick);
+            self.used -= entry.variants.iter().map(|(_, data)| data.len()).sum::<usize>();
+        }
+
+    }
+
+}
+
+// The strong entity tag is derived from the size, modification time and inode
+fn stamp(meta: &Metadata) -> Option<String> {
+    Validator::new(meta, ETagType::Strong).etag
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::file_cache::FileCache;
+    use crate::config::{CompressType, ETagType, Symlinks};
+    use std::fs;
+    use std::env;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_file_cache() {
+        let dir = env::temp_dir().join("see_test_file_cache");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        fs::write(path("a"), vec![b'a'; 40]).unwrap();
+        fs::write(path("b"), vec![b'b'; 40]).unwrap();
+        fs::write(path("c"), vec![b'c'; 40]).unwrap();
+        fs::write(path("big"), vec![b'x'; 200]).unwrap();
+
+        let cache = FileCache::new(100, 50, None);
+        let get = |name: &str, mode| {
+            let meta = fs::metadata(path(name)).unwrap();
+            cache.get(&path(name), &meta, mode, ETagType::Strong, Symlinks::Follow).map(|(data, _)| data.to_vec())
+        };
+
+        assert_eq!(get("a", CompressType::None), Some(vec![b'a'; 40]));
+        assert_eq!(get("a", CompressType::None), Some(vec![b'a'; 40]));
+        assert_eq!(get("big", CompressType::None), None);
+        assert_eq!(cache.report(), "hits: 1\nmisses: 1\nfiles: 1\nsize: 40\n");
+
+        // "a" is used more recently than "b", "b" is dropped for "c"
+        get("b", CompressType::None);
+        get("a", CompressType::None);
+        get("c", CompressType::None);
+        assert_eq!(cache.report(), "hits: 2\nmisses: 3\nfiles: 2\nsize: 80\n");
+        get("b", CompressType::None);
+        assert_eq!(cache.report(), "hits: 2\nmisses: 4\nfiles: 2\nsize: 80\n");
+
+        // A compressed variant is kept next to the file
+        let gzip = get("b", CompressType::Gzip).unwrap();
+        assert_ne!(gzip, vec![b'b'; 40]);
+        assert_eq!(get("b", CompressType::Gzip), Some(gzip.clone()));
+        assert_eq!(cache.report(), format!("hits: 4\nmisses: 4\nfiles: 1\nsize: {}\n", 40 + gzip.len()));
+
+        // A modified file is read again
+        fs::write(path("b"), vec![b'B'; 40]).unwrap();
+        let file = fs::File::options().write(true).open(path("b")).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(get("b", CompressType::None), Some(vec![b'B'; 40]));
+        fs::write(path("b"), vec![b'B'; 30]).unwrap();
+        assert_eq!(get("b", CompressType::None), Some(vec![b'B'; 30]));
+        let other = get("b", CompressType::Gzip).unwrap();
+        assert_ne!(other, gzip);
+        assert_eq!(cache.report(), format!("hits: 5\nmisses: 6\nfiles: 1\nsize: {}\n", 30 + other.len()));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+}
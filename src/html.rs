@@ -1,7 +1,7 @@
 
 
 // HTML directory template
-pub const TEMPLATE: &'static str = r#"<!DOCTYPE html>
+pub const TEMPLATE: &str = r#"<!DOCTYPE html>
 <html>
 <head>
     <meta charset="UTF-8">
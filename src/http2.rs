@@ -0,0 +1,1463 @@
+extern crate hpack;
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::fs::File;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use hpack::Decoder;
+use hpack::encoder::encode_integer;
+use crate::config::{ServerConfig, Limit};
+use crate::connection::{Deadline, Stream, Sink, copy_file, write_compressed};
+use crate::request::Request;
+use crate::response::{Response, StatusCode, CompressedFile};
+use crate::signal;
+use crate::{find_config, output};
+
+// HTTP/2 (RFC 7540).
+// Requests are answered one after another in the order their headers complete,
+// the responses come from `output` like on HTTP/1.1 and are turned into frames here.
+// Their bodies wait on their streams and go out a DATA frame per stream in turn, as the windows allow
+
+// The client starts with it, an h2c client with prior knowledge sends it instead of a request line
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// Settings
+const ENABLE_PUSH: u16 = 0x2;
+const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const INITIAL_WINDOW_SIZE: u16 = 0x4;
+const MAX_FRAME_SIZE: u16 = 0x5;
+const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// The largest frame we accept, the default of the protocol
+const FRAME_SIZE: usize = 16384;
+const WINDOW: i64 = 65535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+const MAX_STREAMS: usize = 100;
+// Size of the HPACK dynamic table of the client, the default of the protocol
+const TABLE_SIZE: usize = 4096;
+
+// Headers that only mean something to HTTP/1.1 connections
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+
+// Serve an HTTP/2 connection, the preface is still in the reader
+pub fn serve(reader: &mut BufReader<Deadline>, stream: &Stream, configs: &Arc<Vec<ServerConfig>>, peer: Option<IpAddr>) {
+
+    reader.get_mut().set_deadline(None);
+    reader.get_mut().set_idle(idle(configs));
+
+    connection(reader, stream, configs, peer);
+
+}
+
+// A connection handed over by the event engine with what it has already read
+pub fn serve_buffered(buffered: Vec<u8>, stream: &Stream, configs: &Arc<Vec<ServerConfig>>, peer: Option<IpAddr>) {
+
+    let reader = io::Cursor::new(buffered).chain(Deadline::new(stream, idle(configs)));
+    connection(BufReader::new(reader), stream, configs, peer);
+
+}
+
+// Streams come and go, the connection only waits for the idle time between frames
+fn idle(configs: &Arc<Vec<ServerConfig>>) -> Duration {
+    let idle = match &configs[0].keep_alive {
+        Some(keep_alive) => keep_alive.timeout,
+        None => configs[0].limit.timeout
+    };
+    Duration::from_secs(idle)
+}
+
+fn connection<R: Arrived, W: Write>(mut reader: R, writer: W, configs: &Arc<Vec<ServerConfig>>, peer: Option<IpAddr>) {
+
+    let mut preface = [0; 24];
+    if reader.read_exact(&mut preface).is_err() {
+        return;
+    }
+    if preface[..] != PREFACE[..] {
+        return;
+    }
+
+    let limit = &configs[0].limit;
+    let mut conn = Connection {
+        reader,
+        writer,
+        peer,
+        decoder: Decoder::new(),
+        frame_size: FRAME_SIZE,
+        initial_window: WINDOW,
+        window: WINDOW,
+        streams: HashMap::new(),
+        max_body: configs.iter().map(|config| config.max_body_size).max().unwrap_or(0),
+        ready: VecDeque::new(),
+        turn: 0,
+        block: None,
+        block_size: 4 * (limit.header_size + limit.url_size),
+        last: 0,
+        goaway: false
+    };
+
+    if let Err(Close::Error(code)) = conn.run(configs) {
+        let mut payload = conn.last.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        let _ = conn.write_frame(GOAWAY, 0, 0, &payload);
+    }
+
+}
+
+
+// Frames that have already arrived are read before more DATA is sent,
+// so the requests that come together are answered together
+trait Arrived: Read {
+    fn arrived(&self) -> bool;
+}
+
+impl<R: Read> Arrived for BufReader<R> {
+    fn arrived(&self) -> bool {
+        !self.buffer().is_empty()
+    }
+}
+
+impl<T: Arrived> Arrived for &mut T {
+    fn arrived(&self) -> bool {
+        (**self).arrived()
+    }
+}
+
+
+// Why the connection ends
+#[derive(Debug)]
+enum Close {
+    // The socket failed or the client is gone
+    Io,
+    // Sent to the client in GOAWAY
+    Error(u32)
+}
+
+impl From<io::Error> for Close {
+    fn from(err: io::Error) -> Close {
+        match err.kind() {
+            // Idle for too long
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Close::Error(NO_ERROR),
+            _ => Close::Io
+        }
+    }
+}
+
+// Decoded header block, name and value pairs
+type Fields = Vec<(Vec<u8>, Vec<u8>)>;
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    id: u32,
+    payload: Vec<u8>
+}
+
+// A stream that has a request
+struct Open {
+    // How much the client lets us send
+    window: i64,
+    // The client has sent the whole request
+    closed: bool,
+    // What has arrived of the body, up to one byte past `max_body`
+    body: Vec<u8>,
+    // The request, until its body is complete
+    fields: Option<Fields>,
+    // The body of the response, once it has been given
+    response: Option<Queue>
+}
+
+struct Connection<R: Arrived, W: Write> {
+    reader: R,
+    writer: W,
+    // The address of the client, for every request
+    peer: Option<IpAddr>,
+    decoder: Decoder<'static>,
+    // Settings of the client
+    frame_size: usize,
+    initial_window: i64,
+    // Send window of the connection
+    window: i64,
+    streams: HashMap<u32, Open>,
+    // The largest body a server takes
+    max_body: u64,
+    // Requests waiting for their response
+    ready: VecDeque<(u32, Fields)>,
+    // The stream of the last DATA frame
+    turn: u32,
+    // A header block continued in CONTINUATION frames, with the flags of its HEADERS frame
+    block: Option<(u32, u8, Vec<u8>)>,
+    block_size: usize,
+    // The highest stream the client has opened
+    last: u32,
+    goaway: bool
+}
+
+impl<R: Arrived, W: Write> Connection<R, W> {
+
+    fn run(&mut self, configs: &Arc<Vec<ServerConfig>>) -> Result<(), Close> {
+
+        let mut settings = vec![];
+        for (key, value) in [
+            (ENABLE_PUSH, 0),
+            (MAX_CONCURRENT_STREAMS, MAX_STREAMS as u32),
+            (MAX_HEADER_LIST_SIZE, configs[0].limit.header_size as u32)
+        ] {
+            settings.extend_from_slice(&key.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        self.write_frame(SETTINGS, 0, 0, &settings)?;
+
+        loop {
+            if let Some((id, fields)) = self.ready.pop_front() {
+                let _busy = signal::busy();
+                self.respond(configs, id, fields)?;
+                continue;
+            }
+            if !self.reader.arrived() && self.send()? {
+                continue;
+            }
+            let sending = self.streams.values().any(|open| open.response.is_some());
+            // The client will not open more streams
+            if self.goaway && !sending {
+                return Ok(());
+            }
+            // Lets the client know the streams after `last` are not served
+            if signal::stopping() && !sending {
+                return Err(Close::Error(NO_ERROR));
+            }
+            let frame = self.read_frame()?;
+            self.handle(frame)?;
+        }
+
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, Close> {
+
+        let mut head = [0; 9];
+        self.reader.read_exact(&mut head)?;
+        let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+        if len > FRAME_SIZE {
+            return Err(Close::Error(FRAME_SIZE_ERROR));
+        }
+        let mut payload = vec![0; len];
+        self.reader.read_exact(&mut payload)?;
+
+        Ok(Frame {
+            kind: head[3],
+            flags: head[4],
+            id: u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff,
+            payload
+        })
+
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, id: u32, payload: &[u8]) -> Result<(), Close> {
+
+        let len = (payload.len() as u32).to_be_bytes();
+        let mut frame = vec![len[1], len[2], len[3], kind, flags];
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.writer.write_all(&frame)?;
+        self.writer.flush()?;
+        Ok(())
+
+    }
+
+    // Stream error, the connection goes on
+    fn reset(&mut self, id: u32, code: u32) -> Result<(), Close> {
+
+        self.streams.remove(&id);
+        self.ready.retain(|(i, _)| *i != id);
+        self.write_frame(RST_STREAM, 0, id, &code.to_be_bytes())
+
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<(), Close> {
+
+        // Nothing may come between the frames of a header block
+        if let Some((id, _, _)) = &self.block {
+            if frame.kind != CONTINUATION || frame.id != *id {
+                return Err(Close::Error(PROTOCOL_ERROR));
+            }
+        }
+
+        match frame.kind {
+            DATA => self.data(frame),
+            HEADERS => self.headers(frame),
+            PRIORITY => {
+                if frame.id == 0 {
+                    return Err(Close::Error(PROTOCOL_ERROR));
+                }
+                Ok(())
+            },
+            RST_STREAM => {
+                if frame.id == 0 {
+                    return Err(Close::Error(PROTOCOL_ERROR));
+                }
+                if frame.payload.len() != 4 {
+                    return Err(Close::Error(FRAME_SIZE_ERROR));
+                }
+                self.streams.remove(&frame.id);
+                self.ready.retain(|(i, _)| *i != frame.id);
+                Ok(())
+            },
+            SETTINGS => self.settings(frame),
+            PUSH_PROMISE => Err(Close::Error(PROTOCOL_ERROR)),
+            PING => {
+                if frame.id != 0 {
+                    return Err(Close::Error(PROTOCOL_ERROR));
+                }
+                if frame.payload.len() != 8 {
+                    return Err(Close::Error(FRAME_SIZE_ERROR));
+                }
+                if frame.flags & ACK == 0 {
+                    self.write_frame(PING, ACK, 0, &frame.payload)?;
+                }
+                Ok(())
+            },
+            GOAWAY => {
+                self.goaway = true;
+                Ok(())
+            },
+            WINDOW_UPDATE => self.window_update(frame),
+            CONTINUATION => {
+                let (id, flags, mut block) = match self.block.take() {
+                    Some(block) => block,
+                    None => return Err(Close::Error(PROTOCOL_ERROR))
+                };
+                block.extend_from_slice(&frame.payload);
+                if block.len() > self.block_size {
+                    return Err(Close::Error(ENHANCE_YOUR_CALM));
+                }
+                if frame.flags & END_HEADERS != 0 {
+                    self.request(id, flags, block)
+                }else {
+                    self.block = Some((id, flags, block));
+                    Ok(())
+                }
+            },
+            // Unknown frame types are ignored
+            _ => Ok(())
+        }
+
+    }
+
+    // Payload without the padding
+    fn unpad(frame: &Frame) -> Result<&[u8], Close> {
+
+        if frame.flags & PADDED == 0 {
+            return Ok(&frame.payload);
+        }
+        let pad = match frame.payload.first() {
+            Some(pad) => *pad as usize,
+            None => return Err(Close::Error(FRAME_SIZE_ERROR))
+        };
+        if pad >= frame.payload.len() {
+            return Err(Close::Error(PROTOCOL_ERROR));
+        }
+        Ok(&frame.payload[1..frame.payload.len() - pad])
+
+    }
+
+    fn data(&mut self, frame: Frame) -> Result<(), Close> {
+
+        if frame.id == 0 {
+            return Err(Close::Error(PROTOCOL_ERROR));
+        }
+        let data = Connection::<R, W>::unpad(&frame)?;
+
+        // The body is bounded by `max_body`, so the whole frame is given back right away
+        let len = frame.payload.len() as u32;
+        if len != 0 {
+            self.write_frame(WINDOW_UPDATE, 0, 0, &len.to_be_bytes())?;
+        }
+
+        match self.streams.get_mut(&frame.id) {
+            Some(open) if !open.closed => {
+                open.closed = frame.flags & END_STREAM != 0;
+                if open.fields.is_some() {
+                    let room = (self.max_body + 1).saturating_sub(open.body.len() as u64);
+                    open.body.extend_from_slice(&data[..data.len().min(room as usize)]);
+                }
+                // Answered once the body is complete, or with 413 once it is too large
+                if open.closed || open.body.len() as u64 > self.max_body {
+                    if let Some(fields) = open.fields.take() {
+                        self.ready.push_back((frame.id, fields));
+                    }
+                }
+                if len != 0 && !open.closed {
+                    self.write_frame(WINDOW_UPDATE, 0, frame.id, &len.to_be_bytes())?;
+                }
+                Ok(())
+            },
+            Some(_) => self.reset(frame.id, STREAM_CLOSED),
+            // Never opened
+            None if frame.id > self.last => Err(Close::Error(PROTOCOL_ERROR)),
+            // Already answered and reset
+            None => Ok(())
+        }
+
+    }
+
+    fn headers(&mut self, frame: Frame) -> Result<(), Close> {
+
+        if frame.id == 0 {
+            return Err(Close::Error(PROTOCOL_ERROR));
+        }
+        let mut block = Connection::<R, W>::unpad(&frame)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            if block.len() < 5 {
+                return Err(Close::Error(FRAME_SIZE_ERROR));
+            }
+            block = &block[5..];
+        }
+        if block.len() > self.block_size {
+            return Err(Close::Error(ENHANCE_YOUR_CALM));
+        }
+
+        if frame.flags & END_HEADERS != 0 {
+            self.request(frame.id, frame.flags, block.to_vec())
+        }else {
+            self.block = Some((frame.id, frame.flags, block.to_vec()));
+            Ok(())
+        }
+
+    }
+
+    // A complete header block, a new request or the trailers of one
+    fn request(&mut self, id: u32, flags: u8, block: Vec<u8>) -> Result<(), Close> {
+
+        // Decoded even if it is not used, the table of the decoder depends on every block.
+        // The decoder panics on some malformed blocks, they are refused before
+        if !valid_block(&block) {
+            return Err(Close::Error(COMPRESSION_ERROR));
+        }
+        let fields = match self.decoder.decode(&block) {
+            Ok(fields) => fields,
+            Err(_) => return Err(Close::Error(COMPRESSION_ERROR))
+        };
+        let end = flags & END_STREAM != 0;
+
+        if id <= self.last {
+            return match self.streams.get_mut(&id) {
+                Some(open) if !open.closed && end => {
+                    open.closed = true;
+                    if let Some(fields) = open.fields.take() {
+                        self.ready.push_back((id, fields));
+                    }
+                    Ok(())
+                },
+                Some(_) => self.reset(id, PROTOCOL_ERROR),
+                None => Ok(())
+            };
+        }
+        // Streams of the client have odd numbers
+        if id & 1 == 0 {
+            return Err(Close::Error(PROTOCOL_ERROR));
+        }
+        self.last = id;
+
+        if self.streams.len() >= MAX_STREAMS {
+            return self.reset(id, REFUSED_STREAM);
+        }
+        let mut open = Open {
+            window: self.initial_window,
+            closed: end,
+            body: vec![],
+            fields: None,
+            response: None
+        };
+        // The body comes first, unless it is already known to be too large
+        let large = fields.iter().any(|(name, value)| {
+            name == b"content-length" && String::from_utf8_lossy(value).parse().is_ok_and(|size: u64| size > self.max_body)
+        });
+        if end || large {
+            self.ready.push_back((id, fields));
+        }else {
+            open.fields = Some(fields);
+        }
+        self.streams.insert(id, open);
+        Ok(())
+
+    }
+
+    fn settings(&mut self, frame: Frame) -> Result<(), Close> {
+
+        if frame.id != 0 {
+            return Err(Close::Error(PROTOCOL_ERROR));
+        }
+        if frame.flags & ACK != 0 {
+            if !frame.payload.is_empty() {
+                return Err(Close::Error(FRAME_SIZE_ERROR));
+            }
+            return Ok(());
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(Close::Error(FRAME_SIZE_ERROR));
+        }
+
+        for entry in frame.payload.chunks(6) {
+            let key = u16::from_be_bytes([entry[0], entry[1]]);
+            let value = u32::from_be_bytes([entry[2], entry[3], entry[4], entry[5]]);
+            match key {
+                ENABLE_PUSH if value > 1 => {
+                    return Err(Close::Error(PROTOCOL_ERROR));
+                },
+                INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return Err(Close::Error(FLOW_CONTROL_ERROR));
+                    }
+                    // Applies to the streams already open
+                    for open in self.streams.values_mut() {
+                        open.window += value - self.initial_window;
+                    }
+                    self.initial_window = value;
+                },
+                MAX_FRAME_SIZE => {
+                    if value < FRAME_SIZE as u32 || value > 16_777_215 {
+                        return Err(Close::Error(PROTOCOL_ERROR));
+                    }
+                    self.frame_size = value as usize;
+                },
+                // HEADER_TABLE_SIZE doesn't matter, responses don't use the dynamic table
+                _ => {}
+            }
+        }
+
+        self.write_frame(SETTINGS, ACK, 0, &[])
+
+    }
+
+    fn window_update(&mut self, frame: Frame) -> Result<(), Close> {
+
+        if frame.payload.len() != 4 {
+            return Err(Close::Error(FRAME_SIZE_ERROR));
+        }
+        let p = &frame.payload;
+        let increment = (u32::from_be_bytes([p[0], p[1], p[2], p[3]]) & 0x7fff_ffff) as i64;
+
+        if frame.id == 0 {
+            if increment == 0 {
+                return Err(Close::Error(PROTOCOL_ERROR));
+            }
+            self.window += increment;
+            if self.window > MAX_WINDOW {
+                return Err(Close::Error(FLOW_CONTROL_ERROR));
+            }
+            return Ok(());
+        }
+
+        let code = match self.streams.get_mut(&frame.id) {
+            Some(_) if increment == 0 => PROTOCOL_ERROR,
+            Some(open) => {
+                open.window += increment;
+                if open.window <= MAX_WINDOW {
+                    return Ok(());
+                }
+                FLOW_CONTROL_ERROR
+            },
+            None => return Ok(())
+        };
+        self.reset(frame.id, code)
+
+    }
+
+    fn respond(&mut self, configs: &Arc<Vec<ServerConfig>>, id: u32, fields: Fields) -> Result<(), Close> {
+
+        let peer = self.peer;
+        let body = match self.streams.get_mut(&id) {
+            Some(open) => std::mem::take(&mut open.body),
+            None => vec![]
+        };
+        let mut writer = Writer::new(self, id);
+
+        let res = match request(&fields, &configs[0].limit).map(|req| Request { peer, ..req }) {
+            Ok(req) => match find_config(&req, configs) {
+                Ok(config) => {
+                    writer.body = req.method != "HEAD";
+                    match body_size(&req) {
+                        Ok(size) if size <= config.max_body_size && body.len() as u64 <= config.max_body_size => {
+                            output(req, &mut &body[..], config, &mut writer)
+                        },
+                        result => {
+                            let status = match result {
+                                Ok(_) => StatusCode::_413,
+                                Err(status) => status
+                            };
+                            if let Some(log) = &config.log.error {
+                                log.write(&req.method, status.code(), &req.path);
+                            }
+                            Response::new(status, &config.headers)
+                                .text(&status.code().to_string())
+                        }
+                    }
+                },
+                Err(status) => Response::new(status, &[])
+                    .text(&status.code().to_string())
+            },
+            Err(status) => Response::new(status, &[])
+                .text(&status.code().to_string())
+        };
+        if !res.is_empty() {
+            let _ = writer.write_all(&res);
+        }
+        writer.finish()
+
+    }
+
+    // A DATA frame of the next stream that has a response and room for it, false if none has
+    fn send(&mut self) -> Result<bool, Close> {
+
+        let window = self.window;
+        let mut ids = self.streams.iter()
+            .filter(|(_, open)| match &open.response {
+                // The last frame can be empty
+                Some(queue) => queue.is_empty() || open.window.min(window) > 0,
+                None => false
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<u32>>();
+        ids.sort_unstable();
+        let id = match ids.iter().find(|id| **id > self.turn).or(ids.first()) {
+            Some(id) => *id,
+            None => return Ok(false)
+        };
+        self.turn = id;
+
+        let size = window.min(self.frame_size as i64);
+        let (data, end) = match self.streams.get_mut(&id) {
+            Some(Open { window, response: Some(queue), .. }) => {
+                let data = match queue.take(size.min(*window).max(0) as usize) {
+                    Ok(data) => data,
+                    Err(_) => {
+                        self.reset(id, INTERNAL_ERROR)?;
+                        return Ok(true);
+                    }
+                };
+                *window -= data.len() as i64;
+                (data, queue.is_empty())
+            },
+            _ => return Ok(false)
+        };
+
+        self.window -= data.len() as i64;
+        self.write_frame(DATA, if end { END_STREAM } else { 0 }, id, &data)?;
+        if end {
+            self.end(id)?;
+        }
+        Ok(true)
+
+    }
+
+    // The response is complete, the rest of the request body is not needed
+    fn end(&mut self, id: u32) -> Result<(), Close> {
+
+        if let Some(open) = self.streams.remove(&id) {
+            if !open.closed {
+                self.write_frame(RST_STREAM, 0, id, &NO_ERROR.to_be_bytes())?;
+            }
+        }
+        Ok(())
+
+    }
+
+}
+
+
+// Rebuild the request head from the header fields, so it's parsed like on HTTP/1.1
+fn request(fields: &[(Vec<u8>, Vec<u8>)], limit: &Limit) -> Result<Request, StatusCode> {
+
+    let (mut method, mut path, mut authority) = (None, None, None);
+    let mut head = vec![];
+    let (mut size, mut count) = (0, 0);
+
+    for (name, value) in fields {
+        // Field names are lowercase, line breaks would end the line of the head early
+        if name.is_empty()
+            || name.iter().any(|b| b.is_ascii_uppercase() || *b == b':' && name[0] != b':')
+            || name.iter().chain(value.iter()).any(|b| *b == b'\r' || *b == b'\n' || *b == 0) {
+            return Err(StatusCode::_400);
+        }
+        let value = String::from_utf8_lossy(value).to_string();
+        match &name[..] {
+            b":method" => method = Some(value),
+            b":path" => path = Some(value),
+            b":authority" => authority = Some(value),
+            b":scheme" => {},
+            name if name[0] == b':' => return Err(StatusCode::_400),
+            name => {
+                let name = String::from_utf8_lossy(name).to_string();
+                if CONNECTION_HEADERS.contains(&name.as_str()) {
+                    return Err(StatusCode::_400);
+                }
+                count += 1;
+                size += name.len() + value.len() + 4;
+                if count > limit.header_count || size > limit.header_size {
+                    return Err(StatusCode::_431);
+                }
+                head.push(format!("{}: {}", name, value));
+            }
+        }
+    }
+
+    let (method, path) = match (method, path) {
+        (Some(method), Some(path)) => (method, path),
+        _ => return Err(StatusCode::_400)
+    };
+    if path.len() > limit.url_size {
+        return Err(StatusCode::_414);
+    }
+    if !path.starts_with('/') || path.contains(' ') || method.contains(' ') {
+        return Err(StatusCode::_400);
+    }
+
+    // :authority takes the place of Host
+    let mut text = format!("{} {} HTTP/2.0\r\n", method, path);
+    if let Some(authority) = authority {
+        text.push_str(&format!("host: {}\r\n", authority));
+        head.retain(|line| !line.starts_with("host: "));
+    }
+    for line in head {
+        text.push_str(&line);
+        text.push_str("\r\n");
+    }
+    text.push_str("\r\n");
+
+    match Request::new(text.as_bytes()) {
+        Ok(req) => Ok(req),
+        Err(_) => Err(StatusCode::_400)
+    }
+
+}
+
+// Declared size of the request body
+fn body_size(req: &Request) -> Result<u64, StatusCode> {
+    match req.headers.get("content-length") {
+        Some(value) => match value.parse::<u64>() {
+            Ok(size) => Ok(size),
+            Err(_) => Err(StatusCode::_400)
+        },
+        None => Ok(0)
+    }
+}
+
+// The representations of a header block are whole, the strings are not decoded
+fn valid_block(block: &[u8]) -> bool {
+
+    let mut rest = block;
+    let mut first = true;
+    while let Some(&octet) = rest.first() {
+        let (prefix, literal) = match octet {
+            0x80..=0xff => (7, false),
+            0x40..=0x7f => (6, true),
+            // Dynamic table size update, only at the start of the block
+            0x20..=0x3f => {
+                match decode_integer(rest, 5) {
+                    Some((size, len)) if first && size <= TABLE_SIZE => rest = &rest[len..],
+                    _ => return false
+                }
+                continue;
+            },
+            _ => (4, true)
+        };
+        first = false;
+
+        let (index, len) = match decode_integer(rest, prefix) {
+            Some(integer) => integer,
+            None => return false
+        };
+        rest = &rest[len..];
+        if !literal {
+            continue;
+        }
+        // A literal name, then the value
+        for _ in 0..if index == 0 { 2 } else { 1 } {
+            match decode_integer(rest, 7) {
+                Some((size, len)) if size <= rest.len() - len => rest = &rest[len + size..],
+                _ => return false
+            }
+        }
+    }
+    true
+
+}
+
+// HPACK integer with a prefix of `prefix` bits, and the octets it takes
+fn decode_integer(buf: &[u8], prefix: u32) -> Option<(usize, usize)> {
+
+    let mask = (1 << prefix) - 1;
+    let mut value = (*buf.first()? & mask) as usize;
+    if value < mask as usize {
+        return Some((value, 1));
+    }
+    // Up to 2^28 more, like the decoder
+    for (i, &octet) in buf.iter().enumerate().skip(1).take(4) {
+        value += ((octet & 0x7f) as usize) << (7 * (i - 1));
+        if octet & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+
+}
+
+// Header block without the dynamic table
+fn encode(status: &str, headers: &[(String, String)]) -> Vec<u8> {
+
+    let mut block = vec![];
+    let string = |block: &mut Vec<u8>, value: &[u8]| {
+        block.extend(encode_integer(value.len(), 7));
+        block.extend_from_slice(value);
+    };
+
+    // Indexed from the static table, or a literal with the indexed name ":status"
+    match status {
+        "200" => block.push(0x80 | 8),
+        "204" => block.push(0x80 | 9),
+        "206" => block.push(0x80 | 10),
+        "304" => block.push(0x80 | 11),
+        "400" => block.push(0x80 | 12),
+        "404" => block.push(0x80 | 13),
+        "500" => block.push(0x80 | 14),
+        _ => {
+            block.extend(encode_integer(8, 4));
+            string(&mut block, status.as_bytes());
+        }
+    }
+
+    // Literal without indexing, new name
+    for (name, value) in headers {
+        block.push(0);
+        string(&mut block, name.as_bytes());
+        string(&mut block, value.as_bytes());
+    }
+
+    block
+
+}
+
+
+// Where the chunked body of a response is
+enum Chunk {
+    Size(Vec<u8>),
+    Data(usize),
+    // The line break after the data
+    End(usize),
+    Trailer(Vec<u8>),
+    Done
+}
+
+// The body of a response waiting for the windows, files are read as it's sent
+struct Queue {
+    parts: VecDeque<Part>,
+    // Read from the parts but not sent yet
+    data: Vec<u8>,
+    // The server waits for it before stopping
+    _busy: signal::Busy
+}
+
+enum Part {
+    Bytes(Vec<u8>),
+    // The file, where to go on and how much is left
+    File(File, u64, u64),
+    // Its chunks are complete, the framing is taken out as it's read
+    Compressed(Box<CompressedFile>)
+}
+
+impl Queue {
+
+    fn new() -> Queue {
+        Queue {
+            parts: VecDeque::new(),
+            data: vec![],
+            _busy: signal::busy()
+        }
+    }
+
+    fn push(&mut self, part: Part) {
+        match (self.parts.back_mut(), part) {
+            (Some(Part::Bytes(bytes)), Part::Bytes(more)) => bytes.extend_from_slice(&more),
+            (_, part) => self.parts.push_back(part)
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.parts.is_empty() && self.data.is_empty()
+    }
+
+    // At most `size` bytes of the body
+    fn take(&mut self, size: usize) -> io::Result<Vec<u8>> {
+
+        while self.data.len() < size {
+            match self.parts.pop_front() {
+                Some(Part::Bytes(bytes)) => self.data.extend_from_slice(&bytes),
+                Some(Part::File(file, offset, len)) => {
+                    let n = len.min((size - self.data.len()) as u64);
+                    copy_file(&file, offset, n, &mut self.data)?;
+                    if n < len {
+                        self.parts.push_front(Part::File(file, offset + n, len - n));
+                    }
+                },
+                Some(Part::Compressed(mut file)) => {
+                    let chunk = file.next_chunk()?;
+                    unchunk(&mut Chunk::Size(vec![]), &chunk, &mut self.data);
+                    if !file.finished() {
+                        self.parts.push_front(Part::Compressed(file));
+                    }
+                },
+                None => break
+            }
+        }
+
+        let rest = self.data.split_off(size.min(self.data.len()));
+        Ok(std::mem::replace(&mut self.data, rest))
+
+    }
+
+}
+
+// Sits between `output` and the connection like ResponseWriter does on HTTP/1.1.
+// Turns the HTTP/1.1 response head into a HEADERS frame, the body is queued on the stream
+struct Writer<'c, R: Arrived, W: Write> {
+    conn: &'c mut Connection<R, W>,
+    id: u32,
+    head: Vec<u8>,
+    sent: bool,
+    body: bool,
+    chunked: Option<Chunk>,
+    queue: Queue,
+    // END_STREAM was sent
+    ended: bool,
+    error: Option<Close>
+}
+
+impl<'c, R: Arrived, W: Write> Writer<'c, R, W> {
+
+    fn new(conn: &'c mut Connection<R, W>, id: u32) -> Writer<'c, R, W> {
+        Writer {
+            conn,
+            id,
+            head: vec![],
+            sent: false,
+            body: true,
+            chunked: None,
+            queue: Queue::new(),
+            ended: false,
+            error: None
+        }
+    }
+
+    fn send_head(&mut self) -> Result<(), Close> {
+
+        let head = String::from_utf8_lossy(&self.head).to_string();
+        let mut lines = head.split("\r\n");
+        let status = lines.next()
+            .and_then(|line| line.split(' ').nth(1))
+            .unwrap_or("500")
+            .to_string();
+
+        let mut headers = vec![];
+        let mut empty = !self.body || status == "204" || status == "304";
+        for line in lines {
+            let colon = match line.find(':') {
+                Some(i) => i,
+                None => continue
+            };
+            let name = line[..colon].trim().to_lowercase();
+            let value = line[colon + 1..].trim().to_string();
+            if name == "transfer-encoding" && value.contains("chunked") {
+                self.chunked = Some(Chunk::Size(vec![]));
+            }
+            if CONNECTION_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            if name == "content-length" && value == "0" {
+                empty = true;
+            }
+            headers.push((name, value));
+        }
+
+        // Split into CONTINUATION frames if the block is larger than a frame
+        let block = encode(&status, &headers);
+        let mut parts = block.chunks(self.conn.frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if empty { END_STREAM } else { 0 };
+        loop {
+            let part = parts.next().unwrap_or(&[]);
+            if parts.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            self.conn.write_frame(kind, flags, self.id, part)?;
+            if flags & END_HEADERS != 0 {
+                break;
+            }
+            kind = CONTINUATION;
+            flags = 0;
+        }
+
+        self.sent = true;
+        self.ended = empty;
+        Ok(())
+
+    }
+
+    // Body bytes as `output` writes them
+    fn send_body(&mut self, buf: &[u8]) {
+
+        if self.ended {
+            return;
+        }
+        let mut data = vec![];
+        match &mut self.chunked {
+            Some(chunk) => unchunk(chunk, buf, &mut data),
+            None => data.extend_from_slice(buf)
+        }
+        if !data.is_empty() {
+            self.queue.push(Part::Bytes(data));
+        }
+
+    }
+
+    fn finish(self) -> Result<(), Close> {
+
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        if !self.sent {
+            return self.conn.reset(self.id, INTERNAL_ERROR);
+        }
+        if self.ended {
+            return self.conn.end(self.id);
+        }
+        // Sent by `Connection::send`
+        if let Some(open) = self.conn.streams.get_mut(&self.id) {
+            open.response = Some(self.queue);
+        }
+        Ok(())
+
+    }
+
+}
+
+impl<'c, R: Arrived, W: Write> Write for Writer<'c, R, W> {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+
+        if self.error.is_some() {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"));
+        }
+
+        if self.sent {
+            self.send_body(buf);
+            return Ok(buf.len());
+        }
+
+        self.head.extend_from_slice(buf);
+        if let Some(i) = self.head.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = self.head.split_off(i + 4);
+            if let Err(err) = self.send_head() {
+                self.error = Some(err);
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"));
+            }
+            self.send_body(&rest);
+        }
+        Ok(buf.len())
+
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+}
+
+// Files wait on the stream like any other body
+impl<'c, R: Arrived, W: Write> Sink for Writer<'c, R, W> {
+
+    fn send_file(&mut self, file: &File, offset: u64, len: u64) -> io::Result<()> {
+
+        // The head has to go first
+        if !self.sent {
+            return copy_file(file, offset, len, self);
+        }
+        if !self.ended && len != 0 {
+            self.queue.push(Part::File(file.try_clone()?, offset, len));
+        }
+        Ok(())
+
+    }
+
+    fn send_compressed(&mut self, file: CompressedFile) -> io::Result<()> {
+
+        if !self.sent {
+            return write_compressed(file, self);
+        }
+        if !self.ended {
+            self.queue.push(Part::Compressed(Box::new(file)));
+        }
+        Ok(())
+
+    }
+
+}
+
+// Take the data out of a chunked body
+fn unchunk(chunk: &mut Chunk, mut buf: &[u8], out: &mut Vec<u8>) {
+
+    while !buf.is_empty() {
+        match chunk {
+            Chunk::Size(line) | Chunk::Trailer(line) => {
+                let (part, found) = match buf.iter().position(|b| *b == b'\n') {
+                    Some(i) => (&buf[..i + 1], true),
+                    None => (buf, false)
+                };
+                line.extend_from_slice(part);
+                buf = &buf[part.len()..];
+                if !found {
+                    continue;
+                }
+                let text = String::from_utf8_lossy(line).trim().to_string();
+                *chunk = match chunk {
+                    Chunk::Size(_) => {
+                        let size = text.split(';').next().unwrap_or("");
+                        match usize::from_str_radix(size.trim(), 16) {
+                            Ok(0) => Chunk::Trailer(vec![]),
+                            Ok(size) => Chunk::Data(size),
+                            Err(_) => Chunk::Done
+                        }
+                    },
+                    _ if text.is_empty() => Chunk::Done,
+                    _ => Chunk::Trailer(vec![])
+                };
+            },
+            Chunk::Data(size) => {
+                let n = (*size).min(buf.len());
+                out.extend_from_slice(&buf[..n]);
+                buf = &buf[n..];
+                *size -= n;
+                if *size == 0 {
+                    *chunk = Chunk::End(2);
+                }
+            },
+            Chunk::End(size) => {
+                let n = (*size).min(buf.len());
+                buf = &buf[n..];
+                *size -= n;
+                if *size == 0 {
+                    *chunk = Chunk::Size(vec![]);
+                }
+            },
+            Chunk::Done => return
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::http2::*;
+    use hpack::Encoder;
+    use std::fs;
+
+    fn frame(kind: u8, flags: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+        let len = (payload.len() as u32).to_be_bytes();
+        let mut frame = vec![len[1], len[2], len[3], kind, flags];
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn request(encoder: &mut Encoder, method: &str, path: &str) -> Vec<u8> {
+        let fields = vec![
+            (b":method".to_vec(), method.as_bytes().to_vec()),
+            (b":scheme".to_vec(), b"http".to_vec()),
+            (b":path".to_vec(), path.as_bytes().to_vec()),
+            (b":authority".to_vec(), b"localhost".to_vec())
+        ];
+        encoder.encode(&fields)
+    }
+
+    // What the client sends, a piece at a time
+    struct Pieces(VecDeque<Vec<u8>>);
+
+    impl Read for Pieces {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let piece = match self.0.front_mut() {
+                Some(piece) => piece,
+                None => return Ok(0)
+            };
+            let n = piece.len().min(buf.len());
+            buf[..n].copy_from_slice(&piece[..n]);
+            piece.drain(..n);
+            if piece.is_empty() {
+                self.0.pop_front();
+            }
+            Ok(n)
+        }
+    }
+
+    // Frames written by the server
+    fn serve(input: Vec<Vec<u8>>) -> Vec<(u8, u8, u32, Vec<u8>)> {
+        let dir = std::env::temp_dir().join("see_test_http2");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("index.txt"), "hello").unwrap();
+        let config = ServerConfig {
+            root: dir.to_str().unwrap().to_string(),
+            methods: vec!["GET".to_string(), "HEAD".to_string()],
+            max_body_size: 1024,
+            ..ServerConfig::default()
+        };
+
+        let mut output = vec![];
+        connection(BufReader::new(Pieces(input.into())), &mut output, &Arc::new(vec![config]), None);
+
+        let (mut frames, mut buf) = (vec![], &output[..]);
+        while !buf.is_empty() {
+            let len = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as usize;
+            let id = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]);
+            frames.push((buf[3], buf[4], id, buf[9..9 + len].to_vec()));
+            buf = &buf[9 + len..];
+        }
+        frames
+    }
+
+    // Each frame arrives on its own
+    fn input(frames: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let mut input = vec![PREFACE.to_vec(), frame(SETTINGS, 0, 0, &[])];
+        input.extend_from_slice(frames);
+        input
+    }
+
+    #[test]
+    fn test_unchunk() {
+        let body = b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nTrailer: x\r\n\r\n";
+        for size in 1..body.len() {
+            let (mut chunk, mut out) = (Chunk::Size(vec![]), vec![]);
+            for part in body.chunks(size) {
+                unchunk(&mut chunk, part, &mut out);
+            }
+            assert_eq!(out, b"hello world");
+            assert!(matches!(chunk, Chunk::Done));
+        }
+    }
+
+    #[test]
+    fn test_http2_get() {
+        let mut encoder = Encoder::new();
+        let get = request(&mut encoder, "GET", "/index.txt");
+        let head = request(&mut encoder, "HEAD", "/index.txt");
+        let missing = request(&mut encoder, "GET", "/missing");
+        let frames = serve(input(&[
+            frame(HEADERS, END_STREAM | END_HEADERS, 1, &get),
+            frame(HEADERS, END_STREAM | END_HEADERS, 3, &head),
+            frame(HEADERS, END_STREAM | END_HEADERS, 5, &missing),
+            frame(PING, 0, 0, b"12345678")
+        ]));
+
+        assert_eq!((frames[0].0, frames[0].1), (SETTINGS, 0));
+        assert_eq!((frames[1].0, frames[1].1), (SETTINGS, ACK));
+
+        let mut decoder = Decoder::new();
+        let (kind, flags, id, block) = &frames[2];
+        assert_eq!((*kind, *flags, *id), (HEADERS, END_HEADERS, 1));
+        let fields = decoder.decode(block).unwrap();
+        assert_eq!(fields[0], (b":status".to_vec(), b"200".to_vec()));
+        assert!(fields.contains(&(b"content-length".to_vec(), b"5".to_vec())));
+        assert!(!fields.iter().any(|(name, _)| name == b"connection"));
+        assert_eq!(frames[3], (DATA, END_STREAM, 1, b"hello".to_vec()));
+
+        // No body for HEAD
+        let (kind, flags, id, block) = &frames[4];
+        assert_eq!((*kind, *flags, *id), (HEADERS, END_STREAM | END_HEADERS, 3));
+        assert_eq!(decoder.decode(block).unwrap()[0].1, b"200");
+
+        let (kind, _, id, block) = &frames[5];
+        assert_eq!((*kind, *id), (HEADERS, 5));
+        assert_eq!(decoder.decode(block).unwrap()[0].1, b"404");
+        assert_eq!((frames[6].0, frames[6].1, frames[6].2), (DATA, END_STREAM, 5));
+
+        assert_eq!(frames[7], (PING, ACK, 0, b"12345678".to_vec()));
+        assert_eq!(frames.len(), 8);
+    }
+
+    #[test]
+    fn test_http2_flow_control() {
+        let mut encoder = Encoder::new();
+        let get = request(&mut encoder, "GET", "/index.txt");
+        // The client allows 2 bytes per stream, then 10 more
+        let frames = serve(input(&[
+            frame(SETTINGS, 0, 0, &[0, 4, 0, 0, 0, 2]),
+            frame(HEADERS, END_STREAM | END_HEADERS, 1, &get),
+            frame(WINDOW_UPDATE, 0, 1, &10_u32.to_be_bytes())
+        ]));
+
+        let data: Vec<_> = frames.iter().filter(|frame| frame.0 == DATA).collect();
+        assert_eq!(data[0], &(DATA, 0, 1, b"he".to_vec()));
+        assert_eq!(data[1], &(DATA, END_STREAM, 1, b"llo".to_vec()));
+    }
+
+    #[test]
+    fn test_http2_interleave() {
+        let dir = std::env::temp_dir().join("see_test_http2");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("big.txt"), vec![b'x'; FRAME_SIZE + 100]).unwrap();
+
+        let mut encoder = Encoder::new();
+        let first = request(&mut encoder, "GET", "/big.txt");
+        let second = request(&mut encoder, "GET", "/big.txt");
+        // Both requests arrive together
+        let frames = serve(input(&[[
+            frame(HEADERS, END_STREAM | END_HEADERS, 1, &first),
+            frame(HEADERS, END_STREAM | END_HEADERS, 3, &second)
+        ].concat()]));
+
+        // A frame of each stream in turn
+        let data: Vec<_> = frames.iter().filter(|frame| frame.0 == DATA).map(|frame| (frame.1, frame.2, frame.3.len())).collect();
+        assert_eq!(data, [(0, 1, FRAME_SIZE), (0, 3, FRAME_SIZE), (END_STREAM, 1, 100), (END_STREAM, 3, 100)]);
+
+        // And when the window of one is full
+        let frames = serve(input(&[
+            frame(SETTINGS, 0, 0, &[0, 4, 0, 0, 0, 10]),
+            frame(HEADERS, END_STREAM | END_HEADERS, 1, &first),
+            frame(HEADERS, END_STREAM | END_HEADERS, 3, &request(&mut encoder, "GET", "/index.txt")),
+            frame(WINDOW_UPDATE, 0, 1, &(FRAME_SIZE as u32 + 90).to_be_bytes())
+        ]));
+        let data: Vec<_> = frames.iter().filter(|frame| frame.0 == DATA).map(|frame| (frame.1, frame.2, frame.3.len())).collect();
+        assert_eq!(data, [(0, 1, 10), (END_STREAM, 3, 5), (0, 1, FRAME_SIZE), (END_STREAM, 1, 90)]);
+    }
+
+    #[test]
+    fn test_http2_body() {
+        let mut encoder = Encoder::new();
+        let mut block = request(&mut encoder, "GET", "/index.txt");
+        block.extend(encoder.encode(&vec![(b"content-length".to_vec(), b"4096".to_vec())]));
+        let frames = serve(input(&[
+            frame(HEADERS, END_HEADERS, 1, &block),
+            frame(DATA, 0, 1, b"data")
+        ]));
+
+        let mut decoder = Decoder::new();
+        let headers = frames.iter().find(|frame| frame.0 == HEADERS).unwrap();
+        assert_eq!(decoder.decode(&headers.3).unwrap()[0].1, b"413");
+        // The rest of the body is refused once the response is sent
+        assert!(frames.contains(&(RST_STREAM, 0, 1, NO_ERROR.to_be_bytes().to_vec())));
+
+        // Answered once the body is complete, in one or more frames
+        let mut encoder = Encoder::new();
+        let block = request(&mut encoder, "GET", "/index.txt");
+        let frames = serve(input(&[
+            frame(HEADERS, END_HEADERS, 1, &block),
+            frame(DATA, 0, 1, b"da"),
+            frame(DATA, END_STREAM, 1, b"ta")
+        ]));
+        let mut decoder = Decoder::new();
+        let headers = frames.iter().position(|frame| frame.0 == HEADERS).unwrap();
+        assert_eq!(decoder.decode(&frames[headers].3).unwrap()[0].1, b"200");
+        // Both frames are given back before that
+        assert_eq!(frames[..headers].iter().filter(|frame| frame.0 == WINDOW_UPDATE).count(), 3);
+        assert!(!frames.iter().any(|frame| frame.0 == RST_STREAM));
+
+        // Or once it is too large, without a content-length
+        let mut encoder = Encoder::new();
+        let block = request(&mut encoder, "GET", "/index.txt");
+        let frames = serve(input(&[
+            frame(HEADERS, END_HEADERS, 1, &block),
+            frame(DATA, 0, 1, &[b'x'; 1000]),
+            frame(DATA, 0, 1, &[b'x'; 1000])
+        ]));
+        let mut decoder = Decoder::new();
+        let headers = frames.iter().find(|frame| frame.0 == HEADERS).unwrap();
+        assert_eq!(decoder.decode(&headers.3).unwrap()[0].1, b"413");
+    }
+
+    #[test]
+    fn test_valid_block() {
+        let mut encoder = Encoder::new();
+        assert!(valid_block(&request(&mut encoder, "GET", "/index.txt")));
+        assert!(valid_block(&request(&mut encoder, "GET", "/index.txt")));
+        assert!(valid_block(&[0x20, 0x3f, 0xe1, 0x1f, 0x82]));
+        assert!(!valid_block(&[0x3f, 0xe2, 0x1f]));
+        assert!(!valid_block(&[0x82, 0x20]));
+        assert_eq!(decode_integer(&[0x1f, 0x9a, 0x0a], 5), Some((1337, 3)));
+        assert_eq!(decode_integer(&[0x1f, 0x9a], 5), None);
+    }
+
+    #[test]
+    fn test_http2_errors() {
+        let goaway = |frames: Vec<(u8, u8, u32, Vec<u8>)>| {
+            let last = frames.last().unwrap();
+            assert_eq!(last.0, GOAWAY);
+            u32::from_be_bytes([last.3[4], last.3[5], last.3[6], last.3[7]])
+        };
+        assert_eq!(goaway(serve(input(&[frame(DATA, 0, 0, b"x")]))), PROTOCOL_ERROR);
+        assert_eq!(goaway(serve(input(&[frame(PING, 0, 0, b"1234")]))), FRAME_SIZE_ERROR);
+        assert_eq!(goaway(serve(input(&[frame(HEADERS, END_HEADERS, 2, &[])]))), PROTOCOL_ERROR);
+        assert_eq!(goaway(serve(input(&[
+            frame(HEADERS, 0, 1, &[]),
+            frame(PING, 0, 0, b"12345678")
+        ]))), PROTOCOL_ERROR);
+        assert_eq!(goaway(serve(input(&[frame(WINDOW_UPDATE, 0, 0, &0x7fff_ffff_u32.to_be_bytes())]))), FLOW_CONTROL_ERROR);
+
+        // Truncated or garbage header blocks
+        for block in [
+            &[0x3f][..],
+            &[0x3f, 0xff, 0xff],
+            &[0x3f, 0xff, 0xff, 0xff, 0xff, 0x7f],
+            &[0x3f, 0xe2, 0x1f],
+            &[0x82, 0x20],
+            &[0xff],
+            &[0x40, 0x05, b'a'],
+            &[0x41, 0x85, b'a'],
+            &[0x00, 0x7f],
+            &[0x82, 0xc5]
+        ] {
+            assert_eq!(goaway(serve(input(&[frame(HEADERS, END_STREAM | END_HEADERS, 1, block)]))), COMPRESSION_ERROR);
+        }
+
+        // A bad request only ends its stream
+        let mut encoder = Encoder::new();
+        let block = encoder.encode(&vec![(b":method".to_vec(), b"GET".to_vec())]);
+        let frames = serve(input(&[frame(HEADERS, END_STREAM | END_HEADERS, 1, &block)]));
+        let headers = frames.iter().find(|frame| frame.0 == HEADERS).unwrap();
+        assert_eq!(Decoder::new().decode(&headers.3).unwrap()[0].1, b"400");
+    }
+
+}
@@ -5,41 +5,100 @@ use std::io::prelude::*;
 use std::fs::File;
 use std::path::Path;
 use std::fs;
+use std::sync::{Arc, OnceLock};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::collections::HashMap;
 use chrono::{DateTime, prelude, Local};
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Log {
-    file: Option<File>
+    // None if the file couldn't be created.
+    // It's opened right away, the path may be gone after chroot
+    file: Option<(Arc<str>, Arc<File>)>
+}
+
+// Lines waiting to be written before logging starts to block the connection
+const QUEUE_SIZE: usize = 4096;
+
+enum Message {
+    Line(Arc<str>, Arc<File>, String),
+    // Open the files again, they have been moved away by logrotate.
+    // The old one is kept if it can't be opened
+    Reopen,
+    // Everything sent before has been written
+    Flush(SyncSender<()>)
+}
+
+static WRITER: OnceLock<SyncSender<Message>> = OnceLock::new();
+
+// A single background thread writes the lines of every log file in order
+fn writer() -> &'static SyncSender<Message> {
+    WRITER.get_or_init(|| {
+        let (sender, receiver) = sync_channel::<Message>(QUEUE_SIZE);
+        std::thread::spawn(move || {
+            let mut files: HashMap<Arc<str>, Arc<File>> = HashMap::new();
+            for message in receiver {
+                match message {
+                    Message::Line(path, file, line) => {
+                        let file = files.entry(path).or_insert(file);
+                        if let Err(e) = (&**file).write_all(line.as_bytes()) {
+                            eprintln!("Couldn't write to file: {}", e);
+                        }
+                    },
+                    Message::Reopen => {
+                        for (path, file) in files.iter_mut() {
+                            if let Some(new) = create_log_file(path.to_string()) {
+                                *file = Arc::new(new);
+                            }
+                        }
+                    },
+                    Message::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        sender
+    })
+}
+
+pub fn reopen() {
+    let _ = writer().send(Message::Reopen);
+}
+
+// Wait for the lines that are still queued
+pub fn flush() {
+    let (done, wait) = sync_channel(1);
+    if writer().send(Message::Flush(done)).is_ok() {
+        let _ = wait.recv();
+    }
 }
 
 
 fn create_log_file(path: String) -> Option<File> {
 
-    if let Err(_) = File::open(&path) {
+    if File::open(&path).is_err() {
         let parent = match Path::new(&path).parent() {
             Some(p) => p,
             None => {
                 return None;
             }
         };
-        if let Err(_) = fs::create_dir_all(parent) {
+        if fs::create_dir_all(parent).is_err() {
             return None;
         }
-        if let Err(_) = File::create(&path) {
+        if File::create(&path).is_err() {
             return None;
         }
     }
 
     let file = OpenOptions::new()
-        .write(true)
+        
         .append(true)
         .open(&path);
 
-    match file {
-        Ok(file) => Some(file),
-        Err(_) => None
-    }
+    file.ok()
 
 }
 
@@ -48,26 +107,34 @@ impl Log {
 
     pub fn new(path: String) -> Log {
         Log {
-            file: create_log_file(path)
+            file: create_log_file(path.clone()).map(|file| (Arc::from(path), Arc::new(file)))
         }
     }
 
+    pub fn path(&self) -> Option<&str> {
+        self.file.as_ref().map(|(path, _)| &**path)
+    }
+
     pub fn write(&self, method: &str, status: i32, path: &str)  {
+        self.write_reason(method, status, path, "");
+    }
+
+    // Why the request was refused after the path
+    pub fn write_reason(&self, method: &str, status: i32, path: &str, reason: &str)  {
 
-        let mut file = if let Some(file) = &self.file {
-            file.try_clone().unwrap()
+        let (name, file) = if let Some((name, file)) = &self.file {
+            (name.clone(), file.clone())
         }else {
             return;
         };
-        let method = String::from(method);
-        let path = String::from(path);
 
-        std::thread::spawn(move || {
-            let time: DateTime<Local> = prelude::Local::now();
-            if let Err(e) = writeln!(file, "{0}  {1: <6}  {2}  {3}", time, method, status, path) {
-                eprintln!("Couldn't write to file: {}", e);
-            }
-        });
+        let time: DateTime<Local> = prelude::Local::now();
+        let line = if reason.is_empty() {
+            format!("{0}  {1: <6}  {2}  {3}\n", time, method, status, path)
+        }else {
+            format!("{0}  {1: <6}  {2}  {3}  {4}\n", time, method, status, path, reason)
+        };
+        let _ = writer().send(Message::Line(name, file, line));
 
     }
 
@@ -76,10 +143,48 @@ impl Log {
 
 #[test]
 fn test_log() {
-    let log = Log::new(String::from("./logs/test.log"));
-    log.write("GET", 200, "/api");
+    let path = "./logs/test.log";
+    let _ = fs::remove_file(path);
+    let log = Log::new(String::from(path));
+    log.clone().write("GET", 200, "/api");
     log.write("HEAD", 404, "/img");
     log.write("DELETE", 500, "/img");
-}
 
+    let mut lines = vec![];
+    for _ in 0..100 {
+        lines = fs::read_to_string(path).unwrap()
+            .lines()
+            .map(|line| line.split_whitespace().skip(3).collect::<Vec<&str>>().join(" "))
+            .collect();
+        if lines.len() == 3 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(lines, vec!["GET 200 /api", "HEAD 404 /img", "DELETE 500 /img"]);
+}
 
+#[test]
+fn test_log_reopen() {
+    let path = "./logs/test_reopen.log";
+    let moved = "./logs/test_reopen.log.1";
+    let _ = fs::remove_file(path);
+    let log = Log::new(String::from(path));
+    log.write("GET", 200, "/old");
+    flush();
+
+    // Like logrotate, then SIGUSR1
+    fs::rename(path, moved).unwrap();
+    log.write("GET", 200, "/moved");
+    reopen();
+    log.write("GET", 200, "/new");
+    flush();
+
+    let lines = |path| fs::read_to_string(path).unwrap()
+        .lines()
+        .map(|line: &str| line.split_whitespace().last().unwrap().to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(lines(moved), vec!["/old", "/moved"]);
+    assert_eq!(lines(path), vec!["/new"]);
+    let _ = fs::remove_file(moved);
+}
@@ -2,19 +2,25 @@
 
 extern crate chrono;
 use std::sync::Arc;
-use std::{fs, fs::File};
+use std::collections::HashMap;
+use std::{fs, fs::File, fs::Metadata};
 use std::env;
-use std::{process, process::Command};
-use std::path::Path;
+use std::process;
+use std::path::{Path, Component};
 use std::io::prelude::*;
 use std::fmt::Write as FmtWrite;
-use std::net::{TcpStream, TcpListener};
-use std::thread::JoinHandle;
+use std::net::{TcpListener, TcpStream, SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use socket2::{Socket, Domain, Type};
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+use std::io;
+use std::io::BufReader;
 use chrono::{DateTime, Local};
+use percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET, QUERY_ENCODE_SET};
 mod response;
 use response::{StatusCode, Response};
 mod request;
-use request::Request;
+use request::{Request, body_status};
 mod html;
 use html::TEMPLATE;
 mod config;
@@ -23,21 +29,45 @@ use config::{
     DirectoryOption,
     RewriteType,
     CompressType,
-    DEFAULT_METHODS
+    Cache,
+    Deny,
+    DenyType,
+    Header,
+    KeepAlive,
+    Engine,
+    Daemon,
+    Privileges,
+    DEFAULT_METHODS,
+    DEFAULT_DENY,
+    DEFAULT_PORT
 };
 mod log;
 mod app;
 use app::App;
-
-#[cfg(target_os = "macos")]
-static PID_PATH: &str = "/usr/local/var/run/see.pid";
-#[cfg(target_os = "linux")]
-static PID_PATH: &str = "/var/run/see.pid";
-#[cfg(target_os = "windows")]
-static PID_PATH: &str = "./see.pid";
+mod connection;
+use connection::{ResponseWriter, Deadline, Stream, Sink};
+mod range;
+use range::Range;
+mod validator;
+use validator::Validator;
+mod tls;
+mod http2;
+mod pool;
+use pool::Pool;
+mod event;
+mod file_cache;
+mod site;
+mod systemd;
+mod resolve;
+mod auth;
+mod access;
+use site::{Site, Address};
+mod signal;
+use signal::Signal;
+mod daemon;
+mod privilege;
 
 const DEFAULT_CONFIG_PATH: &str = "config.yml";
-const DEFAULT_PORT: i64 = 80;
 
 fn main() {
 
@@ -51,20 +81,40 @@ fn main() {
         return app.print_version();
     }
 
-    if app.stop() {
-        return stop_daemon();
-    }
-
     let mut configs: Vec<Arc<Vec<ServerConfig>>>;
+    // Read again on SIGHUP
+    let mut config_path = None;
     let current_buff = env::current_dir()
         .unwrap();
     let current_dir = current_buff.to_str()
         .unwrap();
 
+    if app.stop() {
+        let daemon = daemon_config(&app, current_dir);
+        if let Err(msg) = daemon::stop(&daemon.pid_file.unwrap()) {
+            eprintln!("{}", msg);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if app.status() {
+        let daemon = daemon_config(&app, current_dir);
+        let path = daemon.pid_file.unwrap();
+        match daemon::status(&path) {
+            daemon::Status::Running(pid) => return println!("{} is running, pid {}", env!("CARGO_PKG_NAME"), pid),
+            daemon::Status::Stopped => println!("{} is not running", env!("CARGO_PKG_NAME")),
+            daemon::Status::Stale => println!("{} is not running, {} is stale", env!("CARGO_PKG_NAME"), path)
+        }
+        process::exit(3);
+    }
+
     if app.start() {
 
-        let mut config = ServerConfig::default();
-        config.root = String::from(current_dir);
+        let mut config = ServerConfig {
+            root: String::from(current_dir),
+            ..ServerConfig::default()
+        };
         config.directory = Some(DirectoryOption {
             time: true,
             size: true
@@ -73,11 +123,16 @@ fn main() {
             .iter()
             .map(|m| String::from(*m))
             .collect();
+        config.keep_alive = Some(KeepAlive::default());
+        config.deny = DEFAULT_DENY
+            .iter()
+            .filter_map(|pattern| Deny::new(pattern).ok())
+            .collect();
         config.listen = match app.port() {
             Ok(result) => {
                 match result {
-                    Some(port) => port,
-                    None => DEFAULT_PORT
+                    Some(port) => SocketAddr::from(([0, 0, 0, 0], port)),
+                    None => SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT))
                 }
             },
             Err(arg) => {
@@ -89,13 +144,13 @@ fn main() {
 
     }else {
 
-        let mut config_path = match app.config() {
+        let path = match app.config() {
             Some(path) => path,
             None => String::from(DEFAULT_CONFIG_PATH)
         };
 
-        config_path = fill_path(current_dir, &config_path);
-        configs = match ServerConfig::new(&config_path) {
+        let path = fill_path(current_dir, &path);
+        configs = match ServerConfig::new(&path) {
             Ok(config) => config,
             Err(msg) => {
                 eprintln!("{}", msg);
@@ -105,142 +160,522 @@ fn main() {
 
         // Check configuration file
         if app.test() {
-            return println!("the configuration file {} syntax is ok", config_path);
+            return println!("the configuration file {} syntax is ok", path);
         }
+        config_path = Some(path);
 
     }
 
-    if app.detach() {
-        return start_daemon(&app.args, app.detach_args());
+    // The engine on the command line is used by every server
+    let engine = match app.engine() {
+        Some(name) => match Engine::from_name(&name) {
+            Some(engine) => Some(engine),
+            None => {
+                eprintln!("unknown engine \"{}\", optional value: \"thread\" \"event\"", name);
+                process::exit(1);
+            }
+        },
+        None => None
+    };
+    set_engine(&mut configs, engine);
+
+    let privileges = match &config_path {
+        Some(path) => Privileges::new(path).unwrap_or_else(|msg| {
+            eprintln!("{}", msg);
+            process::exit(1);
+        }),
+        None => Privileges::default()
+    };
+    let servers = configs.iter().flat_map(|group| group.iter()).collect::<Vec<&ServerConfig>>();
+    let chroot = privilege::chroot_dir(&privileges, &servers).unwrap_or_else(|msg| {
+        eprintln!("{}", msg);
+        process::exit(1);
+    });
+    // Paths as they are seen from inside
+    if let Some(dir) = &chroot {
+        for group in configs.iter_mut() {
+            for config in Arc::make_mut(group).iter_mut() {
+                if let Err(msg) = privilege::relocate(config, dir) {
+                    eprintln!("{}", msg);
+                    process::exit(1);
+                }
+            }
+        }
     }
+    let switch = || {
+        if privileges.user.is_none() && privileges.group.is_none() && chroot.is_none() {
+            return Ok(());
+        }
+        privilege::switch(&privileges, chroot.as_deref())
+    };
+
+    let daemon = if app.detach() {
+        let daemon = daemon_config(&app, current_dir);
+        if let daemon::Status::Running(pid) = daemon::status(daemon.pid_file.as_ref().unwrap()) {
+            eprintln!("{} is already running, pid {}", env!("CARGO_PKG_NAME"), pid);
+            process::exit(1);
+        }
+        Some(daemon)
+    }else {
+        None
+    };
 
-    let mut tasks: Vec<JoinHandle<()>> = vec![];
-    let start = app.start();
+    // Bound before detaching, so the errors are seen.
+    // Sockets of a systemd socket unit are used instead where they match
+    let mut listeners = vec![];
+    let mut inherited = systemd::inherited();
 
     for config in configs {
 
-        let task = std::thread::spawn(move || {
+        let listen = config[0].listen;
+        // A port whose TLS can't be set up is not served as plain HTTP
+        let site = Site::new(config.clone()).unwrap_or_else(|msg| {
+            eprintln!("{}", msg);
+            process::exit(1);
+        });
+        let bound = match systemd::take(&mut inherited, listen) {
+            Some(listener) => listener,
+            None => bind(listen)
+        };
+
+        match bound {
+            Ok(listener) => {
+                if app.start() {
+                    println!("Serving path   : \x1b[92m{}\x1b[0m",  &config[0].root);
+                    println!("Serving address: \x1b[93m{}\x1b[0m",  serving_address(listen));
+                }
+                listeners.push((listener, site));
+            },
+            Err(err) => {
+                eprintln!("Binding {} failed", listen);
+                eprintln!("{:?}", err);
+                process::exit(1);
+            }
+        };
+
+    }
 
-            let listen = config[0].listen;
-            let address = format!("0.0.0.0:{}", listen);
+    for socket in inherited {
+        if let Ok(address) = socket.listener.local_addr() {
+            eprintln!("Socket {} from systemd matches no \"listen\", it's closed", address);
+        }
+    }
 
-            match TcpListener::bind(&address) {
-                Ok(listener) => {
-                    if start {
-                        println!("Serving path   : \x1b[92m{}\x1b[0m",  &config[0].root);
-                        if listen != 80 {
-                            println!("Serving address: \x1b[93mhttp://127.0.0.1:{}\x1b[0m",  listen);
-                        }else {
-                            println!("Serving address: \x1b[93mhttp://127.0.0.1\x1b[0m");
-                        }
-                    }
-                    incoming(listener, config);
-                },
-                Err(err) => {
-                    eprintln!("Binding {} failed", address);
-                    eprintln!("{:?}", err);
+    // Only root can bind the ports below 1024, it's given up before serving
+    let started = match daemon {
+        Some(daemon) => daemon::start(&daemon, switch),
+        None => switch()
+    };
+    if let Err(msg) = started {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
+
+    let mut addresses = HashMap::new();
+
+    for (listener, site) in listeners {
+        let listen = site.configs[0].listen;
+        match run(listener, site) {
+            Ok(address) => {
+                addresses.insert(listen, address);
+            },
+            Err(err) => {
+                eprintln!("{:?}", err);
+                process::exit(1);
+            }
+        }
+    }
+
+    systemd::ready();
+    systemd::watchdog();
+
+    let signals = match signal::signals() {
+        Ok(signals) => signals,
+        // Serve until the process is killed
+        Err(_) => loop {
+            std::thread::park();
+        }
+    };
+
+    for signal in signals {
+        match signal {
+            Signal::Stop => {
+                // A second one doesn't wait
+                if signal::stopping() {
+                    daemon::remove_pid_file();
                     process::exit(1);
                 }
-            };
+                signal::stop();
+                systemd::stopping();
+                for address in addresses.values() {
+                    address.close();
+                }
+                std::thread::spawn(|| {
+                    if !signal::drain(signal::DRAIN_TIMEOUT) {
+                        eprintln!("Stopped with requests still in progress");
+                    }
+                    log::flush();
+                    daemon::remove_pid_file();
+                    process::exit(0);
+                });
+            },
+            Signal::Reload => {
+                if signal::stopping() {
+                    continue;
+                }
+                // The configuration and the roots are out of reach
+                if chroot.is_some() {
+                    eprintln!("Reload is not available with \"chroot\", restart instead");
+                    continue;
+                }
+                systemd::reloading();
+                match &config_path {
+                    Some(path) => reload(path, engine, &mut addresses),
+                    None => eprintln!("No configuration file to reload")
+                }
+                systemd::ready();
+            },
+            Signal::Reopen => log::reopen()
+        }
+    }
 
-        });
+}
 
-        tasks.push(task);
 
+fn set_engine(configs: &mut [Arc<Vec<ServerConfig>>], engine: Option<Engine>) {
+    if let Some(engine) = engine {
+        for group in configs.iter_mut() {
+            for config in Arc::make_mut(group).iter_mut() {
+                config.engine = engine;
+            }
+        }
     }
+}
+
+
+// Options of the daemon, the PID file of "-p" comes first
+fn daemon_config(app: &App, current_dir: &str) -> Daemon {
+
+    let mut daemon = match app.config() {
+        Some(path) => Daemon::new(&fill_path(current_dir, &path)),
+        None => {
+            let path = fill_path(current_dir, DEFAULT_CONFIG_PATH);
+            if Path::new(&path).exists() {
+                Daemon::new(&path)
+            }else {
+                Ok(Daemon::default())
+            }
+        }
+    }.unwrap_or_else(|msg| {
+        eprintln!("{}", msg);
+        process::exit(1);
+    });
 
-    for task in tasks {
-        task.join().unwrap();
+    if let Some(path) = app.pid_file() {
+        daemon.pid_file = Some(path);
     }
+    daemon.pid_file = Some(match &daemon.pid_file {
+        Some(path) => fill_path(current_dir, path),
+        None => daemon::PID_PATH.to_string()
+    });
+    daemon.stdout = daemon.stdout.map(|path| fill_path(current_dir, &path));
+    daemon.stderr = daemon.stderr.map(|path| fill_path(current_dir, &path));
+    daemon
 
 }
 
 
-fn incoming(listener: TcpListener, configs: Arc<Vec<ServerConfig>>) {
-    for stream in listener.incoming() {
-        if let Ok(stream) = stream {
-            let configs = configs.clone();
-            std::thread::spawn(|| {
-                handle_connection(stream, configs);
-            });
+// Listen on the address of the servers
+fn open(site: Arc<Site>) -> io::Result<Arc<Address>> {
+    let listener = bind(site.configs[0].listen)?;
+    run(listener, site)
+}
+
+
+// Serve in a thread of its own
+fn run(listener: TcpListener, site: Arc<Site>) -> io::Result<Arc<Address>> {
+
+    let address = Address::new(listener.local_addr()?, site);
+    let serving = address.clone();
+    std::thread::spawn(move || serve(listener, serving));
+    Ok(address)
+
+}
+
+
+// New connections are served with the configuration file as it is now,
+// listeners are opened and closed for the addresses that changed.
+// The engine, workers and connection limit of a listener stay the same
+fn reload(path: &str, engine: Option<Engine>, addresses: &mut HashMap<SocketAddr, Arc<Address>>) {
+
+    let mut configs = match ServerConfig::new(path) {
+        Ok(configs) => configs,
+        Err(msg) => return eprintln!("Reload failed, {}", msg)
+    };
+    set_engine(&mut configs, engine);
+
+    // Nothing changes unless every port can be served
+    let mut sites = vec![];
+    for config in configs {
+        match Site::new(config) {
+            Ok(site) => sites.push(site),
+            Err(msg) => return eprintln!("Reload failed, {}", msg)
+        }
+    }
+
+    let listen = sites.iter()
+        .map(|site| site.configs[0].listen)
+        .collect::<Vec<SocketAddr>>();
+    addresses.retain(|address, listener| {
+        if listen.contains(address) {
+            return true;
+        }
+        listener.close();
+        false
+    });
+
+    for site in sites {
+        let listen = site.configs[0].listen;
+        match addresses.get(&listen) {
+            Some(address) => address.replace(site),
+            None => match open(site) {
+                Ok(address) => {
+                    addresses.insert(listen, address);
+                },
+                Err(err) => eprintln!("Binding {} failed, {:?}", listen, err)
+            }
         }
     }
+
+    log::reopen();
+
 }
 
 
-fn start_daemon(args: &Vec<String>, detach: [&str; 2]) {
+fn bind(address: SocketAddr) -> io::Result<TcpListener> {
+
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    // [::] only takes IPv6, so 0.0.0.0 can have the same port
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
 
-    let args = args
-        .iter()
-        .filter(|item| {
-            return *item != detach[0] && *item != detach[1]
-        })
-        .cloned()
-        .collect::<Vec<String>>();
+}
 
-    let child = Command::new(&args[0])
-        .args(&args[1..])
-        .spawn();
 
-    match child {
-        Ok(child) => {
-            let mut pid = File::create(PID_PATH)
-                .unwrap();
-            write!(pid, "{}", child.id()).unwrap();
-        },
-        Err(e) => {
-            eprintln!("{}", e);
-        }
+// The URL of a listen address, loopback for a wildcard one
+fn serving_address(listen: SocketAddr) -> String {
+
+    let host = match listen.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.to_string(),
+        IpAddr::V6(ip) if ip.is_unspecified() => format!("[{}]", Ipv6Addr::LOCALHOST),
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip)
+    };
+    if listen.port() == 80 {
+        format!("http://{}", host)
+    }else {
+        format!("http://{}:{}", host, listen.port())
     }
 
 }
 
 
-fn stop_daemon() {
-    match fs::read_to_string(PID_PATH) {
-        Ok(pid) => {
-            let kill = Command::new("kill")
-                .arg(pid)
-                .status();
-            match kill {
-                Ok(status) => {
-                    if status.success() {
-                        if let Err(e) = fs::remove_file(PID_PATH) {
-                            eprintln!("{}", e);
-                        }
+fn serve(listener: TcpListener, address: Arc<Address>) {
+    match address.site().configs[0].engine {
+        Engine::Thread => incoming(listener, address),
+        Engine::Event => event::serve(listener, address)
+    }
+}
+
+
+fn incoming(listener: TcpListener, address: Arc<Address>) {
+
+    let configs = address.site().configs.clone();
+    let pool = Pool::new(configs[0].workers, configs[0].max_connections);
+
+    for stream in listener.incoming() {
+        // Woken up by close()
+        if address.closed() {
+            return;
+        }
+        if let Ok(mut stream) = stream {
+            let site = address.site();
+            if pool.full() {
+                output_unavailable(stream, &site.configs[0], site.tls.is_some());
+                continue;
+            }
+            pool.execute(move || {
+                let mut peer = stream.peer_addr().ok().map(|addr| addr.ip());
+                // The proxy says who the client is before anything else
+                let config = &site.configs[0];
+                if config.proxy_protocol && peer.is_some_and(|ip| config.trusted_proxies.iter().any(|proxy| proxy.contains(ip))) {
+                    let _ = stream.set_read_timeout(Some(Duration::from_secs(config.limit.timeout)));
+                    match access::read(&mut stream) {
+                        Ok(Some(ip)) => peer = Some(ip),
+                        Ok(None) => {},
+                        Err(_) => return
                     }
-                },
-                Err(e) => {
-                    eprintln!("{}", e);
                 }
+                // The handshake happens on the first read
+                let stream = match &site.tls {
+                    Some(tls) => match rustls::ServerConnection::new(tls.clone()) {
+                        Ok(conn) => Stream::Tls(Box::new(RefCell::new(rustls::StreamOwned::new(conn, stream)))),
+                        Err(_) => return
+                    },
+                    None => Stream::Tcp(stream)
+                };
+                handle_connection(stream, site.configs.clone(), peer);
+            });
+        }
+    }
+
+}
+
+
+// Every worker is busy and the queue is full
+fn output_unavailable(mut stream: TcpStream, config: &ServerConfig, tls: bool) {
+
+    if let Some(log) = &config.log.error {
+        log.write("-", StatusCode::_503.code(), &stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default());
+    }
+    // Without a handshake a TLS client can't read the response, just close it
+    if tls {
+        return;
+    }
+    let res = Response::new(StatusCode::_503, &config.headers)
+        .header("Connection", "close")
+        .header("Retry-After", "1")
+        .text("503");
+    let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+    let _ = stream.write_all(&res);
+
+}
+
+
+fn handle_connection(stream: Stream, configs: Arc<Vec<ServerConfig>>, peer: Option<IpAddr>) {
+
+    let limit = &configs[0].limit;
+    let timeout = Duration::from_secs(limit.timeout);
+
+    // Buffered bytes that have not been parsed yet, pipelined requests wait here
+    let mut reader = BufReader::new(Deadline::new(&stream, timeout));
+    let mut count = 0;
+
+    loop {
+
+        // Wait for the next request, the idle time is limited by the read timeout
+        reader.get_mut().set_deadline(None);
+        let preface = match reader.fill_buf() {
+            Ok(buff) => {
+                if buff.is_empty() {
+                    return;
+                }
+                buff.starts_with(&http2::PREFACE[..4])
+            },
+            Err(_) => return
+        };
+
+        // Negotiated with ALPN over TLS, or h2c with prior knowledge
+        if count == 0 && (preface || stream.alpn() == Some(b"h2".to_vec())) {
+            return http2::serve(&mut reader, &stream, &configs, peer);
+        }
+
+        // Once it starts, the whole request head has to arrive in time
+        reader.get_mut().set_deadline(Some(Instant::now() + timeout));
+        let mut req = match Request::read(&mut reader, limit) {
+            Ok(req) => req,
+            Err(status) => return output_close(&stream, &[], status)
+        };
+        req.peer = peer;
+        count += 1;
+        let _busy = signal::busy();
+
+        // No more requests once the server stops
+        let keep = match &configs[0].keep_alive {
+            Some(keep_alive) => req.keep_alive() && count < keep_alive.requests && !signal::stopping(),
+            None => false
+        };
+
+        let config = match find_config(&req, &configs) {
+            Ok(config) => config,
+            Err(status) => return output_close(&stream, &[], status)
+        };
+
+        let mut body = match req.body(&mut reader, config.max_body_size, &stream) {
+            Ok(body) => body,
+            Err(status) => {
+                if let Some(log) = &config.log.error {
+                    log.write(&req.method, status.code(), &req.path);
+                }
+                return output_close(&stream, &config.headers, status);
             }
-        },
-        Err(e) => {
-            eprintln!("open \"{}\" failed, {:?}", PID_PATH, e.to_string());
+        };
+
+        let mut writer = response_writer(&stream, &req, &configs[0].keep_alive, keep);
+
+        let res = output(req, &mut body, config, &mut writer);
+
+        // What is left of the body goes before the next request. A client still waiting
+        // for "100 Continue" sends it after the response, so the connection ends there
+        let rest = !body.waiting() && io::copy(&mut body, &mut io::sink()).is_ok();
+        if !rest {
+            writer.close();
+        }
+        if !res.is_empty() {
+            let _ = writer.write_all(&res);
+        }
+        let _ = writer.flush();
+
+        if !keep || !rest || !writer.complete() {
+            return;
         }
+
+        if let Some(keep_alive) = &configs[0].keep_alive {
+            reader.get_mut().set_idle(Duration::from_secs(keep_alive.timeout));
+        }
+
     }
+
 }
 
 
-fn handle_connection(mut stream: TcpStream, configs: Arc<Vec<ServerConfig>>) {
+// Adds the connection headers of the response to `req`
+fn response_writer<W: Write>(stream: W, req: &Request, keep_alive: &Option<KeepAlive>, keep: bool) -> ResponseWriter<W> {
 
-    let mut buffer = [0; 512];
-    stream.read(&mut buffer).unwrap();
+    let writer = ResponseWriter::new(stream)
+        .body(req.method != "HEAD");
+    match (keep_alive, keep) {
+        (Some(keep_alive), true) => writer
+            .header("Connection", "keep-alive")
+            .header("Keep-Alive", &format!("timeout={}", keep_alive.timeout)),
+        _ => writer.header("Connection", "close")
+    }
 
-    let mut res: Vec<u8>;
-    let req = if let Ok(req) = Request::new(&buffer) {
-        req
-    }else {
-        res = Response::new(StatusCode::_400, &vec![])
-            .text("400");
-        stream.write(&res).unwrap();
-        stream.flush().unwrap();
-        return;
-    };
+}
+
+
+// Respond with an error and close the connection
+fn output_close<W: Write>(mut stream: W, headers: &[Header], status: StatusCode) {
+
+    let res = Response::new(status, headers)
+        .header("Connection", "close")
+        .text(&status.code().to_string());
+    let _ = stream.write_all(&res).and_then(|_| stream.flush());
+
+}
+
+
+// Select the server by the Host header
+fn find_config<'a>(req: &Request, configs: &'a Arc<Vec<ServerConfig>>) -> Result<&'a ServerConfig, StatusCode> {
 
     if let Some(host) = req.headers.get("host") {
         let mut index = None;
-        let host = &host.replace(&format!(":{}", configs[0].listen), "");
+        let host = &host.replace(&format!(":{}", configs[0].listen.port()), "");
         'configs: for (i, config) in configs.iter().enumerate() {
             if let Some(hosts) = &config.hosts {
                 for val in hosts {
@@ -251,73 +686,73 @@ fn handle_connection(mut stream: TcpStream, configs: Arc<Vec<ServerConfig>>) {
                 }
             }
         }
-        if let None = index {
+        if index.is_none() {
             for (i, conf) in configs.iter().enumerate() {
-                if let None = conf.hosts {
+                if conf.hosts.is_none() {
                     index = Some(i);
                     break;
                 }
             }
         }
         if let Some(i) = index {
-            res = output(req, &configs[i], &stream);
+            Ok(&configs[i])
         }else {
-            res = Response::new(StatusCode::_403, &vec![])
-                .text("403");
+            Err(StatusCode::_403)
         }
     }else {
         // A Host header field must be sent in all HTTP/1.1 request messages
-        res = Response::new(StatusCode::_400, &vec![])
-            .text("400");
+        Err(StatusCode::_400)
     }
 
-    if res.len() != 0 {
-        stream.write(&res).unwrap();
-    }
+}
 
-    stream.flush().unwrap();
 
-}
+// The body is read as it is needed, at most "max_body_size" bytes
+fn output<W: Sink>(mut request: Request, body: &mut dyn Read, config: &ServerConfig, stream: &mut W) -> Vec<u8> {
+
+    // Plain HTTP listener of a TLS server
+    if let Some(port) = config.https {
+        return output_https(&request, config, port);
+    }
 
+    // Nothing outside of the root can be named
+    request.path = match resolve::normalize(&request.path) {
+        Some(path) => path,
+        None => {
+            if let Some(log) = &config.log.error {
+                log.write(&request.method, 400, &request.path);
+            }
+            return Response::new(StatusCode::_400, &config.headers)
+                .text("400");
+        }
+    };
 
-fn output(mut request: Request, config: &ServerConfig, stream: &TcpStream) -> Vec<u8> {
+    // The options of the location the path is in
+    let server = config;
+    let config = server.location(&request.path);
+    if let Some(res) = output_refused(&request, config) {
+        return res;
+    }
 
-    // Not allowed method
-    let allow = config.methods.iter().find(|m| {
-        return **m == request.method;
-    });
-    if let None = allow {
+    // Files don't take a body, it's read and dropped before the response
+    if let Err(err) = io::copy(body, &mut io::sink()) {
+        let status = body_status(&err);
         if let Some(log) = &config.log.error {
-            log.write(&request.method, 405, &request.path);
-        }
-        if &request.method == "OPTIONS" {
-            return Response::new(StatusCode::_405, &config.headers)
-                .header("Allow", &config.methods.join(", "))
-                .text("405");
-        }else {
-            return Response::new(StatusCode::_405, &config.headers)
-                .text("405");
+            log.write(&request.method, status.code(), &request.path);
         }
+        return Response::new(status, &config.headers)
+            .text(&status.code().to_string());
     }
 
-    if let Some(auth) = &config.auth {
-        let authorization = request.headers.get("authorization");
-        if let Some(value) = authorization {
-            if auth != value {
-                if let Some(log) = &config.log.error {
-                    log.write(&request.method, 401, &request.path);
-                }
-                return Response::new(StatusCode::_401, &config.headers)
-                    .header("WWW-Authenticate", "Basic realm=\"User Visible Realm\"")
-                    .text("401");
-            }
-        }else {
-            if let Some(log) = &config.log.error {
-                log.write(&request.method, 401, &request.path);
+    // Hit and miss counters of the file cache
+    if let Some(files) = &config.file_cache {
+        if files.status.as_ref() == Some(&request.path) {
+            if let Some(log) = &config.log.success {
+                log.write(&request.method, 200, &request.path);
             }
-            return Response::new(StatusCode::_401, &config.headers)
-                .header("WWW-Authenticate", "Basic realm=\"User Visible Realm\"")
-                .text("401");
+            return Response::new(StatusCode::_200, &config.headers)
+                .header("Cache-Control", "no-store")
+                .text(&files.report());
         }
     }
 
@@ -344,50 +779,76 @@ fn output(mut request: Request, config: &ServerConfig, stream: &TcpStream) -> Ve
             }
         }
     }
+    let config = match output_location(&request, server, config, &request.path) {
+        Ok(config) => config,
+        Err(res) => return res
+    };
 
-    let cur_path = String::from(".") + &request.path;
     let path_buff = Path::new(&config.root)
-        .join(&cur_path);
+        .join(&request.path[1..]);
     let path = path_buff
         .to_str()
         .unwrap();
 
-    match fs::metadata(&path) {
+    if let Some(deny) = denied(config, &request.path) {
+        return output_denied(&request, config, deny);
+    }
+    if !resolve::symlinks_allowed(&config.root, path, config.symlinks) {
+        return output_403(&request, config);
+    }
+
+    match fs::metadata(path) {
         Ok(meta) => {
             if meta.is_dir() {
                 if request.path.chars().last().unwrap_or('.') == '/' {
                     if let Some(index) = &config.index {
-                        let index_path = fill_path(&path, &index);
-                        match File::open(index_path) {
-                            Ok(file) => {
-                                if let Some(log) = &config.log.success {
-                                    log.write(&request.method, 200, &request.path);
+                        let index_path = fill_path(path, index);
+                        let ext = get_extension(index);
+                        let config = match output_location(&request, server, config, &relative(server, &index_path)) {
+                            Ok(config) => config,
+                            Err(res) => return res
+                        };
+                        if let Some(deny) = denied(config, &relative(config, &index_path)) {
+                            return output_denied(&request, config, deny);
+                        }
+                        if !resolve::symlinks_allowed(&config.root, &index_path, config.symlinks) {
+                            return output_403(&request, config);
+                        }
+                        if config.file_cache.is_some() {
+                            if let Ok(meta) = fs::metadata(&index_path) {
+                                if let Some(res) = output_cached(&request, config, &index_path, ext, &meta) {
+                                    return res;
                                 }
-                                let ext = get_extension(index);
-                                return Response::new(StatusCode::_200, &config.headers)
-                                    .content_type(ext)
-                                    .compress(can_compress(&request, &config, &ext))
-                                    .file(&stream, file);
+                            }
+                        }
+                        match resolve::open(&index_path, config.symlinks) {
+                            Ok(file) => {
+                                return output_file(&request, config, stream, &index_path, ext, file);
                             },
                             Err(_) => {
                                 if let Some(log) = &config.log.error {
                                     log.write(&request.method, 404, &request.path);
                                 }
-                                return output_error(&config, &stream, StatusCode::_404);
+                                return output_error(config, stream, StatusCode::_404);
                             }
                         }
                     }
                     if let Some(option) = &config.directory {
+                        let compress = match can_compress(&request, config, "html") {
+                            Ok(compress) => compress,
+                            Err(_) => return output_406(&request, config)
+                        };
                         if let Some(log) = &config.log.success {
                             log.write(&request.method, 200, &request.path);
                         }
                         return Response::new(StatusCode::_200, &config.headers)
-                            .html(response_dir_html(&path, &request.path, option.time, option.size));
+                            .compress(compress)
+                            .html(response_dir_html(path, &request.path, option.time, option.size, &config.deny));
                     }
                     if let Some(log) = &config.log.error {
                         log.write(&request.method, 404, &request.path);
                     }
-                    return output_error(&config, &stream, StatusCode::_404);
+                    output_error(config, stream, StatusCode::_404)
                 }else {
                     if let Some(log) = &config.log.success {
                         log.write(&request.method, 301, &request.path);
@@ -398,100 +859,508 @@ fn output(mut request: Request, config: &ServerConfig, stream: &TcpStream) -> Ve
                     }else {
                         aims = format!("{}/", request.path);
                     }
-                    return Response::new(StatusCode::_301, &config.headers)
-                        .rewrite(aims);
+                    Response::new(StatusCode::_301, &config.headers)
+                        .rewrite(aims)
                 }
             }else {
-                match File::open(&path) {
+                let ext = get_extension(path);
+                if let Some(res) = output_cached(&request, config, path, ext, &meta) {
+                    return res;
+                }
+                match resolve::open(path, config.symlinks) {
                     Ok(file) => {
-                        if let Some(log) = &config.log.success {
-                            log.write(&request.method, 200, &request.path);
-                        }
-                        let ext = get_extension(&path);
-                        return Response::new(StatusCode::_200, &config.headers)
-                            .content_type(&ext)
-                            .compress(can_compress(&request, &config, &ext))
-                            .file(&stream, file);
+                        output_file(&request, config, stream, path, ext, file)
                     },
                     Err(_) => {
                         if let Some(log) = &config.log.error {
                             log.write(&request.method, 500, &request.path);
                         }
-                        return output_error(&config, &stream, StatusCode::_500);
+                        output_error(config, stream, StatusCode::_500)
                     }
                 }
             }
         },
         Err(_) => {
             if let Some(exts) = &config.extensions {
-                match fallbacks(&path, exts) {
+                match fallbacks(path, exts) {
                     Ok(fallback) => {
-                        if let Some(log) = &config.log.success {
-                            log.write(&request.method, 200, &request.path);
+                        let config = match output_location(&request, server, config, &relative(server, &fallback.path)) {
+                            Ok(config) => config,
+                            Err(res) => return res
+                        };
+                        if let Some(deny) = denied(config, &relative(config, &fallback.path)) {
+                            return output_denied(&request, config, deny);
+                        }
+                        if !resolve::symlinks_allowed(&config.root, &fallback.path, config.symlinks) {
+                            return output_403(&request, config);
+                        }
+                        match resolve::open(&fallback.path, config.symlinks) {
+                            Ok(file) => output_file(&request, config, stream, &fallback.path, &fallback.ext, file),
+                            Err(_) => {
+                                if let Some(log) = &config.log.error {
+                                    log.write(&request.method, 404, &request.path);
+                                }
+                                output_error(config, stream, StatusCode::_404)
+                            }
                         }
-                        return Response::new(StatusCode::_200, &config.headers)
-                            .content_type(&fallback.ext)
-                            .compress(can_compress(&request, &config, &fallback.ext))
-                            .file(&stream, fallback.file);
                     },
                     Err(_) => {
                         if let Some(log) = &config.log.error {
                             log.write(&request.method, 404, &request.path);
                         }
-                        return output_error(&config, &stream, StatusCode::_404);
+                        output_error(config, stream, StatusCode::_404)
                     }
                 }
             }else {
                 if let Some(log) = &config.log.error {
                     log.write(&request.method, 404, &request.path);
                 }
-                return output_error(&config, &stream, StatusCode::_404);
+                output_error(config, stream, StatusCode::_404)
             }
         }
-    };
+    }
 
 }
 
+// Refused by the client address, the method or "auth" of the location
+fn output_refused(request: &Request, config: &ServerConfig) -> Option<Vec<u8>> {
 
-fn can_compress(request: &Request, config: &ServerConfig, ext: &str) -> CompressType {
-
-    if let Some(compress) = &config.compress {
-        if let Some(exts) = &compress.extensions {
-            let allow = exts.iter().find(|item| {
-                return *item == ext
-            });
-            if let None = allow {
-                return CompressType::None;
-            }
-            let encoding = if let Some(val) = request.headers.get("accept-encoding") {
-                val
-            }else {
-                return CompressType::None;
-            };
-
-            let ways: Vec<&str> = encoding.split(", ").collect();
-            for way in ways {
-                match way {
-                    "gzip" => return CompressType::Gzip,
-                    "br" => return CompressType::Br,
-                    _ => {}
-                }
-            }
+    // Clients by their address, before anything else is looked at
+    let client = access::client(request, &config.trusted_proxies, config.proxy_protocol);
+    if let Some(rule) = config.access.refuse(client) {
+        if let Some(log) = &config.log.error {
+            log.write_reason(&request.method, 403, &request.path, &rule);
         }
+        return Some(Response::new(StatusCode::_403, &config.headers)
+            .text("403"));
     }
 
-    return CompressType::None;
-
-}
-
-
-pub fn fill_path(root: &str, file: &str) -> String {
-
-    if Path::new(&file).is_absolute() {
-        file.to_string()
+    // Not allowed method
+    let allow = config.methods.iter().find(|m| {
+        **m == request.method
+    });
+    if allow.is_none() {
+        if let Some(log) = &config.log.error {
+            log.write(&request.method, 405, &request.path);
+        }
+        if &request.method == "OPTIONS" {
+            return Some(Response::new(StatusCode::_405, &config.headers)
+                .header("Allow", &config.methods.join(", "))
+                .text("405"));
+        }else {
+            return Some(Response::new(StatusCode::_405, &config.headers)
+                .text("405"));
+        }
+    }
+
+    if let Some(auth) = &config.auth {
+        let authorization = request.headers.get("authorization");
+        if !authorization.is_some_and(|value| auth.check(value)) {
+            if let Some(log) = &config.log.error {
+                log.write(&request.method, 401, &request.path);
+            }
+            return Some(Response::new(StatusCode::_401, &config.headers)
+                .header("WWW-Authenticate", &format!("Basic realm=\"{}\"", auth.realm))
+                .text("401"));
+        }
+    }
+
+    None
+
+}
+
+
+// The location of the path a request ends up at, rewritten, an index or a fallback.
+// Checked again when it isn't the one of the request path
+fn output_location<'a>(request: &Request, server: &'a ServerConfig, config: &'a ServerConfig, path: &str) -> Result<&'a ServerConfig, Vec<u8>> {
+
+    let location = server.location(path);
+    if std::ptr::eq(location, config) {
+        return Ok(config);
+    }
+    match output_refused(request, location) {
+        Some(res) => Err(res),
+        None => Ok(location)
+    }
+
+}
+
+
+fn output_file<W: Sink>(request: &Request, config: &ServerConfig, stream: &mut W, path: &str, ext: &str, file: File) -> Vec<u8> {
+
+    let meta = match file.metadata() {
+        Ok(meta) => meta,
+        Err(_) => {
+            if let Some(log) = &config.log.error {
+                log.write(&request.method, 500, &request.path);
+            }
+            return output_error(config, stream, StatusCode::_500);
+        }
+    };
+    let validator = Validator::new(&meta, config.etag);
+    let range = range(request, &validator, meta.len());
+
+    // Ranges are served from the file itself, otherwise pick the representation first,
+    // the preconditions are evaluated against its validator
+    let (mode, min) = match range {
+        Range::Full => match precompressed(request, config, path) {
+            Some((min, mode)) => (mode, Some(min)),
+            None => match can_compress(request, config, ext) {
+                Ok(compress) => (compress, None),
+                Err(_) => return output_406(request, config)
+            }
+        },
+        _ => (CompressType::None, None)
+    };
+    let validator = validator.encoded(mode);
+    let cache = cache(config, path);
+    let vary = varies(config, path, ext);
+
+    if let Some(status) = validator.condition(request) {
+        return output_condition(request, config, &validator, cache, vary, status);
+    }
+
+    match range {
+        Range::Partial(ranges) => {
+            if let Some(log) = &config.log.success {
+                log.write(&request.method, 206, &request.path);
+            }
+            return Response::new(StatusCode::_206, &config.headers)
+                .content_type(ext)
+                .header("Accept-Ranges", "bytes")
+                .validator(&validator)
+                .cache(cache)
+                .vary(vary)
+                .ranges(ranges)
+                .file(stream, file);
+        },
+        Range::Unsatisfiable => {
+            if let Some(log) = &config.log.error {
+                log.write(&request.method, 416, &request.path);
+            }
+            return Response::new(StatusCode::_416, &config.headers)
+                .header("Content-Range", &format!("bytes */{}", meta.len()))
+                .text("416");
+        },
+        Range::Full => {}
+    }
+
+    if let Some(log) = &config.log.success {
+        log.write(&request.method, 200, &request.path);
+    }
+    let res = Response::new(StatusCode::_200, &config.headers)
+        .content_type(ext)
+        .validator(&validator)
+        .cache(cache)
+        .vary(vary);
+    if let Some(min) = min {
+        res.encoded(mode)
+            .file(stream, min)
+    }else if let CompressType::None = mode {
+        res.header("Accept-Ranges", "bytes")
+            .file(stream, file)
+    }else {
+        res.compress(mode)
+            .file(stream, file)
+    }
+
+}
+
+
+// A whole file from memory, ranges and precompressed siblings are left to `output_file`
+fn output_cached(request: &Request, config: &ServerConfig, path: &str, ext: &str, meta: &Metadata) -> Option<Vec<u8>> {
+
+    let files = config.file_cache.as_ref()?;
+    if !meta.is_file() || !files.fits(meta.len()) {
+        return None;
+    }
+    if request.headers.contains_key("range") {
+        return None;
+    }
+    if precompressed_mode(request, config, path).is_some() {
+        return None;
+    }
+
+    let mode = match can_compress(request, config, ext) {
+        Ok(compress) => compress,
+        Err(_) => return Some(output_406(request, config))
+    };
+    let (data, validator) = files.get(path, meta, mode, config.etag, config.symlinks)?;
+    let validator = validator.encoded(mode);
+    let cache = cache(config, path);
+    let vary = varies(config, path, ext);
+
+    if let Some(status) = validator.condition(request) {
+        return Some(output_condition(request, config, &validator, cache, vary, status));
+    }
+
+    if let Some(log) = &config.log.success {
+        log.write(&request.method, 200, &request.path);
+    }
+    let res = Response::new(StatusCode::_200, &config.headers)
+        .content_type(ext)
+        .validator(&validator)
+        .cache(cache)
+        .vary(vary);
+    if let CompressType::None = mode {
+        Some(res.header("Accept-Ranges", "bytes").bytes(&data))
+    }else {
+        Some(res.encoded(mode).bytes(&data))
+    }
+
+}
+
+
+// Redirect to the same URL over HTTPS
+fn output_https(request: &Request, config: &ServerConfig, port: u16) -> Vec<u8> {
+
+    let host = request.headers.get("host").map(|host| host.as_str()).unwrap_or("");
+    // Drop the port, keep IPv6 brackets
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host
+    };
+    let mut location = if port == 443 {
+        format!("https://{}", host)
+    }else {
+        format!("https://{}:{}", host, port)
+    };
+    location.push_str(&utf8_percent_encode(&request.path, DEFAULT_ENCODE_SET).to_string());
+    if let Some(query) = &request.query {
+        location.push_str(&utf8_percent_encode(query, QUERY_ENCODE_SET).to_string());
+    }
+
+    if let Some(log) = &config.log.success {
+        log.write(&request.method, 301, &request.path);
+    }
+    Response::new(StatusCode::_301, &config.headers)
+        .rewrite(location)
+
+}
+
+
+// 304 Not Modified or 412 Precondition Failed
+fn output_condition(request: &Request, config: &ServerConfig, validator: &Validator, cache: Option<&Cache>, vary: bool, status: StatusCode) -> Vec<u8> {
+
+    if let StatusCode::_412 = status {
+        if let Some(log) = &config.log.error {
+            log.write(&request.method, 412, &request.path);
+        }
+        return Response::new(StatusCode::_412, &config.headers)
+            .text("412");
+    }
+
+    if let Some(log) = &config.log.success {
+        log.write(&request.method, 304, &request.path);
+    }
+    Response::new(StatusCode::_304, &config.headers)
+        .validator(validator)
+        .cache(cache)
+        .vary(vary)
+        .empty()
+
+}
+
+
+// The first cache rule matching the file, by its path from the root
+fn cache<'a>(config: &'a ServerConfig, path: &str) -> Option<&'a Cache> {
+    let name = relative(config, path);
+    config.cache.iter().find(|cache| cache.matches(&name))
+}
+
+
+// The first deny rule matching a path from the root
+fn denied<'a>(config: &'a ServerConfig, path: &str) -> Option<&'a Deny> {
+    config.deny.iter().find(|deny| deny.matches(path))
+}
+
+
+// "/a/b.txt" for the file at root/a/b.txt
+fn relative(config: &ServerConfig, path: &str) -> String {
+
+    let relative = match Path::new(path).strip_prefix(&config.root) {
+        Ok(relative) => relative,
+        Err(_) => Path::new(path)
+    };
+    let mut name = String::new();
+    for component in relative.components() {
+        if let Component::Normal(part) = component {
+            name.push('/');
+            name.push_str(&part.to_string_lossy());
+        }
+    }
+    name
+
+}
+
+
+// Requested byte ranges, only for GET and if the file hasn't changed since If-Range
+fn range(request: &Request, validator: &Validator, len: u64) -> Range {
+
+    if request.method != "GET" {
+        return Range::Full;
+    }
+    let value = match request.headers.get("range") {
+        Some(value) => value,
+        None => return Range::Full
+    };
+    if let Some(if_range) = request.headers.get("if-range") {
+        if !validator.if_range(if_range) {
+            return Range::Full;
+        }
+    }
+    range::parse(value, len)
+
+}
+
+
+// Negotiate the content coding, Err(()) if nothing acceptable can be sent
+fn can_compress(request: &Request, config: &ServerConfig, ext: &str) -> Result<CompressType, ()> {
+
+    let mut available = vec![];
+
+    if let Some(compress) = &config.compress {
+        if let Some(exts) = &compress.extensions {
+            let allow = exts.iter().find(|item| {
+                *item == ext
+            });
+            if allow.is_some() {
+                // zstd is only served from precompressed files
+                available = compress.mode
+                    .iter()
+                    .filter(|mode| **mode != CompressType::Zstd)
+                    .cloned()
+                    .collect();
+            }
+        }
+    }
+
+    request.accept_encoding(&available)
+
+}
+
+
+// Whether the file may be sent compressed, then every response for it depends on Accept-Encoding
+fn varies(config: &ServerConfig, path: &str, ext: &str) -> bool {
+
+    let compress = match &config.compress {
+        Some(compress) => compress,
+        None => return false
+    };
+    let extension = compress.extensions.as_ref().is_some_and(|exts| exts.iter().any(|item| item == ext));
+    if extension && compress.mode.iter().any(|mode| *mode != CompressType::Zstd) {
+        return true;
+    }
+    compress.precompressed && compress.mode.iter()
+        .filter_map(|mode| mode.extension())
+        .any(|ext| sibling(config, path, ext).is_some())
+
+}
+
+
+// The sibling file with this extension, if it could be served itself
+fn sibling(config: &ServerConfig, path: &str, ext: &str) -> Option<String> {
+
+    let sibling = format!("{}.{}", path, ext);
+    if !Path::new(&sibling).is_file() || denied(config, &relative(config, &sibling)).is_some() {
+        return None;
+    }
+    if !resolve::symlinks_allowed(&config.root, &sibling, config.symlinks) {
+        return None;
+    }
+    Some(sibling)
+
+}
+
+
+// Find a sibling file compressed at build time, e.g. app.js.br for app.js
+fn precompressed(request: &Request, config: &ServerConfig, path: &str) -> Option<(File, CompressType)> {
+
+    let mode = precompressed_mode(request, config, path)?;
+    let sibling = sibling(config, path, mode.extension()?)?;
+    match resolve::open(&sibling, config.symlinks) {
+        Ok(file) => Some((file, mode)),
+        Err(_) => None
+    }
+
+}
+
+
+// Encoding of the sibling file that would be served
+fn precompressed_mode(request: &Request, config: &ServerConfig, path: &str) -> Option<CompressType> {
+
+    let compress = match &config.compress {
+        Some(compress) => compress,
+        None => return None
+    };
+    if !compress.precompressed {
+        return None;
+    }
+
+    let mut available = vec![];
+    for mode in compress.mode.iter() {
+        if let Some(ext) = mode.extension() {
+            if sibling(config, path, ext).is_some() {
+                available.push(*mode);
+            }
+        }
+    }
+
+    match request.accept_encoding(&available) {
+        Ok(CompressType::None) | Err(_) => None,
+        Ok(mode) => Some(mode)
+    }
+
+}
+
+
+// A hidden file, as if it wasn't there or forbidden
+fn output_denied(request: &Request, config: &ServerConfig, deny: &Deny) -> Vec<u8> {
+
+    let status = match deny.status {
+        DenyType::_403 => StatusCode::_403,
+        DenyType::_404 => StatusCode::_404
+    };
+    if let Some(log) = &config.log.error {
+        log.write(&request.method, status.code(), &request.path);
+    }
+    Response::new(status, &config.headers)
+        .text(&status.code().to_string())
+
+}
+
+
+// A symbolic link that isn't followed
+fn output_403(request: &Request, config: &ServerConfig) -> Vec<u8> {
+
+    if let Some(log) = &config.log.error {
+        log.write(&request.method, 403, &request.path);
+    }
+    Response::new(StatusCode::_403, &config.headers)
+        .text("403")
+
+}
+
+
+fn output_406(request: &Request, config: &ServerConfig) -> Vec<u8> {
+
+    if let Some(log) = &config.log.error {
+        log.write(&request.method, 406, &request.path);
+    }
+    Response::new(StatusCode::_406, &config.headers)
+        .header("Vary", "Accept-Encoding")
+        .text("406")
+
+}
+
+
+pub fn fill_path(root: &str, file: &str) -> String {
+
+    if Path::new(&file).is_absolute() {
+        file.to_string()
     } else {
         let buff = Path::new(&root)
-            .join(&file);
+            .join(file);
         let path = buff
             .to_str()
             .unwrap();
@@ -501,7 +1370,7 @@ pub fn fill_path(root: &str, file: &str) -> String {
 }
 
 
-fn output_error(config: &ServerConfig, stream: &TcpStream, status: StatusCode) -> Vec<u8> {
+fn output_error<W: Sink>(config: &ServerConfig, stream: &mut W, status: StatusCode) -> Vec<u8> {
 
     let (path, text) = match status {
         StatusCode::_404 => (&config.error._404, "404"),
@@ -511,18 +1380,18 @@ fn output_error(config: &ServerConfig, stream: &TcpStream, status: StatusCode) -
     let res = Response::new(status, &config.headers);
 
     if let Some(path) = path {
-        match File::open(&path) {
+        match File::open(path) {
             Ok(f) => {
-                return res
+                res
                     .content_type(get_extension(path))
-                    .file(&stream, f);
+                    .file(stream, f)
             },
             Err(_) => {
-                return res.text(text);
+                res.text(text)
             }
         }
     }else {
-        return res.text(text);
+        res.text(text)
     }
 
 }
@@ -534,10 +1403,7 @@ fn get_extension(path: &str) -> &str {
         .extension();
     
     if let Some(ext) = extension {
-        match ext.to_str() {
-            Some(e) => e,
-            None => ""
-        }
+        ext.to_str().unwrap_or_default()
     } else {
         ""
     }
@@ -546,7 +1412,7 @@ fn get_extension(path: &str) -> &str {
 
 
 struct Fallbacks {
-    file: File,
+    path: String,
     ext: String
 }
 
@@ -554,26 +1420,26 @@ fn fallbacks(file: &str, exts: &Vec<String>) -> Result<Fallbacks, ()> {
 
     let has_ext = Path::new(&file)
         .extension();
-    if let Some(_) = has_ext {
+    if has_ext.is_some() {
         return Err(());
     }
 
     for x in exts {
         let path = format!("{}.{}", file, x);
-        if let Ok(file) = File::open(&path) {
+        if Path::new(&path).is_file() {
             return Ok(Fallbacks {
-                file,
+                path,
                 ext: x.to_string()
             });
         }
     }
 
-    return Err(());
+    Err(())
 
 }
 
 
-fn response_dir_html(path: &str, title: &str, show_time: bool, show_size: bool) -> String {
+fn response_dir_html(path: &str, title: &str, show_time: bool, show_size: bool, deny: &[Deny]) -> String {
 
     let dir = match fs::read_dir(path) {
         Ok(dir) => dir,
@@ -622,6 +1488,11 @@ fn response_dir_html(path: &str, title: &str, show_time: bool, show_size: bool)
             },
             None => continue
         };
+        // Hidden files aren't listed
+        let name = format!("{}{}", title, filename);
+        if deny.iter().any(|deny| deny.matches(&name)) {
+            continue;
+        }
 
         let _ = write!(files, "<a href=\"{}\">{}</a>", filename, filename);
 
@@ -689,3 +1560,1038 @@ fn test_bytes_to_size() {
     assert_eq!(bytes_to_size(99999999_f64), "95.37 MB");
     assert_eq!(bytes_to_size(99999999999_f64), "93.13 GB");
 }
+
+#[test]
+fn test_precompressed() {
+    use config::Compress;
+
+    let dir = env::temp_dir().join("see_test_precompressed");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("app.js");
+    let path = path.to_str().unwrap();
+    fs::write(path, "app").unwrap();
+    fs::write(format!("{}.br", path), "br").unwrap();
+    fs::write(format!("{}.gz", path), "gz").unwrap();
+
+    let mut config = ServerConfig {
+        root: dir.to_str().unwrap().to_string(),
+        compress: Some(Compress {
+            mode: vec![CompressType::Zstd, CompressType::Br, CompressType::Gzip],
+            extensions: None,
+            precompressed: true
+        }),
+        ..ServerConfig::default()
+    };
+
+    let request = |encoding: &str| {
+        let buff = format!("GET /app.js HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", encoding);
+        Request::new(buff.as_bytes()).unwrap()
+    };
+    let mode = |config: &ServerConfig, encoding: &str| {
+        precompressed(&request(encoding), config, path).map(|(_, mode)| mode)
+    };
+
+    assert_eq!(mode(&config, "gzip, deflate, br, zstd"), Some(CompressType::Br));
+    assert_eq!(mode(&config, "gzip, deflate"), Some(CompressType::Gzip));
+    assert_eq!(mode(&config, "zstd"), None);
+    assert_eq!(mode(&config, "identity"), None);
+
+    let (mut file, _) = precompressed(&request("br"), &config, path).unwrap();
+    let mut content = String::new();
+    file.read_to_string(&mut content).unwrap();
+    assert_eq!(content, "br");
+
+    // Not chosen, but a cache has to know it could have been
+    let mut stream = vec![];
+    let res = output_file(&request("identity"), &config, &mut stream, path, "js", File::open(path).unwrap());
+    stream.extend(res);
+    let res = String::from_utf8_lossy(&stream).to_string();
+    assert!(res.ends_with("app"));
+    assert_eq!(header(&res, "Vary").unwrap(), "Accept-Encoding");
+    assert_eq!(header(&res, "Content-Encoding"), None);
+
+    // Hidden like the file itself would be
+    config.deny = vec![config::Deny::new("*.br").unwrap()];
+    assert_eq!(mode(&config, "gzip, br"), Some(CompressType::Gzip));
+    config.deny = vec![];
+
+    #[cfg(unix)]
+    {
+        fs::remove_file(format!("{}.br", path)).unwrap();
+        std::os::unix::fs::symlink(format!("{}.gz", path), format!("{}.br", path)).unwrap();
+        config.symlinks = config::Symlinks::Deny;
+        assert_eq!(mode(&config, "br"), None);
+        assert_eq!(mode(&config, "gzip, br"), Some(CompressType::Gzip));
+        config.symlinks = config::Symlinks::Follow;
+        assert_eq!(mode(&config, "br"), Some(CompressType::Br));
+    }
+
+    config.compress.as_mut().unwrap().precompressed = false;
+    assert_eq!(mode(&config, "br"), None);
+    let mut stream = vec![];
+    output_file(&request("identity"), &config, &mut stream, path, "js", File::open(path).unwrap());
+    assert_eq!(header(&String::from_utf8_lossy(&stream), "Vary"), None);
+}
+
+#[cfg(test)]
+fn get(config: &ServerConfig, method: &str, path: &str, headers: &str) -> String {
+    let buff = format!("{} {} HTTP/1.1\r\n{}\r\n\r\n", method, path, headers);
+    let mut stream = vec![];
+    let res = output(Request::new(buff.as_bytes()).unwrap(), &mut io::empty(), config, &mut stream);
+    stream.extend(res);
+    String::from_utf8_lossy(&stream).to_string()
+}
+
+// A new directory and what loads a server rooted there, `yaml` follows its "root"
+#[cfg(test)]
+fn fixture(name: &str) -> (std::path::PathBuf, impl Fn(&str) -> Result<ServerConfig, String>) {
+    let dir = env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let root = dir.clone();
+    let load = move |yaml: &str| {
+        let path = root.join("config.yml");
+        fs::write(&path, format!("- server:\n    listen: 80\n    root: {}\n{}", root.to_str().unwrap(), yaml)).unwrap();
+        ServerConfig::new(path.to_str().unwrap()).map(|configs| configs[0][0].clone())
+    };
+    (dir, load)
+}
+
+#[test]
+fn test_conditional_get() {
+    use config::ETagType;
+
+    let dir = env::temp_dir().join("see_test_conditional_get");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("index.txt"), "hello").unwrap();
+
+    let mut config = ServerConfig {
+        root: dir.to_str().unwrap().to_string(),
+        methods: vec!["GET".to_string(), "HEAD".to_string(), "PUT".to_string()],
+        ..ServerConfig::default()
+    };
+
+    let res = get(&config, "GET", "/index.txt", "Accept: */*");
+    assert!(res.starts_with("HTTP/1.1 200"));
+    let etag = header(&res, "ETag").unwrap();
+    let date = header(&res, "Last-Modified").unwrap();
+
+    let res = get(&config, "GET", "/index.txt", &format!("If-None-Match: {}", etag));
+    assert!(res.starts_with("HTTP/1.1 304"));
+    assert_eq!(header(&res, "ETag"), Some(etag.clone()));
+    assert_eq!(header(&res, "Content-Length"), None);
+    assert!(res.ends_with("\r\n\r\n"));
+
+    assert!(get(&config, "HEAD", "/index.txt", &format!("If-Modified-Since: {}", date)).starts_with("HTTP/1.1 304"));
+    assert!(get(&config, "GET", "/index.txt", "If-None-Match: \"other\"").starts_with("HTTP/1.1 200"));
+    assert!(get(&config, "GET", "/index.txt", "If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT").starts_with("HTTP/1.1 200"));
+    assert!(get(&config, "GET", "/index.txt", "If-Match: \"other\"").starts_with("HTTP/1.1 412"));
+    assert!(get(&config, "PUT", "/index.txt", &format!("If-None-Match: {}", etag)).starts_with("HTTP/1.1 412"));
+    assert!(get(&config, "GET", "/index.txt", "If-Unmodified-Since: Sun, 06 Nov 1994 08:49:37 GMT").starts_with("HTTP/1.1 412"));
+    // Preconditions come before the range
+    assert!(get(&config, "GET", "/index.txt", &format!("If-None-Match: {}\r\nRange: bytes=0-1", etag)).starts_with("HTTP/1.1 304"));
+    assert!(get(&config, "GET", "/index.txt", &format!("If-Match: {}\r\nRange: bytes=0-1", etag)).starts_with("HTTP/1.1 206"));
+
+    config.etag = ETagType::Weak;
+    let res = get(&config, "GET", "/index.txt", "Accept: */*");
+    assert_eq!(header(&res, "ETag"), Some(format!("W/{}", etag)));
+    assert!(get(&config, "GET", "/index.txt", &format!("If-None-Match: {}", etag)).starts_with("HTTP/1.1 304"));
+    assert!(get(&config, "GET", "/index.txt", &format!("If-Match: {}", etag)).starts_with("HTTP/1.1 412"));
+
+    config.etag = ETagType::None;
+    let res = get(&config, "GET", "/index.txt", "Accept: */*");
+    assert_eq!(header(&res, "ETag"), None);
+    assert!(get(&config, "GET", "/index.txt", &format!("If-None-Match: {}", etag)).starts_with("HTTP/1.1 200"));
+    assert!(get(&config, "GET", "/index.txt", &format!("If-Modified-Since: {}", date)).starts_with("HTTP/1.1 304"));
+}
+
+#[test]
+fn test_traversal() {
+    let dir = env::temp_dir().join("see_test_traversal");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("www/a")).unwrap();
+    fs::create_dir_all(dir.join("private")).unwrap();
+    fs::write(dir.join("www/index.txt"), "index").unwrap();
+    fs::write(dir.join("secret.txt"), "secret").unwrap();
+    fs::write(dir.join("private/key.txt"), "secret").unwrap();
+
+    let config = ServerConfig {
+        root: dir.join("www").to_str().unwrap().to_string(),
+        methods: vec!["GET".to_string()],
+        directory: Some(DirectoryOption { time: false, size: false }),
+        ..ServerConfig::default()
+    };
+
+    let payloads = [
+        "/../secret.txt",
+        "/a/../../secret.txt",
+        "//../secret.txt",
+        "/%2e%2e/secret.txt",
+        "/.%2e/secret.txt",
+        "/%2e%2e%2fsecret.txt",
+        "/..%2f..%2fsecret.txt",
+        "/a/%2e%2e/%2e%2e/secret.txt",
+        "/%2e%2e%5csecret.txt",
+        "/..%5c..%5csecret.txt",
+        "/..;/secret.txt",
+        "/%00/../secret.txt",
+        "/index.txt%00.html",
+        "/../private/",
+        "/%2e%2e/private/key.txt",
+        "..%2fsecret.txt",
+        "%2fsecret.txt",
+        "*"
+    ];
+    for payload in payloads.iter() {
+        let res = get(&config, "GET", payload, "Host: localhost");
+        assert!(!res.contains("secret"), "{} {}", payload, res);
+        assert!(!res.starts_with("HTTP/1.1 200"), "{} {}", payload, res);
+    }
+    assert!(get(&config, "GET", "/../secret.txt", "").starts_with("HTTP/1.1 400"));
+    assert!(get(&config, "GET", "/a/../index.txt", "").ends_with("index"));
+    assert!(get(&config, "GET", "//a/./../index.txt", "").ends_with("index"));
+}
+
+#[test]
+fn test_deny() {
+    let (dir, load) = fixture("see_test_deny");
+    fs::create_dir_all(dir.join(".git")).unwrap();
+    fs::create_dir_all(dir.join("private")).unwrap();
+    fs::write(dir.join(".git/config"), "git").unwrap();
+    fs::write(dir.join(".env"), "env").unwrap();
+    fs::write(dir.join("index.html.swp"), "swp").unwrap();
+    fs::write(dir.join("index.html"), "index").unwrap();
+    fs::write(dir.join("private/key.txt"), "key").unwrap();
+    fs::create_dir_all(dir.join(".well-known/acme-challenge")).unwrap();
+    fs::write(dir.join(".well-known/acme-challenge/token"), "token").unwrap();
+    fs::write(dir.join(".well-known/.env"), "env").unwrap();
+    fs::write(dir.join(".well-known.bak"), "bak").unwrap();
+
+    let config = load("    directory: true\n    log:\n      error: logs/error.log\n").unwrap();
+    for path in ["/.git/config", "/.git/", "/.git", "/.env", "/index.html.swp", "/config.yml", "/logs/error.log"] {
+        assert!(get(&config, "GET", path, "").starts_with("HTTP/1.1 404"), "{}", path);
+    }
+    assert!(get(&config, "GET", "/index.html", "").ends_with("index"));
+    let res = get(&config, "GET", "/", "");
+    assert!(res.contains("index.html") && res.contains("private/"));
+    for name in [".git", ".env", ".swp", "config.yml"] {
+        assert!(!res.contains(name), "{}", name);
+    }
+    assert!(!get(&config, "GET", "/logs/", "").contains("error.log"));
+
+    // For ACME challenges and the like, dotfiles inside are still hidden
+    assert!(get(&config, "GET", "/.well-known/acme-challenge/token", "").ends_with("token"));
+    assert!(get(&config, "GET", "/.well-known/.env", "").starts_with("HTTP/1.1 404"));
+    assert!(get(&config, "GET", "/.well-known.bak", "").starts_with("HTTP/1.1 404"));
+    log::flush();
+    let logged = fs::read_to_string(dir.join("logs/error.log")).unwrap();
+    assert!(logged.contains("404  /.git/config"));
+
+    let config = load("    deny: [\"/private 403\", \"*.html\"]\n    extension: [html]\n").unwrap();
+    assert!(get(&config, "GET", "/private/key.txt", "").starts_with("HTTP/1.1 403"));
+    assert!(get(&config, "GET", "/index", "").starts_with("HTTP/1.1 404"));
+    assert!(get(&config, "GET", "/.env", "").ends_with("env"));
+    assert!(get(&config, "GET", "/config.yml", "").starts_with("HTTP/1.1 404"));
+
+    let config = load("    deny: [\".*\", /.well-known]\n").unwrap();
+    assert!(get(&config, "GET", "/.well-known/acme-challenge/token", "").starts_with("HTTP/1.1 404"));
+
+    let config = load("    deny: false\n").unwrap();
+    assert!(get(&config, "GET", "/.git/config", "").ends_with("git"));
+    assert!(load("    deny: [\"[\"]\n").is_err());
+    assert!(load("    deny: [\".* 500\"]\n").is_err());
+}
+
+#[test]
+fn test_auth() {
+    let (dir, load) = fixture("see_test_auth");
+    fs::write(dir.join("index.txt"), "index").unwrap();
+    // "password" with bcrypt
+    fs::write(dir.join("users"), "bob:$2y$05$bvIG6Nmid91Mu9RcmmWZfO5HJIMCT8riNW0hEp8f6/FuA2/mHZFpe\n").unwrap();
+    let basic = |credentials: &str| format!("Authorization: Basic {}", base64::encode(credentials));
+
+    let config = load("    auth:\n      user: name\n      password: pwd\n").unwrap();
+    let res = get(&config, "GET", "/index.txt", "");
+    assert!(res.starts_with("HTTP/1.1 401"));
+    assert_eq!(header(&res, "WWW-Authenticate").unwrap(), "Basic realm=\"User Visible Realm\"");
+    assert!(get(&config, "GET", "/index.txt", &basic("name:pwd")).ends_with("index"));
+    assert!(get(&config, "GET", "/index.txt", &basic("name:pw")).starts_with("HTTP/1.1 401"));
+
+    let config = load("    auth:\n      realm: Team\n      users:\n        alice: secret\n      htpasswd: users\n").unwrap();
+    let res = get(&config, "GET", "/index.txt", &basic("name:pwd"));
+    assert_eq!(header(&res, "WWW-Authenticate").unwrap(), "Basic realm=\"Team\"");
+    assert!(get(&config, "GET", "/index.txt", &basic("alice:secret")).ends_with("index"));
+    assert!(get(&config, "GET", "/index.txt", &basic("bob:password")).ends_with("index"));
+    // Not served, even without "deny"
+    assert!(get(&config, "GET", "/users", &basic("bob:password")).starts_with("HTTP/1.1 404"));
+
+    assert!(load("    auth:\n      user: name\n").is_err());
+    assert!(load("    auth:\n      realm: Team\n").is_err());
+    assert!(load("    auth:\n      htpasswd: missing\n").is_err());
+}
+
+#[test]
+fn test_locations() {
+    let (dir, load) = fixture("see_test_locations");
+    fs::create_dir_all(dir.join("admin/deep")).unwrap();
+    fs::create_dir_all(dir.join("files")).unwrap();
+    fs::write(dir.join("index.html"), "home").unwrap();
+    fs::write(dir.join("admin/index.html"), "admin").unwrap();
+    fs::write(dir.join("admin/deep/home.html"), "deep").unwrap();
+    fs::write(dir.join("admin/report.txt"), "report").unwrap();
+    fs::write(dir.join("files/a.txt"), "a").unwrap();
+    fs::write(dir.join("404.html"), "missing").unwrap();
+    fs::write(dir.join("admin.html"), "not found here").unwrap();
+    fs::write(dir.join("admin/notes.txt"), "notes").unwrap();
+    fs::create_dir_all(dir.join("locked")).unwrap();
+    fs::write(dir.join("locked/index.html"), "locked").unwrap();
+    fs::write(dir.join("secret.html"), "secret").unwrap();
+    fs::create_dir_all(dir.join("private")).unwrap();
+    fs::write(dir.join("private/plan.txt"), "plan").unwrap();
+    let config = load(r#"
+    index: index.html
+    extension: [html]
+    method: [POST]
+    rewrite:
+      /notes: /admin/notes.txt path
+      /plan: /private/plan.txt path
+    header:
+      X-Site: main
+    location:
+      /admin/:
+        auth:
+          user: root
+          password: pwd
+          realm: Admin
+        method: [GET]
+        header:
+          X-Site: admin
+          Cache-Control: no-store
+      /admin/deep/:
+        index: home.html
+      = /admin/report.txt:
+        auth: false
+      ~ \.txt$:
+        header:
+          X-Text: "yes"
+      /files/:
+        directory: true
+        index: false
+        error:
+          404: 404.html
+      = /locked/index.html:
+        auth:
+          user: root
+          password: pwd
+      = /secret.html:
+        auth:
+          user: root
+          password: pwd
+      /private/:
+        access:
+          allow: [10.0.0.0/8]
+"#).unwrap();
+    let admin = format!("Authorization: Basic {}", base64::encode("root:pwd"));
+
+    let res = get(&config, "GET", "/", "");
+    assert!(res.ends_with("home"));
+    assert_eq!(header(&res, "X-Site").unwrap(), "main");
+    assert!(get(&config, "POST", "/", "").starts_with("HTTP/1.1 200"));
+
+    // A prefix location
+    let res = get(&config, "GET", "/admin/", "");
+    assert!(res.starts_with("HTTP/1.1 401"));
+    assert_eq!(header(&res, "WWW-Authenticate").unwrap(), "Basic realm=\"Admin\"");
+    let res = get(&config, "GET", "/admin/", &admin);
+    assert!(res.ends_with("admin"));
+    assert_eq!(header(&res, "X-Site").unwrap(), "admin");
+    assert_eq!(header(&res, "Cache-Control").unwrap(), "no-store");
+    assert!(get(&config, "POST", "/admin/", &admin).starts_with("HTTP/1.1 405"));
+    // Matched after normalizing
+    assert!(get(&config, "GET", "/x/../admin/", "").starts_with("HTTP/1.1 401"));
+    assert!(get(&config, "GET", "//admin//", "").starts_with("HTTP/1.1 401"));
+    assert!(get(&config, "GET", "/admin.html", "").ends_with("not found here"));
+
+    // The longest prefix adds its options to those of /admin/
+    assert!(get(&config, "GET", "/admin/deep/", "").starts_with("HTTP/1.1 401"));
+    let res = get(&config, "GET", "/admin/deep/", &admin);
+    assert!(res.ends_with("deep"));
+    assert_eq!(header(&res, "X-Site").unwrap(), "admin");
+
+    // An exact path comes before a regex and a prefix, it is in /admin/ too
+    let res = get(&config, "GET", "/admin/report.txt", "");
+    assert!(res.ends_with("report"));
+    assert_eq!(header(&res, "X-Text"), None);
+    assert_eq!(header(&res, "X-Site").unwrap(), "admin");
+
+    // A regex in a prefix adds its options to those of the prefix
+    assert!(get(&config, "GET", "/admin/notes.txt", "").starts_with("HTTP/1.1 401"));
+    let res = get(&config, "GET", "/admin/notes.txt", &admin);
+    assert!(res.ends_with("notes"));
+    assert_eq!(header(&res, "X-Text").unwrap(), "yes");
+    assert_eq!(header(&res, "X-Site").unwrap(), "admin");
+
+    // The index file and the extension fallback have their own location
+    assert!(get(&config, "GET", "/locked/", "").starts_with("HTTP/1.1 401"));
+    assert!(get(&config, "GET", "/locked/", &admin).ends_with("locked"));
+    assert!(get(&config, "GET", "/secret", "").starts_with("HTTP/1.1 401"));
+    assert!(get(&config, "GET", "/secret", &admin).ends_with("secret"));
+
+    // So does the path of a rewrite
+    assert!(get(&config, "GET", "/notes", "").starts_with("HTTP/1.1 401"));
+    assert!(get(&config, "GET", "/notes", &admin).ends_with("notes"));
+    assert!(get(&config, "GET", "/plan", "").starts_with("HTTP/1.1 403"));
+
+    // A regex comes before a prefix
+    let res = get(&config, "GET", "/files/a.txt", "");
+    assert_eq!(header(&res, "X-Text").unwrap(), "yes");
+    assert!(get(&config, "GET", "/files/", "").contains("a.txt"));
+    assert!(get(&config, "GET", "/files/b.html", "").ends_with("missing"));
+    assert!(get(&config, "GET", "/b.html", "").ends_with("404"));
+
+    let location = |yaml: &str| load(&format!("    location:\n{}", yaml));
+    assert!(location("      admin: {}\n").is_err());
+    assert!(location("      \"~ (\": {}\n").is_err());
+    assert!(location("      /a: true\n").is_err());
+    assert!(location("      /a:\n        method: GET\n").is_err());
+}
+
+#[test]
+fn test_access() {
+    let (dir, load) = fixture("see_test_access");
+    fs::create_dir_all(dir.join("public")).unwrap();
+    fs::write(dir.join("index.txt"), "index").unwrap();
+    fs::write(dir.join("public/index.txt"), "public").unwrap();
+    fs::write(dir.join(".env"), "env").unwrap();
+    let from = |config: &ServerConfig, peer: &str, path: &str, headers: &str| {
+        let buff = format!("GET {} HTTP/1.1\r\n{}\r\n\r\n", path, headers);
+        let mut request = Request::new(buff.as_bytes()).unwrap();
+        request.peer = Some(peer.parse().unwrap());
+        let mut stream = vec![];
+        let res = output(request, &mut io::empty(), config, &mut stream);
+        stream.extend(res);
+        String::from_utf8_lossy(&stream).to_string()
+    };
+
+    let config = load(r#"
+    access:
+      allow: [10.0.0.0/8, "fd00::/8"]
+      deny: [10.0.0.5]
+    deny: [.*]
+    auth:
+      user: name
+      password: pwd
+    log:
+      error: logs/error.log
+    location:
+      /public/:
+        access:
+          allow: false
+        auth: false
+"#).unwrap();
+    // Refused before "auth" is asked for
+    assert!(from(&config, "10.0.0.5", "/index.txt", "").starts_with("HTTP/1.1 403"));
+    assert!(from(&config, "8.8.8.8", "/index.txt", "").starts_with("HTTP/1.1 403"));
+    assert!(from(&config, "10.0.0.4", "/index.txt", "").starts_with("HTTP/1.1 401"));
+    assert!(from(&config, "fd00::1", "/index.txt", "").starts_with("HTTP/1.1 401"));
+    assert!(from(&config, "::ffff:10.0.0.5", "/index.txt", "").starts_with("HTTP/1.1 403"));
+    assert!(get(&config, "GET", "/index.txt", "").starts_with("HTTP/1.1 403"));
+    // Its own list replaces that of the server, the other is inherited
+    assert!(from(&config, "8.8.8.8", "/public/index.txt", "").ends_with("public"));
+    assert!(from(&config, "10.0.0.5", "/public/index.txt", "").starts_with("HTTP/1.1 403"));
+    // "deny" only has file patterns
+    let basic = format!("Authorization: Basic {}", base64::encode("name:pwd"));
+    assert!(from(&config, "10.0.0.4", "/index.txt", &basic).ends_with("index"));
+    assert!(from(&config, "10.0.0.4", "/.env", &basic).starts_with("HTTP/1.1 404"));
+    log::flush();
+    let logged = fs::read_to_string(dir.join("logs/error.log")).unwrap();
+    assert!(logged.contains("403  /index.txt  deny 10.0.0.5/32 for 10.0.0.5"));
+    assert!(logged.contains("403  /index.txt  not in allow for 8.8.8.8"));
+
+    // Only a trusted proxy can say who the client is
+    let config = load("    access:\n      deny: [1.2.3.4]\n    trusted_proxies: [127.0.0.1]\n").unwrap();
+    assert!(from(&config, "127.0.0.1", "/index.txt", "X-Forwarded-For: 1.2.3.4").starts_with("HTTP/1.1 403"));
+    assert!(from(&config, "127.0.0.1", "/index.txt", "X-Forwarded-For: 1.2.3.4, 5.6.7.8").ends_with("index"));
+    assert!(from(&config, "5.6.7.8", "/index.txt", "X-Forwarded-For: 1.2.3.4").ends_with("index"));
+    assert!(from(&config, "5.6.7.8", "/.env", "").starts_with("HTTP/1.1 404"));
+
+    // An address in "deny" is a file pattern, it doesn't refuse clients
+    let config = load("    deny: [10.0.0.5]\n").unwrap();
+    assert!(from(&config, "10.0.0.5", "/index.txt", "").ends_with("index"));
+
+    assert!(load("    access:\n      allow: [10.0.0.0/33]\n").is_err());
+    assert!(load("    access:\n      deny: [10.0.0.5, .*]\n").is_err());
+    assert!(load("    access:\n      allow: local\n").is_err());
+    assert!(load("    access:\n      alow: [10.0.0.0/8]\n").is_err());
+    assert!(load("    access: [10.0.0.0/8]\n").is_err());
+    assert!(load("    allow: [10.0.0.0/8]\n").is_err());
+    assert!(load("    trusted_proxies: [\"*\"]\n").is_err());
+    assert!(load("    proxy_protocol: true\n").is_err());
+    assert!(load("    location:\n      /a/:\n        deny: [10.0.0.5]\n").is_err());
+    assert!(load("    location:\n      /a/:\n        allow: [10.0.0.5]\n").is_err());
+}
+
+#[test]
+fn test_proxy_protocol() {
+    let read = |stream: &mut TcpStream| {
+        let mut res = String::new();
+        let _ = stream.read_to_string(&mut res);
+        res
+    };
+
+    for engine in [Engine::Thread, Engine::Event] {
+        let mut config = ServerConfig {
+            root: String::from("./"),
+            methods: vec![String::from("GET")],
+            engine,
+            ..ServerConfig::default()
+        };
+        config.access.allow = vec![access::Cidr::parse("1.2.3.4").unwrap()];
+        config.trusted_proxies = vec![access::Cidr::parse("127.0.0.1").unwrap()];
+        config.proxy_protocol = true;
+        let address = listen(vec![config]);
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"PROXY TCP4 1.2.3.4 127.0.0.1 5000 80\r\nGET /Cargo.toml HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read(&mut stream).starts_with("HTTP/1.1 200"));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"PROXY TCP4 5.6.7.8 127.0.0.1 5000 80\r\nGET /Cargo.toml HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read(&mut stream).starts_with("HTTP/1.1 403"));
+
+        // Without the header the connection is closed
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET /Cargo.toml HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(read(&mut stream), "");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinks() {
+    use config::Symlinks;
+    use std::os::unix::fs::symlink;
+
+    let dir = env::temp_dir().join("see_test_symlinks");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("www")).unwrap();
+    fs::create_dir_all(dir.join("other")).unwrap();
+    fs::write(dir.join("other/file.txt"), "linked").unwrap();
+    fs::write(dir.join("www/index.txt"), "index").unwrap();
+    symlink(dir.join("other/file.txt"), dir.join("www/file.txt")).unwrap();
+    symlink(dir.join("other"), dir.join("www/other")).unwrap();
+
+    let mut config = ServerConfig {
+        root: dir.join("www").to_str().unwrap().to_string(),
+        methods: vec!["GET".to_string()],
+        index: Some("file.txt".to_string()),
+        extensions: Some(vec!["txt".to_string()]),
+        ..ServerConfig::default()
+    };
+
+    for symlinks in [Symlinks::Follow, Symlinks::OwnerMatch] {
+        config.symlinks = symlinks;
+        assert!(get(&config, "GET", "/file.txt", "").ends_with("linked"));
+        assert!(get(&config, "GET", "/other/file.txt", "").ends_with("linked"));
+        assert!(get(&config, "GET", "/", "").ends_with("linked"));
+    }
+
+    config.symlinks = Symlinks::Deny;
+    assert!(get(&config, "GET", "/index.txt", "").ends_with("index"));
+    for path in ["/file.txt", "/other/file.txt", "/other/", "/", "/file"] {
+        assert!(get(&config, "GET", path, "").starts_with("HTTP/1.1 403"), "{}", path);
+    }
+    // A link put in place after the check isn't opened either
+    let link = dir.join("www/file.txt");
+    assert!(resolve::open(link.to_str().unwrap(), Symlinks::Deny).is_err());
+    assert!(resolve::open(link.to_str().unwrap(), Symlinks::Follow).is_ok());
+}
+
+#[cfg(test)]
+fn header(res: &str, key: &str) -> Option<String> {
+    res.lines()
+        .find(|line| line.starts_with(&format!("{}: ", key)))
+        .map(|line| line[key.len() + 2..].to_string())
+}
+
+#[test]
+fn test_cache() {
+    let dir = env::temp_dir().join("see_test_cache");
+    fs::create_dir_all(dir.join("assets")).unwrap();
+    fs::write(dir.join("index.html"), "index").unwrap();
+    fs::write(dir.join("about.html"), "about").unwrap();
+    fs::write(dir.join("assets/app.1234.js"), "app").unwrap();
+    fs::write(dir.join("assets/logo.png"), "png").unwrap();
+    fs::write(dir.join("robots.txt"), "txt").unwrap();
+
+    let mut config = ServerConfig {
+        root: dir.to_str().unwrap().to_string(),
+        methods: vec!["GET".to_string()],
+        index: Some("index.html".to_string()),
+        extensions: Some(vec!["html".to_string()]),
+        ..ServerConfig::default()
+    };
+    config.cache = vec![
+        Cache::new("/assets/*.js", "public, max-age=31536000, immutable").unwrap(),
+        Cache::new("png", "max-age=3600").unwrap(),
+        Cache::new("*.html", "no-cache").unwrap()
+    ];
+
+    let res = get(&config, "GET", "/assets/app.1234.js", "");
+    assert_eq!(header(&res, "Cache-Control").unwrap(), "public, max-age=31536000, immutable");
+    assert!(header(&res, "Expires").is_some());
+
+    let res = get(&config, "GET", "/assets/logo.png", "");
+    assert_eq!(header(&res, "Cache-Control").unwrap(), "max-age=3600");
+
+    // The index file and extension fallbacks match by the resolved file
+    let res = get(&config, "GET", "/", "");
+    assert_eq!(header(&res, "Cache-Control").unwrap(), "no-cache");
+    assert_eq!(header(&res, "Expires").unwrap(), "Thu, 01 Jan 1970 00:00:00 GMT");
+    let res = get(&config, "GET", "/about", "");
+    assert_eq!(header(&res, "Cache-Control").unwrap(), "no-cache");
+
+    let res = get(&config, "GET", "/robots.txt", "");
+    assert!(res.starts_with("HTTP/1.1 200"));
+    assert_eq!(header(&res, "Cache-Control"), None);
+    assert_eq!(header(&res, "Expires"), None);
+
+    // Also on 304
+    let etag = header(&get(&config, "GET", "/assets/logo.png", ""), "ETag").unwrap();
+    let res = get(&config, "GET", "/assets/logo.png", &format!("If-None-Match: {}", etag));
+    assert!(res.starts_with("HTTP/1.1 304"));
+    assert_eq!(header(&res, "Cache-Control").unwrap(), "max-age=3600");
+}
+
+#[test]
+fn test_file_cache() {
+    use config::Compress;
+    use file_cache::FileCache;
+    use libflate::gzip;
+
+    let dir = env::temp_dir().join("see_test_file_cache");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("index.txt"), "hello").unwrap();
+
+    let mut disk = ServerConfig {
+        root: dir.to_str().unwrap().to_string(),
+        methods: vec!["GET".to_string()],
+        ..ServerConfig::default()
+    };
+    disk.compress = Some(Compress {
+        mode: vec![CompressType::Gzip],
+        extensions: Some(vec!["txt".to_string()]),
+        precompressed: false
+    });
+    let mut config = disk.clone();
+    config.file_cache = Some(Arc::new(FileCache::new(1024, 1024, Some("/status".to_string()))));
+
+    // The same response as from disk
+    let sorted = |res: String| {
+        let mut lines: Vec<String> = res.lines().map(|line| line.to_string()).collect();
+        lines.sort();
+        lines
+    };
+    for _ in 0..2 {
+        assert_eq!(sorted(get(&config, "GET", "/index.txt", "Accept: */*")), sorted(get(&disk, "GET", "/index.txt", "Accept: */*")));
+    }
+    let res = get(&config, "GET", "/index.txt", "Accept: */*");
+    assert!(res.ends_with("\r\n\r\nhello"));
+    let etag = header(&res, "ETag").unwrap();
+    assert!(get(&config, "GET", "/index.txt", &format!("If-None-Match: {}", etag)).starts_with("HTTP/1.1 304"));
+    assert!(get(&config, "GET", "/index.txt", "Range: bytes=1-2").ends_with("el"));
+
+    // Compressed once with a known length
+    let res = get(&config, "GET", "/index.txt", "Accept-Encoding: gzip");
+    assert_eq!(header(&res, "Content-Encoding").unwrap(), "gzip");
+    let res = output(Request::new(b"GET /index.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n").unwrap(), &mut io::empty(), &config, &mut vec![]);
+    let end = res.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let body = res[end..].to_vec();
+    assert_eq!(header(&String::from_utf8_lossy(&res[..end]), "Content-Length").unwrap(), body.len().to_string());
+    let mut text = String::new();
+    gzip::Decoder::new(&body[..]).unwrap().read_to_string(&mut text).unwrap();
+    assert_eq!(text, "hello");
+
+    let res = get(&config, "GET", "/status", "Accept: */*");
+    assert!(res.ends_with(&format!("hits: 5\nmisses: 1\nfiles: 1\nsize: {}\n", 5 + body.len())));
+
+    // A changed file is read again
+    fs::write(dir.join("index.txt"), "hello world").unwrap();
+    assert!(get(&config, "GET", "/index.txt", "Accept: */*").ends_with("\r\n\r\nhello world"));
+    assert!(get(&config, "GET", "/status", "Accept: */*").contains("misses: 2\n"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_tls_sni() {
+    use config::Tls;
+    use std::net::TcpStream;
+    use rustls::pki_types::ServerName;
+    use std::convert::TryFrom;
+
+    let dir = env::temp_dir().join("see_test_tls_sni");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("index.txt"), "hello").unwrap();
+
+    // A self-signed certificate for each host
+    let mut configs = vec![];
+    let mut roots = rustls::RootCertStore::empty();
+    let mut certs = vec![];
+    for host in ["a.test", "b.test"] {
+        let key = rcgen::generate_simple_self_signed(vec![host.to_string()]).unwrap();
+        let cert = dir.join(format!("{}.crt", host));
+        let pem = dir.join(format!("{}.key", host));
+        fs::write(&cert, key.cert.pem()).unwrap();
+        fs::write(&pem, key.key_pair.serialize_pem()).unwrap();
+        roots.add(key.cert.der().clone()).unwrap();
+        certs.push(key.cert.der().to_vec());
+
+        let mut config = ServerConfig {
+            hosts: Some(vec![host.to_string()]),
+            root: dir.to_str().unwrap().to_string(),
+            methods: vec!["GET".to_string()],
+            ..ServerConfig::default()
+        };
+        config.tls = Some(Tls {
+            certified_key: tls::certified_key(cert.to_str().unwrap(), pem.to_str().unwrap()).unwrap(),
+            redirect: vec![]
+        });
+        configs.push(config);
+    }
+
+    let mut client = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let http1 = Arc::new(client.clone());
+    client.alpn_protocols = vec![b"h2".to_vec()];
+    let h2 = Arc::new(client);
+
+    for engine in [Engine::Thread, Engine::Event] {
+        for config in configs.iter_mut() {
+            config.engine = engine;
+        }
+        let address = listen(configs.clone());
+
+        for (i, host) in ["a.test", "b.test"].iter().enumerate() {
+            let name = ServerName::try_from(host.to_string()).unwrap();
+            let conn = rustls::ClientConnection::new(http1.clone(), name).unwrap();
+            let mut stream = rustls::StreamOwned::new(conn, TcpStream::connect(address).unwrap());
+            let req = format!("GET /index.txt HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", host);
+            stream.write_all(req.as_bytes()).unwrap();
+            let mut res = vec![];
+            let _ = stream.read_to_end(&mut res);
+
+            let peer = stream.conn.peer_certificates().unwrap();
+            assert_eq!(peer[0].to_vec(), certs[i]);
+            let res = String::from_utf8_lossy(&res);
+            assert!(res.starts_with("HTTP/1.1 200"));
+            assert!(res.ends_with("hello"));
+        }
+
+        // HTTP/2 negotiated with ALPN, the server starts with its SETTINGS
+        let name = ServerName::try_from("a.test").unwrap();
+        let conn = rustls::ClientConnection::new(h2.clone(), name).unwrap();
+        let mut stream = rustls::StreamOwned::new(conn, TcpStream::connect(address).unwrap());
+        stream.write_all(http2::PREFACE).unwrap();
+        let mut frame = [0; 9];
+        stream.read_exact(&mut frame).unwrap();
+        assert_eq!(stream.conn.alpn_protocol(), Some(&b"h2"[..]));
+        assert_eq!(frame[3], 0x4);
+    }
+}
+
+// Serve the servers on a free local port
+#[cfg(test)]
+fn listen(configs: Vec<ServerConfig>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = Address::new(listener.local_addr().unwrap(), Site::new(Arc::new(configs)).unwrap());
+    let local = address.local;
+    std::thread::spawn(move || serve(listener, address));
+    local
+}
+
+#[test]
+fn test_https_redirect() {
+    let mut config = ServerConfig {
+        https: Some(443),
+        ..ServerConfig::default()
+    };
+
+    let res = get(&config, "GET", "/a%20b/?x=1", "Host: example.com:80");
+    assert!(res.starts_with("HTTP/1.1 301"));
+    assert_eq!(header(&res, "Location").unwrap(), "https://example.com/a%20b/?x=1");
+
+    config.https = Some(8443);
+    let res = get(&config, "GET", "/", "Host: [::1]");
+    assert_eq!(header(&res, "Location").unwrap(), "https://[::1]:8443/");
+}
+
+#[test]
+fn test_max_connections() {
+    let read = |stream: &mut TcpStream| {
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        res
+    };
+
+    for engine in [Engine::Thread, Engine::Event] {
+        let config = ServerConfig {
+            root: String::from("./"),
+            methods: vec![String::from("GET")],
+            workers: 1,
+            max_connections: 2,
+            engine,
+            ..ServerConfig::default()
+        };
+        let address = listen(vec![config]);
+
+        // One is served and idle, one waits for the worker
+        let idle = TcpStream::connect(address).unwrap();
+        let mut waiting = TcpStream::connect(address).unwrap();
+        let mut refused = TcpStream::connect(address).unwrap();
+        let res = read(&mut refused);
+        assert!(res.starts_with("HTTP/1.1 503"));
+        assert_eq!(header(&res, "Retry-After").unwrap(), "1");
+
+        drop(idle);
+        waiting.write_all(b"GET /Cargo.toml HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(read(&mut waiting).starts_with("HTTP/1.1 200"));
+    }
+}
+
+#[test]
+fn test_reload() {
+    let dir = env::temp_dir().join("see_test_reload");
+    for name in ["a", "b"] {
+        fs::create_dir_all(dir.join(name)).unwrap();
+        fs::write(dir.join(name).join("index.txt"), name).unwrap();
+    }
+    let site = |name: &str, engine: Engine| {
+        let config = ServerConfig {
+            root: dir.join(name).to_str().unwrap().to_string(),
+            methods: vec![String::from("GET")],
+            keep_alive: Some(KeepAlive::default()),
+            engine,
+            ..ServerConfig::default()
+        };
+        Site::new(Arc::new(vec![config])).unwrap()
+    };
+    let get = |stream: &mut TcpStream, body: &str| {
+        stream.write_all(b"GET /index.txt HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut res = vec![];
+        let mut buf = [0; 1024];
+        while !res.ends_with(format!("\r\n\r\n{}", body).as_bytes()) {
+            let size = stream.read(&mut buf).unwrap();
+            assert_ne!(size, 0);
+            res.extend_from_slice(&buf[..size]);
+        }
+    };
+
+    for engine in [Engine::Thread, Engine::Event] {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = Address::new(listener.local_addr().unwrap(), site("a", engine));
+        let serving = address.clone();
+        std::thread::spawn(move || serve(listener, serving));
+
+        let mut kept = TcpStream::connect(address.local).unwrap();
+        get(&mut kept, "a");
+
+        // New connections get the new site, the old ones keep theirs
+        address.replace(site("b", engine));
+        get(&mut TcpStream::connect(address.local).unwrap(), "b");
+        get(&mut kept, "a");
+
+        // The port is given up
+        address.close();
+        let mut closed = false;
+        for _ in 0..100 {
+            if TcpStream::connect(address.local).is_err() {
+                closed = true;
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(closed);
+        get(&mut kept, "a");
+    }
+}
+
+#[test]
+fn test_engines() {
+    use config::Compress;
+    use libflate::gzip;
+
+    let dir = env::temp_dir().join("see_test_engines");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("index.txt"), "hello").unwrap();
+    let big = (0..300_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    fs::write(dir.join("big.bin"), &big).unwrap();
+
+    let mut config = ServerConfig {
+        root: dir.to_str().unwrap().to_string(),
+        methods: vec![String::from("GET"), String::from("HEAD"), String::from("POST")],
+        keep_alive: Some(KeepAlive::default()),
+        max_body_size: 16,
+        compress: Some(Compress {
+            mode: vec![CompressType::Gzip],
+            extensions: Some(vec!["bin".to_string()]),
+            precompressed: false
+        }),
+        ..ServerConfig::default()
+    };
+    config.limit.timeout = 1;
+
+    // Sent in pieces, the server closes the connection in the end
+    let exchanges: Vec<Vec<&[u8]>> = vec![
+        vec![b"GET /index.txt HTTP/1.1\r\nHost: a\r\n\r\nHEAD /index.txt HTTP/1.1\r\nHost: a\r\n\r\n\
+            GET /big.bin HTTP/1.1\r\nHost: a\r\nRange: bytes=10-20\r\n\r\nGET /big.bin HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"],
+        vec![b"GET /index", b".txt HTTP/1.1\r\nHo", b"st: a\r\n", b"\r\nGET /index.txt HTTP/1.0\r\nHost: a\r\n\r\n"],
+        vec![b"POST /index.txt HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhel", b"loGET /index.txt HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"],
+        vec![b"POST /index.txt HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel", b"lo\r\n0\r\n\r\nGET /index.txt HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"],
+        vec![b"POST /index.txt HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n", b"hello\
+            GET /index.txt HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"],
+        vec![b"POST /index.txt HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhel"],
+        vec![b"POST /index.txt HTTP/1.1\r\nHost: a\r\nContent-Length: 17\r\n\r\n"],
+        vec![b"POST /index.txt HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n11\r\n0123456789abcdefg\r\n0\r\n\r\n"],
+        vec![b"BAD\r\n\r\n"],
+        vec![b"GET /index.txt HTTP/1.1\r\n"],
+        vec![b"GET /big.bin HTTP/1.1\r\nHost: a\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n"]
+    ];
+
+    let mut results = vec![];
+    for engine in [Engine::Thread, Engine::Event] {
+        config.engine = engine;
+        let address = listen(vec![config.clone()]);
+        let mut responses = vec![];
+        for pieces in exchanges.iter() {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            for piece in pieces {
+                stream.write_all(piece).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            let mut res = vec![];
+            stream.read_to_end(&mut res).unwrap();
+            responses.push(res);
+        }
+        results.push(responses);
+    }
+
+    // Headers come in any order
+    let sorted = |res: &Vec<u8>| {
+        let res = String::from_utf8_lossy(res).to_string();
+        let mut parts = res.split("HTTP/1.1 ").map(|part| {
+            match part.find("\r\n\r\n") {
+                Some(end) => {
+                    let mut lines = part[..end].split("\r\n").collect::<Vec<&str>>();
+                    lines[1..].sort();
+                    format!("{}{}", lines.join("\r\n"), &part[end..])
+                },
+                None => part.to_string()
+            }
+        }).collect::<Vec<String>>();
+        parts.remove(0);
+        parts
+    };
+    let event = results.pop().unwrap();
+    let thread = results.pop().unwrap();
+    // The gzip header of the last one holds the time it was compressed
+    for (thread, event) in thread.iter().zip(event.iter()).take(exchanges.len() - 1) {
+        assert_eq!(sorted(thread), sorted(event));
+    }
+
+    let status = |res: &Vec<u8>| {
+        String::from_utf8_lossy(res)
+            .split("\r\n\r\n")
+            .filter_map(|part| part.find("HTTP/1.1 ").map(|i| part[i + 9..i + 12].to_string()))
+            .collect::<Vec<String>>()
+    };
+    assert_eq!(status(&event[0]), ["200", "200", "206", "200"]);
+    assert!(event[0].ends_with(&big));
+    assert_eq!(status(&event[1]), ["200", "200"]);
+    assert_eq!(status(&event[2]), ["200", "200"]);
+    assert_eq!(status(&event[3]), ["200", "200"]);
+    // The body is read before the response
+    assert_eq!(status(&event[4]), ["100", "200", "200"]);
+    assert_eq!(status(&event[5]), ["408"]);
+    assert_eq!(status(&event[6]), ["413"]);
+    assert_eq!(status(&event[7]), ["413"]);
+    assert_eq!(status(&event[8]), ["400"]);
+    assert_eq!(status(&event[9]), ["408"]);
+
+    // Compressed as it's sent, in chunks
+    let gunzip = |res: &Vec<u8>| {
+        let start = res.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let mut chunks = &res[start..];
+        let mut min = vec![];
+        loop {
+            let end = chunks.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size = usize::from_str_radix(std::str::from_utf8(&chunks[..end]).unwrap(), 16).unwrap();
+            if size == 0 {
+                break;
+            }
+            min.extend_from_slice(&chunks[end + 2..end + 2 + size]);
+            chunks = &chunks[end + 4 + size..];
+        }
+        let mut data = vec![];
+        gzip::Decoder::new(&min[..]).unwrap().read_to_end(&mut data).unwrap();
+        data
+    };
+    assert_eq!(gunzip(&thread[10]), big);
+    assert_eq!(gunzip(&event[10]), big);
+}
+
+#[test]
+fn test_listen() {
+    let dir = env::temp_dir().join("see_test_listen");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.yml");
+    let path = path.to_str().unwrap();
+    let load = |yaml: &str| {
+        fs::write(path, yaml).unwrap();
+        ServerConfig::new(path)
+    };
+
+    let configs = load(r#"
+- server:
+    host: a
+    listen: [8080, "127.0.0.1:9090", "[::1]:9090"]
+    root: ./
+- server:
+    host: b
+    listen: "127.0.0.1:9090"
+    root: ./
+- server:
+    listen: "8080"
+    root: ./
+"#).unwrap();
+    let groups: Vec<(String, Vec<String>)> = configs.iter()
+        .map(|group| (
+            group[0].listen.to_string(),
+            group.iter().map(|config| config.hosts.clone().unwrap_or(vec![]).join(",")).collect()
+        ))
+        .collect();
+    assert_eq!(groups, vec![
+        ("0.0.0.0:8080".to_string(), vec!["a".to_string(), "".to_string()]),
+        ("127.0.0.1:9090".to_string(), vec!["a".to_string(), "b".to_string()]),
+        ("[::1]:9090".to_string(), vec!["a".to_string()])
+    ]);
+
+    // The same port on IPv4 and IPv6 is fine, on a wildcard and one address it's not
+    assert!(load("- server:\n    listen: [80, \"[::]:80\"]\n    root: ./\n").is_ok());
+    assert!(load("- server:\n    listen: [80, \"127.0.0.1:80\"]\n    root: ./\n").is_err());
+    assert!(load("- server:\n    listen: \"localhost:80\"\n    root: ./\n").is_err());
+    assert!(load("- server:\n    listen: 70000\n    root: ./\n").is_err());
+    assert!(load("- server:\n    root: ./\n").is_err());
+
+    let url = |listen: &str| serving_address(listen.parse().unwrap());
+    assert_eq!(url("0.0.0.0:80"), "http://127.0.0.1");
+    assert_eq!(url("[::]:8080"), "http://[::1]:8080");
+    assert_eq!(url("192.168.1.7:8080"), "http://192.168.1.7:8080");
+    assert_eq!(url("[fd00::7]:80"), "http://[fd00::7]");
+}
@@ -0,0 +1,113 @@
+use std::thread;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// A fixed number of threads serving the connections of one listener.
+// Connections wait in the queue while every worker is busy
+pub struct Pool {
+    sender: SyncSender<Job>,
+    // Connections being served or waiting for a worker
+    active: Arc<AtomicUsize>,
+    max: usize
+}
+
+impl Pool {
+
+    pub fn new(workers: usize, max: usize) -> Pool {
+
+        let (sender, receiver) = sync_channel::<Job>(max);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return
+                };
+                // A panic ends the connection, not the worker
+                let _ = catch_unwind(AssertUnwindSafe(job));
+            });
+        }
+
+        Pool {
+            sender,
+            active: Arc::new(AtomicUsize::new(0)),
+            max
+        }
+
+    }
+
+    // No more room, a new connection should be turned away
+    pub fn full(&self) -> bool {
+        self.active.load(Ordering::SeqCst) >= self.max
+    }
+
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+
+        self.active.fetch_add(1, Ordering::SeqCst);
+        let slot = Slot(self.active.clone());
+        let _ = self.sender.send(Box::new(move || {
+            let _slot = slot;
+            job();
+        }));
+
+    }
+
+}
+
+// Gives the place back when the connection is done, even if it panicked
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::pool::Pool;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pool() {
+        let pool = Pool::new(2, 3);
+        let (done, finished) = channel();
+        let mut releases = vec![];
+
+        for i in 0..3 {
+            assert!(!pool.full());
+            let (release, wait) = channel::<()>();
+            let done = done.clone();
+            pool.execute(move || {
+                let _ = wait.recv();
+                done.send(i).unwrap();
+            });
+            releases.push(release);
+        }
+        // Two are served, one waits in the queue
+        assert!(pool.full());
+
+        releases.clear();
+        let mut results: Vec<i32> = (0..3).map(|_| finished.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        results.sort();
+        assert_eq!(results, vec![0, 1, 2]);
+
+        // A panic doesn't take the worker or the slot with it
+        pool.execute(|| panic!("connection failed"));
+        let done = done.clone();
+        pool.execute(move || done.send(3).unwrap());
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)).unwrap(), 3);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!pool.full());
+    }
+
+}
@@ -0,0 +1,243 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(unix)]
+use std::ffi::{CStr, CString};
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use crate::config::{ServerConfig, Privileges, Chroot};
+
+// The directory to chroot to, every root has to be inside it
+pub fn chroot_dir(privileges: &Privileges, configs: &[&ServerConfig]) -> Result<Option<PathBuf>, String> {
+
+    let dir = match &privileges.chroot {
+        Some(Chroot::Dir(dir)) => canonical(dir)?,
+        Some(Chroot::Root) => {
+            let mut roots = vec![];
+            for config in configs {
+                let root = canonical(&config.root)?;
+                if !roots.contains(&root) {
+                    roots.push(root);
+                }
+            }
+            if roots.len() != 1 {
+                return Err(String::from("\"chroot\" true needs the same root for every server"));
+            }
+            roots.remove(0)
+        },
+        None => return Ok(None)
+    };
+
+    Ok(Some(dir))
+
+}
+
+// The paths of a server as they are seen after chroot
+pub fn relocate(config: &mut ServerConfig, dir: &Path) -> Result<(), String> {
+
+    config.root = inside(&config.root, dir)?;
+    for path in config.error._404.iter_mut().chain(config.error._500.iter_mut()) {
+        // A missing error page is only a 404 anyway
+        if let Ok(new) = inside(path, dir) {
+            *path = new;
+        }
+    }
+    // The htpasswd file is read again when it changes
+    let htpasswd = config.auth.as_ref().and_then(|auth| auth.htpasswd());
+    if let (Some(auth), Some(path)) = (&config.auth, htpasswd) {
+        config.auth = Some(Arc::new(auth.relocate(inside(path, dir)?)));
+    }
+    for location in config.locations.iter_mut() {
+        relocate(&mut location.config, dir)?;
+    }
+    Ok(())
+
+}
+
+fn inside(path: &str, dir: &Path) -> Result<String, String> {
+
+    let path = canonical(path)?;
+    match path.strip_prefix(dir) {
+        Ok(relative) => Ok(Path::new("/").join(relative).to_string_lossy().to_string()),
+        Err(_) => Err(format!("{} is outside of \"chroot\" {}", path.display(), dir.display()))
+    }
+
+}
+
+fn canonical(path: &str) -> Result<PathBuf, String> {
+    fs::canonicalize(path).map_err(|err| format!("{} {}", path, err))
+}
+
+// Give up root for good: groups, chroot, then the user
+#[cfg(unix)]
+pub fn switch(privileges: &Privileges, chroot: Option<&Path>) -> Result<(), String> {
+
+    let user = match &privileges.user {
+        Some(name) => Some(user(name)?),
+        None => None
+    };
+    let gid = match &privileges.group {
+        Some(name) => Some(group(name)?),
+        None => user.as_ref().map(|user| user.gid)
+    };
+
+    unsafe {
+        // Supplementary groups come from /etc/group, before chroot
+        if let Some(gid) = gid {
+            let done = match &user {
+                Some(user) => libc::initgroups(user.name.as_ptr(), gid as _),
+                None => libc::setgroups(0, std::ptr::null())
+            };
+            if done != 0 {
+                return Err(format!("Setting the groups failed, {}", io::Error::last_os_error()));
+            }
+        }
+
+        if let Some(dir) = chroot {
+            let path = CString::new(dir.as_os_str().as_bytes()).map_err(|err| err.to_string())?;
+            if libc::chroot(path.as_ptr()) != 0 {
+                return Err(format!("chroot {} failed, {}", dir.display(), io::Error::last_os_error()));
+            }
+            env::set_current_dir("/").map_err(|err| err.to_string())?;
+        }
+
+        if let Some(gid) = gid {
+            if libc::setgid(gid) != 0 {
+                return Err(format!("setgid {} failed, {}", gid, io::Error::last_os_error()));
+            }
+        }
+
+        if let Some(user) = user {
+            if libc::setuid(user.uid) != 0 {
+                return Err(format!("setuid {} failed, {}", user.uid, io::Error::last_os_error()));
+            }
+            // It must not be possible to get root back
+            if user.uid != 0 && libc::setuid(0) == 0 {
+                return Err(String::from("Root privileges could be regained"));
+            }
+        }
+    }
+
+    Ok(())
+
+}
+
+#[cfg(not(unix))]
+pub fn switch(_: &Privileges, _: Option<&Path>) -> Result<(), String> {
+    Err(String::from("\"privileges\" is not supported on this system"))
+}
+
+#[cfg(unix)]
+struct User {
+    name: CString,
+    uid: libc::uid_t,
+    gid: libc::gid_t
+}
+
+// A name or an id from /etc/passwd
+#[cfg(unix)]
+fn user(name: &str) -> Result<User, String> {
+
+    let c_name = CString::new(name).map_err(|err| err.to_string())?;
+    let passwd = unsafe {
+        match name.parse::<libc::uid_t>() {
+            Ok(uid) => libc::getpwuid(uid),
+            Err(_) => libc::getpwnam(c_name.as_ptr())
+        }
+    };
+    if passwd.is_null() {
+        return Err(format!("Unknown user \"{}\"", name));
+    }
+    unsafe {
+        Ok(User {
+            name: CStr::from_ptr((*passwd).pw_name).to_owned(),
+            uid: (*passwd).pw_uid,
+            gid: (*passwd).pw_gid
+        })
+    }
+
+}
+
+// A name from /etc/group or any id
+#[cfg(unix)]
+fn group(name: &str) -> Result<libc::gid_t, String> {
+
+    if let Ok(gid) = name.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let c_name = CString::new(name).map_err(|err| err.to_string())?;
+    let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if group.is_null() {
+        return Err(format!("Unknown group \"{}\"", name));
+    }
+    Ok(unsafe { (*group).gr_gid })
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::privilege::{chroot_dir, relocate};
+    use crate::config::{ServerConfig, Privileges, Chroot};
+    use crate::auth::{Auth, DEFAULT_REALM};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::fs;
+    use std::env;
+
+    #[test]
+    fn test_relocate() {
+        let dir = env::temp_dir().join("see_test_relocate");
+        fs::create_dir_all(dir.join("www/a")).unwrap();
+        fs::create_dir_all(dir.join("other")).unwrap();
+        fs::write(dir.join("www/404.html"), "404").unwrap();
+        fs::write(dir.join("www/users"), "").unwrap();
+        fs::write(dir.join("other/users"), "").unwrap();
+        let dir = fs::canonicalize(dir).unwrap();
+
+        let server = |root: &str| {
+            let mut config = ServerConfig {
+                root: dir.join(root).to_str().unwrap().to_string(),
+                ..ServerConfig::default()
+            };
+            config.error._404 = Some(dir.join("www/404.html").to_str().unwrap().to_string());
+            config
+        };
+        let mut privileges = Privileges::default();
+        assert_eq!(chroot_dir(&privileges, &[&server("www")]), Ok(None));
+
+        privileges.chroot = Some(Chroot::Root);
+        assert_eq!(chroot_dir(&privileges, &[&server("www"), &server("www/")]), Ok(Some(dir.join("www"))));
+        assert!(chroot_dir(&privileges, &[&server("www"), &server("other")]).is_err());
+
+        let mut config = server("www/a");
+        relocate(&mut config, &dir.join("www")).unwrap();
+        assert_eq!(config.root, "/a");
+        assert_eq!(config.error._404.unwrap(), "/404.html");
+        assert!(relocate(&mut server("other"), &dir.join("www")).is_err());
+
+        let auth = |path: &str| {
+            let htpasswd = Some(dir.join(path).to_str().unwrap().to_string());
+            Some(Arc::new(Auth::new(DEFAULT_REALM.to_string(), HashMap::new(), htpasswd).unwrap()))
+        };
+        let mut config = ServerConfig {
+            auth: auth("www/users"),
+            ..server("www/a")
+        };
+        relocate(&mut config, &dir.join("www")).unwrap();
+        assert_eq!(config.auth.unwrap().htpasswd(), Some("/users"));
+        let mut config = ServerConfig {
+            auth: auth("other/users"),
+            ..server("www/a")
+        };
+        assert!(relocate(&mut config, &dir.join("www")).is_err());
+
+        privileges.chroot = Some(Chroot::Dir(dir.to_str().unwrap().to_string()));
+        assert_eq!(chroot_dir(&privileges, &[&server("www"), &server("other")]), Ok(Some(dir.clone())));
+    }
+
+}
@@ -0,0 +1,144 @@
+// Byte ranges (RFC 7233)
+
+// More ranges than this in one request are ignored and the whole file is sent
+const MAX_RANGES: usize = 32;
+
+#[derive(Debug, PartialEq)]
+pub enum Range {
+    // Send the whole file, the header is malformed or not in bytes
+    Full,
+    // Inclusive (start, end) pairs, sorted and without overlaps
+    Partial(Vec<(u64, u64)>),
+    // None of the ranges overlap the file, 416
+    Unsatisfiable
+}
+
+// Parse the value of the Range header against a file of `len` bytes
+pub fn parse(value: &str, len: u64) -> Range {
+
+    let value = value.trim();
+    if value.len() < 6 || !value[..6].eq_ignore_ascii_case("bytes=") {
+        return Range::Full;
+    }
+
+    let (mut ranges, mut specs) = (vec![], 0);
+
+    for spec in value[6..].split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        specs += 1;
+        if specs > MAX_RANGES {
+            return Range::Full;
+        }
+
+        let mut pair = spec.splitn(2, '-');
+        let (first, last) = match (pair.next(), pair.next()) {
+            (Some(first), Some(last)) => (first.trim(), last.trim()),
+            _ => return Range::Full
+        };
+
+        if first.is_empty() {
+            // Suffix: the last n bytes
+            let n = match last.parse::<u64>() {
+                Ok(n) => n,
+                Err(_) => return Range::Full
+            };
+            if n != 0 && len != 0 {
+                ranges.push((len - n.min(len), len - 1));
+            }
+            continue;
+        }
+
+        let start = match first.parse::<u64>() {
+            Ok(start) => start,
+            Err(_) => return Range::Full
+        };
+        let end = if last.is_empty() {
+            u64::MAX
+        }else {
+            match last.parse::<u64>() {
+                Ok(end) => end,
+                Err(_) => return Range::Full
+            }
+        };
+        if end < start {
+            return Range::Full;
+        }
+        if start < len {
+            ranges.push((start, end.min(len - 1)));
+        }
+    }
+
+    if specs == 0 {
+        return Range::Full;
+    }
+    if ranges.is_empty() {
+        return Range::Unsatisfiable;
+    }
+
+    // Coalesce overlapping and adjacent ranges
+    ranges.sort();
+    let mut merged: Vec<(u64, u64)> = vec![];
+    for (start, end) in ranges {
+        if let Some(last) = merged.last_mut() {
+            if start <= last.1.saturating_add(1) {
+                last.1 = last.1.max(end);
+                continue;
+            }
+        }
+        merged.push((start, end));
+    }
+
+    Range::Partial(merged)
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::range::{parse, Range};
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse("bytes=0-99", 1000), Range::Partial(vec![(0, 99)]));
+        assert_eq!(parse("Bytes = 0-99", 1000), Range::Full);
+        assert_eq!(parse("BYTES=0-0", 1000), Range::Partial(vec![(0, 0)]));
+        assert_eq!(parse("bytes=500-", 1000), Range::Partial(vec![(500, 999)]));
+        assert_eq!(parse("bytes=-200", 1000), Range::Partial(vec![(800, 999)]));
+        assert_eq!(parse("bytes=-2000", 1000), Range::Partial(vec![(0, 999)]));
+        assert_eq!(parse("bytes=900-2000", 1000), Range::Partial(vec![(900, 999)]));
+        assert_eq!(parse("bytes=0-1, 5-6, , 9-", 10), Range::Partial(vec![(0, 1), (5, 6), (9, 9)]));
+    }
+
+    #[test]
+    fn test_parse_range_merge() {
+        assert_eq!(parse("bytes=5-9, 0-4", 100), Range::Partial(vec![(0, 9)]));
+        assert_eq!(parse("bytes=0-10, 5-20, 30-40", 100), Range::Partial(vec![(0, 20), (30, 40)]));
+        assert_eq!(parse("bytes=50-, -10", 100), Range::Partial(vec![(50, 99)]));
+    }
+
+    #[test]
+    fn test_parse_range_invalid() {
+        assert_eq!(parse("", 1000), Range::Full);
+        assert_eq!(parse("items=0-1", 1000), Range::Full);
+        assert_eq!(parse("bytes=", 1000), Range::Full);
+        assert_eq!(parse("bytes=a-b", 1000), Range::Full);
+        assert_eq!(parse("bytes=5-1", 1000), Range::Full);
+        assert_eq!(parse("bytes=1", 1000), Range::Full);
+        assert_eq!(parse("bytes=--1", 1000), Range::Full);
+        assert_eq!(parse(&format!("bytes={}", vec!["0-0"; 33].join(",")), 1000), Range::Full);
+    }
+
+    #[test]
+    fn test_parse_range_unsatisfiable() {
+        assert_eq!(parse("bytes=1000-", 1000), Range::Unsatisfiable);
+        assert_eq!(parse("bytes=1000-1001, 2000-", 1000), Range::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 1000), Range::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), Range::Unsatisfiable);
+        assert_eq!(parse("bytes=-5", 0), Range::Unsatisfiable);
+    }
+
+}
@@ -2,51 +2,82 @@
 
 extern crate percent_encoding;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
 use percent_encoding::percent_decode;
+use crate::config::{CompressType, Limit};
+use crate::response::StatusCode;
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Request {
     pub method: String,
     pub path: String,
     pub query: Option<String>,
-    pub headers: HashMap<String, String>
+    pub version: String,
+    pub headers: HashMap<String, String>,
+    // The address the connection comes from, set by the engine
+    pub peer: Option<IpAddr>
 }
 
 
 const LINE: [u8; 2] = [13, 10];          // '\r\n'
 const SPACE: [u8; 1] = [32];             // ' '
-const COLON_SPACE: [u8; 2] = [58, 32];   // ': '
+const COLON: u8 = 58;                    // ':'
 const QUESTION_MARK: [u8; 1] = [63];     // '?'
+const HEAD_END: [u8; 4] = [13, 10, 13, 10];  // '\r\n\r\n'
+
+// Room for the method and version around the url in the request line
+const REQUEST_LINE_EXTRA: usize = 32;
 
 
 impl Request {
 
     pub fn new(buff: &[u8]) -> Result<Request, ()> {
 
-        let req = Request::split(&buff.to_vec(), &LINE);
-        if req.len() < 1 {
+        // Anything after the empty line is the body
+        let buff = match buff.windows(4).position(|w| w == HEAD_END) {
+            Some(i) => &buff[..i + 2],
+            None => buff
+        };
+
+        let req = Request::split(buff, &LINE);
+        if req.is_empty() {
             return Err(());
         }
 
-        let (method, path, query) = if let Ok(first) = Request::first(&req[0]) {
+        let (method, path, query, version) = if let Ok(first) = Request::first(&req[0]) {
             first
         }else {
             return Err(());
         };
 
-        let mut headers = HashMap::new();
+        let mut headers: HashMap<String, String> = HashMap::new();
         if req.len() > 1 {
             for h in &req[1..] {
-                let header = Request::split(h, &COLON_SPACE);
-                if header.len() != 2 {
-                    break;
-                }
+                let colon = match h.iter().position(|b| *b == COLON) {
+                    Some(i) => i,
+                    None => return Err(())
+                };
                 // The key in the header is uniformly used in lowercase
-                headers.insert(
-                    String::from_utf8_lossy(&header[0]).to_lowercase(),
-                    String::from_utf8_lossy(&header[1]).to_string(),
-                );
+                let key = String::from_utf8_lossy(&h[..colon]).to_lowercase();
+                let value = String::from_utf8_lossy(&h[colon + 1..]).trim().to_string();
+                // No whitespace is allowed before the colon, this also rejects obsolete line folding
+                if key.is_empty() || key.trim() != key {
+                    return Err(());
+                }
+                // Repeated headers are combined into one comma-separated list
+                match headers.get_mut(&key) {
+                    Some(prev) => {
+                        prev.push_str(", ");
+                        prev.push_str(&value);
+                    },
+                    None => {
+                        headers.insert(key, value);
+                    }
+                }
             }
         }
 
@@ -54,12 +85,251 @@ impl Request {
             method,
             path,
             query,
-            headers
+            version,
+            headers,
+            peer: None
         })
 
     }
 
-    fn split(buff: &Vec<u8>, split: &[u8]) -> Vec<Vec<u8>> {
+    // Read the request line and headers from the connection, stopping at the empty line.
+    // The body, and any pipelined request after it, is left in the reader
+    pub fn read<R: BufRead>(reader: &mut R, limit: &Limit) -> Result<Request, StatusCode> {
+
+        let mut head = vec![];
+
+        // Ignore an empty line in front of the request line (RFC 7230 3.5)
+        let mut line = Request::read_line(reader, limit.url_size + REQUEST_LINE_EXTRA, StatusCode::_414)?;
+        if line.is_empty() {
+            line = Request::read_line(reader, limit.url_size + REQUEST_LINE_EXTRA, StatusCode::_414)?;
+            if line.is_empty() {
+                return Err(StatusCode::_400);
+            }
+        }
+        head.extend_from_slice(&line);
+        head.extend_from_slice(&LINE);
+
+        let (mut size, mut count) = (0, 0);
+        loop {
+            let max = limit.header_size.saturating_sub(size + LINE.len());
+            let line = Request::read_line(reader, max, StatusCode::_431)?;
+            if line.is_empty() {
+                break;
+            }
+            count += 1;
+            if count > limit.header_count {
+                return Err(StatusCode::_431);
+            }
+            size += line.len() + LINE.len();
+            head.extend_from_slice(&line);
+            head.extend_from_slice(&LINE);
+        }
+        head.extend_from_slice(&LINE);
+
+        match Request::new(&head) {
+            Ok(request) => Ok(request),
+            Err(_) => Err(StatusCode::_400)
+        }
+
+    }
+
+    // A line without the line break, `too_long` if it doesn't end within `max` bytes
+    fn read_line<R: BufRead>(reader: &mut R, max: usize, too_long: StatusCode) -> Result<Vec<u8>, StatusCode> {
+
+        let mut line = vec![];
+        let result = reader
+            .by_ref()
+            .take(max as u64 + LINE.len() as u64)
+            .read_until(LINE[1], &mut line);
+
+        match result {
+            Ok(_) => {
+                if line.last() != Some(&LINE[1]) {
+                    // The client has gone away in the middle of the request
+                    if line.len() < max + LINE.len() {
+                        return Err(StatusCode::_400);
+                    }
+                    return Err(too_long);
+                }
+                line.pop();
+                if line.last() == Some(&LINE[0]) {
+                    line.pop();
+                }
+                if line.len() > max {
+                    return Err(too_long);
+                }
+                Ok(line)
+            },
+            Err(err) => match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Err(StatusCode::_408),
+                _ => Err(StatusCode::_400)
+            }
+        }
+
+    }
+
+    // Whether the client wants to keep the connection open after the response
+    pub fn keep_alive(&self) -> bool {
+        let connection = match self.headers.get("connection") {
+            Some(value) => value.to_lowercase(),
+            None => String::new()
+        };
+        let has = |token: &str| {
+            connection.split(',').any(|item| item.trim() == token)
+        };
+        match self.version.as_str() {
+            "HTTP/1.1" => !has("close"),
+            "HTTP/1.0" => has("keep-alive"),
+            _ => false
+        }
+    }
+
+    // The decoder of the body that follows the head, at most `max` bytes
+    pub fn decoder(&self, max: u64) -> Result<Decoder, StatusCode> {
+
+        let framing = match self.headers.get("transfer-encoding") {
+            Some(value) => {
+                // Both are present, a classic way to smuggle requests
+                if self.headers.contains_key("content-length") {
+                    return Err(StatusCode::_400);
+                }
+                // Only chunked is supported, and it must be the final coding
+                if !value.trim().eq_ignore_ascii_case("chunked") {
+                    return Err(StatusCode::_501);
+                }
+                Framing::Chunked
+            },
+            None => match self.headers.get("content-length") {
+                Some(value) => {
+                    // Repeated headers have been joined with ", ", they must all agree
+                    let mut lengths = value.split(',').map(|item| item.trim().parse::<u64>());
+                    let length = match lengths.next() {
+                        Some(Ok(length)) => length,
+                        _ => return Err(StatusCode::_400)
+                    };
+                    if lengths.any(|item| item != Ok(length)) {
+                        return Err(StatusCode::_400);
+                    }
+                    if length > max {
+                        return Err(StatusCode::_413);
+                    }
+                    Framing::Length(length)
+                },
+                None => Framing::Length(0)
+            }
+        };
+
+        let expect = match self.headers.get("expect") {
+            Some(value) => {
+                if !value.eq_ignore_ascii_case("100-continue") {
+                    return Err(StatusCode::_417);
+                }
+                match framing {
+                    Framing::Length(0) => false,
+                    // HTTP/1.0 clients don't know about 100 Continue
+                    _ => self.version != "HTTP/1.0"
+                }
+            },
+            None => false
+        };
+
+        Ok(Decoder::new(framing, max, expect))
+
+    }
+
+    // The body that follows the head in `reader`, at most `max` bytes.
+    // `expect` receives "100 Continue" when the client is waiting for it before sending the body
+    pub fn body<'a, R: BufRead, W: Write>(&self, reader: &'a mut R, max: u64, expect: W) -> Result<Body<'a, R, W>, StatusCode> {
+
+        let decoder = self.decoder(max)?;
+        let expect = if decoder.expect { Some(expect) } else { None };
+        Ok(Body {
+            reader,
+            decoder,
+            failed: false,
+            expect
+        })
+
+    }
+
+    // Accept-Encoding negotiation (RFC 7231 5.3.4)
+    // `available` is in the order of server preference, the highest q-value wins
+    // and ties go to the server. Err(()) if even identity has been refused
+    pub fn accept_encoding(&self, available: &[CompressType]) -> Result<CompressType, ()> {
+
+        let value = match self.headers.get("accept-encoding") {
+            Some(value) => value,
+            // No preference, don't take the risk
+            None => return Ok(CompressType::None)
+        };
+
+        let (mut identity, mut any) = (None, None);
+        let mut codings: Vec<(CompressType, f32)> = vec![];
+
+        for item in value.split(',') {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or("").trim();
+            if name.is_empty() {
+                continue;
+            }
+            let q = match Request::qvalue(params) {
+                Some(q) => q,
+                None => continue
+            };
+            if name == "*" {
+                any = Some(q);
+            }else if name.eq_ignore_ascii_case("identity") {
+                identity = Some(q);
+            }else if let Some(coding) = CompressType::from_name(name) {
+                codings.push((coding, q));
+            }
+        }
+
+        let mut best = (CompressType::None, 0_f32);
+        for mode in available {
+            let q = match codings.iter().find(|(coding, _)| coding == mode) {
+                Some((_, q)) => *q,
+                None => any.unwrap_or(0_f32)
+            };
+            if q > best.1 {
+                best = (*mode, q);
+            }
+        }
+
+        // Identity only competes on weight when the client names it (or "*"),
+        // otherwise it is the fallback that is acceptable unless excluded
+        let weight = identity.or(any).unwrap_or(0_f32);
+        let acceptable = match identity {
+            Some(q) => q > 0_f32,
+            None => any != Some(0_f32)
+        };
+
+        if best.1 > 0_f32 && best.1 >= weight {
+            Ok(best.0)
+        }else if acceptable {
+            Ok(CompressType::None)
+        }else {
+            Err(())
+        }
+
+    }
+
+    // q=0.8, None if the weight is malformed
+    fn qvalue<'a>(params: impl Iterator<Item = &'a str>) -> Option<f32> {
+        for param in params {
+            let mut kv = param.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim();
+            if key.eq_ignore_ascii_case("q") {
+                return match kv.next().unwrap_or("").trim().parse::<f32>() {
+                    Ok(q) if (0_f32..=1_f32).contains(&q) => Some(q),
+                    _ => None
+                };
+            }
+        }
+        Some(1_f32)
+    }
+
+    fn split(buff: &[u8], split: &[u8]) -> Vec<Vec<u8>> {
 
         let mut result: Vec<Vec<u8>> = vec![];
         let (buff_len, split_len) = (buff.len(), split.len());
@@ -85,7 +355,7 @@ impl Request {
 
     }
 
-    fn first(buff: &Vec<u8>) -> Result<(String, String, Option<String>), ()> {
+    fn first(buff: &[u8]) -> Result<(String, String, Option<String>, String), ()> {
 
         let line = Request::split(buff, &SPACE);
         if line.len() != 3 {
@@ -109,7 +379,246 @@ impl Request {
             None
         };
 
-        Ok((method, path, query))
+        let version = String::from_utf8_lossy(&line[2]).to_string();
+
+        Ok((method, path, query, version))
+
+    }
+
+}
+
+
+#[derive(Debug, PartialEq)]
+enum Framing {
+    Length(u64),
+    Chunked
+}
+
+// Maximum length of a chunk size line, including chunk extensions
+const CHUNK_LINE_SIZE: usize = 1024;
+
+// Request body decoded as it arrives, Content-Length or chunked (RFC 7230 4.1).
+// Decoding fails after `max` bytes, `body_status` gives the status to answer with
+pub struct Decoder {
+    framing: Framing,
+    step: Step,
+    // A chunk line that hasn't all arrived yet
+    line: Vec<u8>,
+    size: u64,
+    max: u64,
+    // The client waits for "100 Continue" before sending the body
+    expect: bool
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Step {
+    // Bytes left in the current chunk, or in the whole body
+    Data(u64),
+    Size,
+    // The line break after a chunk
+    DataEnd,
+    Trailer,
+    Done
+}
+
+impl Decoder {
+
+    fn new(framing: Framing, max: u64, expect: bool) -> Decoder {
+        let step = match framing {
+            Framing::Length(0) => Step::Done,
+            Framing::Length(length) => Step::Data(length),
+            Framing::Chunked => Step::Size
+        };
+        Decoder { framing, step, line: vec![], size: 0, max, expect }
+    }
+
+    // The client is waiting for "100 Continue" before sending the body
+    pub fn expect(&self) -> bool {
+        self.expect
+    }
+
+    pub fn done(&self) -> bool {
+        self.step == Step::Done
+    }
+
+    fn invalid(message: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+    }
+
+    fn too_large() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, TooLarge)
+    }
+
+    // Decodes what it can of `input` into `output`, returns the bytes used of both.
+    // Nothing is used after the end of the body
+    pub fn decode(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<(usize, usize)> {
+
+        let mut used = 0;
+        let mut written = 0;
+
+        loop {
+            match self.step {
+                Step::Done => break,
+                Step::Data(left) => {
+                    let len = left.min((input.len() - used) as u64).min((output.len() - written) as u64) as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    if self.size + len as u64 > self.max {
+                        return Err(Decoder::too_large());
+                    }
+                    output[written..written + len].copy_from_slice(&input[used..used + len]);
+                    used += len;
+                    written += len;
+                    self.size += len as u64;
+
+                    self.step = match (left - len as u64, &self.framing) {
+                        (0, Framing::Length(_)) => Step::Done,
+                        // Every chunk ends with a line break
+                        (0, Framing::Chunked) => Step::DataEnd,
+                        (left, _) => Step::Data(left)
+                    };
+                },
+                step => {
+                    let rest = &input[used..];
+                    let end = match rest.iter().position(|b| *b == LINE[1]) {
+                        Some(end) => end,
+                        None => {
+                            self.line.extend_from_slice(rest);
+                            used = input.len();
+                            if self.line.len() > CHUNK_LINE_SIZE {
+                                return Err(Decoder::invalid("invalid chunk"));
+                            }
+                            break;
+                        }
+                    };
+                    self.line.extend_from_slice(&rest[..end]);
+                    used += end + 1;
+                    if self.line.len() > CHUNK_LINE_SIZE {
+                        return Err(Decoder::invalid("invalid chunk"));
+                    }
+                    let mut line = std::mem::take(&mut self.line);
+                    if line.last() == Some(&LINE[0]) {
+                        line.pop();
+                    }
+                    self.step = self.next_step(step, &line)?;
+                }
+            }
+        }
+
+        Ok((used, written))
+
+    }
+
+    // The step after a whole chunk line
+    fn next_step(&self, step: Step, line: &[u8]) -> io::Result<Step> {
+
+        match step {
+            Step::Size => {
+                // Chunk extensions are ignored
+                let size = line.split(|b| *b == b';').next().unwrap_or(&[]);
+                let size = String::from_utf8_lossy(size);
+                let size = match u64::from_str_radix(size.trim(), 16) {
+                    Ok(size) => size,
+                    Err(_) => return Err(Decoder::invalid("invalid chunk size"))
+                };
+                if size == 0 {
+                    return Ok(Step::Trailer);
+                }
+                if self.size.saturating_add(size) > self.max {
+                    return Err(Decoder::too_large());
+                }
+                Ok(Step::Data(size))
+            },
+            Step::DataEnd => {
+                if !line.is_empty() {
+                    return Err(Decoder::invalid("invalid chunk"));
+                }
+                Ok(Step::Size)
+            },
+            // Trailer fields are discarded
+            _ => Ok(if line.is_empty() { Step::Done } else { Step::Trailer })
+        }
+
+    }
+
+}
+
+// Request body as a stream read with a `Decoder`.
+// Once reading fails it keeps failing, the rest of the connection can't be trusted
+pub struct Body<'a, R: BufRead, W: Write> {
+    reader: &'a mut R,
+    decoder: Decoder,
+    failed: bool,
+    expect: Option<W>
+}
+
+// More than `max` bytes were sent
+#[derive(Debug)]
+struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "request body too large")
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+// The status of a body that couldn't be read
+pub fn body_status(err: &io::Error) -> StatusCode {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => StatusCode::_408,
+        _ if err.get_ref().is_some_and(|err| err.is::<TooLarge>()) => StatusCode::_413,
+        _ => StatusCode::_400
+    }
+}
+
+impl<'a, R: BufRead, W: Write> Body<'a, R, W> {
+
+    // The client is still waiting for "100 Continue", the body hasn't been sent yet
+    pub fn waiting(&self) -> bool {
+        self.expect.is_some()
+    }
+
+    // A read of the body, `read` remembers when it fails
+    fn read_some(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+
+        if self.decoder.done() || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if let Some(mut expect) = self.expect.take() {
+            expect.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            expect.flush()?;
+        }
+
+        loop {
+            let input = self.reader.fill_buf()?;
+            if input.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "request body incomplete"));
+            }
+            let (used, written) = self.decoder.decode(input, buf)?;
+            self.reader.consume(used);
+            if written > 0 || self.decoder.done() {
+                return Ok(written);
+            }
+        }
+
+    }
+
+}
+
+impl<'a, R: BufRead, W: Write> Read for Body<'a, R, W> {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+
+        if self.failed {
+            return Err(Decoder::invalid("request body failed"));
+        }
+        let result = self.read_some(buf);
+        self.failed = result.is_err();
+        result
 
     }
 
@@ -119,32 +628,37 @@ impl Request {
 #[cfg(test)]
 mod tests {
 
-    use crate::request::Request;
+    use crate::request::{Request, body_status};
+    use crate::config::{CompressType, Limit};
+    use crate::response::StatusCode;
+    use std::io;
+    use std::io::BufReader;
+    use std::io::prelude::*;
 
     #[test]
     fn test_split() {
         assert_eq!(
-            Request::split(&vec![], &[1]),
+            Request::split(&[], &[1]),
             Vec::<Vec<u8>>::new()
         );
         assert_eq!(
-            Request::split(&vec![1, 2], &[1]),
+            Request::split(&[1, 2], &[1]),
             vec![vec![2]]
         );
         assert_eq!(
-            Request::split(&vec![1, 2], &[2]),
+            Request::split(&[1, 2], &[2]),
             vec![vec![1]]
         );
         assert_eq!(
-            Request::split(&vec![1, 2, 3], &[2]),
+            Request::split(&[1, 2, 3], &[2]),
             vec![vec![1], vec![3]]
         );
         assert_eq!(
-            Request::split(&vec![49, 50, 51, 52, 53], &[51]),
+            Request::split(&[49, 50, 51, 52, 53], &[51]),
             vec![vec![49, 50], vec![52, 53]]
         );
         assert_eq!(
-            Request::split(&vec![1, 2, 3, 4, 5, 6], &[1, 2, 3, 4, 5, 6]),
+            Request::split(&[1, 2, 3, 4, 5, 6], &[1, 2, 3, 4, 5, 6]),
             Vec::<Vec<u8>>::new()
         );
     }
@@ -152,27 +666,29 @@ mod tests {
     #[test]
     fn test_parse_first() {
         assert_eq!(
-            Request::first(&b"".to_vec()),
+            Request::first(b""),
             Err(())
         );
         assert_eq!(
-            Request::first(&b"POST /abc".to_vec()),
+            Request::first(b"POST /abc"),
             Err(())
         );
         assert_eq!(
-            Request::first(&b"POST /abc HTTP/1.1".to_vec()),
+            Request::first(b"POST /abc HTTP/1.1"),
             Ok((
                 String::from("POST"),
                 String::from("/abc"),
-                None
+                None,
+                String::from("HTTP/1.1")
             ))
         );
         assert_eq!(
-            Request::first(&b"GET /abc?type=1 HTTP/1.1".to_vec()),
+            Request::first(b"GET /abc?type=1 HTTP/1.1"),
             Ok((
                 String::from("GET"),
                 String::from("/abc"),
-                Some(String::from("?type=1"))
+                Some(String::from("?type=1")),
+                String::from("HTTP/1.1")
             ))
         );
     }
@@ -191,9 +707,257 @@ mod tests {
         assert_eq!(&req.path, "/abc");
         assert_eq!(req.headers.get("host").unwrap(), "127.0.0.1");
         assert_eq!(req.headers.get("accept-encoding").unwrap(), "gzip, deflate, br");
+        assert_eq!(&req.version, "HTTP/1.1");
     }
 
-}
+    #[test]
+    fn test_parse_headers() {
+        let buff = b"\
+            GET / HTTP/1.1\r\n\
+            Host:127.0.0.1\r\n\
+            Referer: http://127.0.0.1:8080/a\r\n\
+            Accept: text/html\r\n\
+            accept:  */*  \r\n\
+            \r\n\
+        ";
+        let req = Request::new(buff).unwrap();
+        assert_eq!(req.headers.get("host").unwrap(), "127.0.0.1");
+        assert_eq!(req.headers.get("referer").unwrap(), "http://127.0.0.1:8080/a");
+        assert_eq!(req.headers.get("accept").unwrap(), "text/html, */*");
+
+        assert!(Request::new(b"GET / HTTP/1.1\r\nHost\r\n\r\n").is_err());
+        assert!(Request::new(b"GET / HTTP/1.1\r\nHost : a\r\n\r\n").is_err());
+        assert!(Request::new(b"GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n").is_err());
+    }
+
+    fn read(buff: &[u8], limit: &Limit) -> Result<Request, StatusCode> {
+        Request::read(&mut BufReader::new(buff), limit)
+    }
+
+    #[test]
+    fn test_read_request() {
+        let limit = Limit::default();
+
+        let mut reader = BufReader::new(&b"\
+            GET /a HTTP/1.1\r\nHost: a\r\n\r\n\
+            GET /b HTTP/1.1\nHost: b\n\n\
+            \r\nGET /c HTTP/1.1\r\n\r\nrest\
+        "[..]);
+        let req = Request::read(&mut reader, &limit).unwrap();
+        assert_eq!((req.path.as_str(), req.headers.get("host").unwrap().as_str()), ("/a", "a"));
+        let req = Request::read(&mut reader, &limit).unwrap();
+        assert_eq!((req.path.as_str(), req.headers.get("host").unwrap().as_str()), ("/b", "b"));
+        let req = Request::read(&mut reader, &limit).unwrap();
+        assert_eq!(req.path.as_str(), "/c");
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "rest");
+
+        assert_eq!(read(b"GET / HTTP/1.1\r\nHost: a\r\n", &limit).unwrap_err(), StatusCode::_400);
+        assert_eq!(read(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", &limit).unwrap().method, "GET");
+        assert_eq!(read(b"\r\n\r\nGET / HTTP/1.1\r\n\r\n", &limit).unwrap_err(), StatusCode::_400);
+        assert_eq!(read(b"GET /\r\n\r\n", &limit).unwrap_err(), StatusCode::_400);
+    }
 
+    #[test]
+    fn test_read_request_limit() {
+        let limit = Limit {
+            url_size: 16,
+            header_size: 32,
+            header_count: 2,
+            ..Limit::default()
+        };
 
+        let url = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(15));
+        assert!(read(url.as_bytes(), &limit).is_ok());
+        let url = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64));
+        assert_eq!(read(url.as_bytes(), &limit).unwrap_err(), StatusCode::_414);
+
+        let header = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(22));
+        assert!(read(header.as_bytes(), &limit).is_ok());
+        let header = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(23));
+        assert_eq!(read(header.as_bytes(), &limit).unwrap_err(), StatusCode::_431);
+        let header = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(1000));
+        assert_eq!(read(header.as_bytes(), &limit).unwrap_err(), StatusCode::_431);
+
+        let count = "GET / HTTP/1.1\r\na: 1\r\nb: 2\r\n\r\n";
+        assert!(read(count.as_bytes(), &limit).is_ok());
+        let count = "GET / HTTP/1.1\r\na: 1\r\nb: 2\r\nc: 3\r\n\r\n";
+        assert_eq!(read(count.as_bytes(), &limit).unwrap_err(), StatusCode::_431);
+    }
 
+    #[test]
+    fn test_keep_alive() {
+        let keep_alive = |first: &str, connection: Option<&str>| {
+            let mut buff = format!("{}\r\nHost: 127.0.0.1\r\n", first);
+            if let Some(value) = connection {
+                buff.push_str(&format!("Connection: {}\r\n", value));
+            }
+            buff.push_str("\r\n");
+            Request::new(buff.as_bytes()).unwrap().keep_alive()
+        };
+        assert!(keep_alive("GET / HTTP/1.1", None));
+        assert!(keep_alive("GET / HTTP/1.1", Some("keep-alive")));
+        assert!(!keep_alive("GET / HTTP/1.1", Some("close")));
+        assert!(!keep_alive("GET / HTTP/1.1", Some("Upgrade, Close")));
+        assert!(!keep_alive("GET / HTTP/1.0", None));
+        assert!(keep_alive("GET / HTTP/1.0", Some("Keep-Alive")));
+    }
+
+    fn accept(value: Option<&str>, available: &[CompressType]) -> Result<CompressType, ()> {
+        let mut buff = b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n".to_vec();
+        if let Some(value) = value {
+            buff.extend_from_slice(format!("Accept-Encoding: {}\r\n", value).as_bytes());
+        }
+        buff.extend_from_slice(b"\r\n");
+        Request::new(&buff).unwrap().accept_encoding(available)
+    }
+
+    #[test]
+    fn test_accept_encoding() {
+        use CompressType::{Gzip, Deflate, Br, None};
+        let all = [Br, Gzip, Deflate];
+
+        assert_eq!(accept(Option::None, &all), Ok(None));
+        assert_eq!(accept(Some(""), &all), Ok(None));
+        assert_eq!(accept(Some("gzip, deflate, br"), &all), Ok(Br));
+        assert_eq!(accept(Some("gzip, deflate, br"), &[Gzip, Br]), Ok(Gzip));
+        assert_eq!(accept(Some("gzip,br"), &[Deflate, Br]), Ok(Br));
+        assert_eq!(accept(Some("GZIP ;  Q=0.5 , identity;q=0.1"), &all), Ok(Gzip));
+        assert_eq!(accept(Some("x-gzip"), &all), Ok(Gzip));
+        assert_eq!(accept(Some("br;q=0.2, gzip;q=0.8"), &all), Ok(Gzip));
+        assert_eq!(accept(Some("br;q=0, gzip"), &[Br]), Ok(None));
+        assert_eq!(accept(Some("*"), &all), Ok(Br));
+        assert_eq!(accept(Some("*;q=0.5, br;q=0"), &all), Ok(Gzip));
+        assert_eq!(accept(Some("compress, sdch"), &all), Ok(None));
+        assert_eq!(accept(Some("br;q=abc"), &all), Ok(None));
+        assert_eq!(accept(Some("identity;q=1, gzip;q=0.5"), &all), Ok(None));
+        assert_eq!(accept(Some("gzip"), &[]), Ok(None));
+    }
+
+    #[test]
+    fn test_accept_encoding_not_acceptable() {
+        use CompressType::{Gzip, Br};
+
+        assert_eq!(accept(Some("identity;q=0"), &[]), Err(()));
+        assert_eq!(accept(Some("*;q=0"), &[Gzip]), Err(()));
+        assert_eq!(accept(Some("br, identity;q=0"), &[Gzip]), Err(()));
+        assert_eq!(accept(Some("br, identity;q=0"), &[Gzip, Br]), Ok(Br));
+        assert_eq!(accept(Some("*;q=0, identity"), &[Gzip]), Ok(CompressType::None));
+    }
+
+    fn body(buff: &[u8], max: u64) -> (Result<Vec<u8>, StatusCode>, Vec<u8>, String) {
+        let mut reader = BufReader::new(buff);
+        let req = Request::read(&mut reader, &Limit::default()).unwrap();
+        let mut expect = vec![];
+        let result = match req.body(&mut reader, max, &mut expect) {
+            Ok(mut body) => {
+                let mut data = vec![];
+                match body.read_to_end(&mut data) {
+                    Ok(_) => Ok(data),
+                    Err(err) => Err(body_status(&err))
+                }
+            },
+            Err(status) => Err(status)
+        };
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        (result, expect, rest)
+    }
+
+    #[test]
+    fn test_body_length() {
+        let (data, _, rest) = body(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET", 1024);
+        assert_eq!(data.unwrap(), b"hello");
+        assert_eq!(rest, "GET");
+
+        let (data, _, rest) = body(b"GET / HTTP/1.1\r\n\r\nGET", 1024);
+        assert_eq!(data.unwrap(), b"");
+        assert_eq!(rest, "GET");
+
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel", 1024).0.unwrap_err(), StatusCode::_400);
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello", 4).0.unwrap_err(), StatusCode::_413);
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: -5\r\n\r\n", 1024).0.unwrap_err(), StatusCode::_400);
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!", 1024).0.unwrap_err(), StatusCode::_400);
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello", 1024).0.unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_body_chunked() {
+        let (data, _, rest) = body(b"\
+            POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n\
+            1;name=value\r\n \r\n\
+            A\r\n0123456789\r\n\
+            0\r\nTrailer: 1\r\n\r\n\
+            GET\
+        ", 1024);
+        assert_eq!(data.unwrap(), b"hello 0123456789");
+        assert_eq!(rest, "GET");
+
+        let chunked = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n";
+        assert_eq!(body(chunked, 10).0.unwrap(), b"helloworld");
+        assert_eq!(body(chunked, 9).0.unwrap_err(), StatusCode::_413);
+
+        assert_eq!(body(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nx\r\n", 1024).0.unwrap_err(), StatusCode::_400);
+        assert_eq!(body(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloX\r\n0\r\n\r\n", 1024).0.unwrap_err(), StatusCode::_400);
+        assert_eq!(body(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n", 1024).0.unwrap_err(), StatusCode::_501);
+        assert_eq!(body(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n", 1024).0.unwrap_err(), StatusCode::_400);
+
+        // Once it fails, what follows isn't read as a chunk
+        let mut reader = BufReader::new(&b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloX\r\n3\r\nabc\r\n0\r\n\r\n"[..]);
+        let req = Request::read(&mut reader, &Limit::default()).unwrap();
+        let mut body = req.body(&mut reader, 1024, io::sink()).unwrap();
+        assert!(body.read_to_end(&mut vec![]).is_err());
+        assert!(body.read(&mut [0; 8]).is_err());
+    }
+
+    #[test]
+    fn test_body_expect() {
+        let (data, expect, _) = body(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nok", 1024);
+        assert_eq!(data.unwrap(), b"ok");
+        assert_eq!(expect, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        let (_, expect, _) = body(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 0\r\n\r\n", 1024);
+        assert_eq!(expect, b"");
+
+        assert_eq!(body(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 9\r\n\r\n", 8).0.unwrap_err(), StatusCode::_413);
+        assert_eq!(body(b"POST / HTTP/1.1\r\nExpect: nothing\r\n\r\n", 8).0.unwrap_err(), StatusCode::_417);
+
+        let mut reader = BufReader::new(&b"POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n"[..]);
+        let req = Request::read(&mut reader, &Limit::default()).unwrap();
+        let body = req.body(&mut reader, 1024, io::sink()).unwrap();
+        assert!(body.waiting());
+    }
+
+    #[test]
+    fn test_decoder() {
+        let head = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let req = Request::read(&mut BufReader::new(&head[..]), &Limit::default()).unwrap();
+        let input = b"5\r\nhello\r\n1;name=value\r\n \r\nA\r\n0123456789\r\n0\r\nTrailer: 1\r\n\r\nGET";
+
+        // The input arrives a byte at a time
+        let mut decoder = req.decoder(1024).unwrap();
+        let mut data = vec![];
+        let mut used = 0;
+        while !decoder.done() {
+            let mut output = [0; 8];
+            let (size, written) = decoder.decode(&input[used..used + 1], &mut output).unwrap();
+            used += size;
+            data.extend_from_slice(&output[..written]);
+        }
+        assert_eq!(data, b"hello 0123456789");
+        assert_eq!(&input[used..], b"GET");
+
+        // Too large as soon as the chunk size is known
+        let mut decoder = req.decoder(8).unwrap();
+        let err = decoder.decode(b"9\r\n", &mut [0; 16]).unwrap_err();
+        assert_eq!(body_status(&err), StatusCode::_413);
+
+        // A chunk line can't grow without end
+        let mut decoder = req.decoder(1024).unwrap();
+        assert!(decoder.decode(&[b'0'; 1000], &mut []).is_ok());
+        assert!(decoder.decode(&[b'0'; 1000], &mut []).is_err());
+    }
+
+}
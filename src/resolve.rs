@@ -0,0 +1,135 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, Component};
+use crate::config::Symlinks;
+
+// The request path without "." and "..", None if it would leave the root.
+// A trailing "/" is kept, it asks for a directory
+pub fn normalize(path: &str) -> Option<String> {
+
+    if !path.starts_with('/') || path.contains('\0') {
+        return None;
+    }
+    // Separators and drives of Windows
+    if cfg!(windows) && (path.contains('\\') || path.contains(':')) {
+        return None;
+    }
+
+    let mut parts = vec![];
+    let mut dir = false;
+    for part in path.split('/') {
+        dir = true;
+        match part {
+            "" | "." => {},
+            ".." => {
+                parts.pop()?;
+            },
+            _ => {
+                parts.push(part);
+                dir = false;
+            }
+        }
+    }
+
+    let mut normal = String::from("/");
+    normal.push_str(&parts.join("/"));
+    if dir && !parts.is_empty() {
+        normal.push('/');
+    }
+    Some(normal)
+
+}
+
+// Whether the symbolic links between the root and `path` may be followed
+pub fn symlinks_allowed(root: &str, path: &str, symlinks: Symlinks) -> bool {
+
+    if let Symlinks::Follow = symlinks {
+        return true;
+    }
+    let relative = match Path::new(path).strip_prefix(root) {
+        Ok(relative) => relative,
+        Err(_) => return false
+    };
+
+    let mut current = Path::new(root).to_path_buf();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => current.push(part),
+            Component::CurDir => continue,
+            _ => return false
+        }
+        let meta = match fs::symlink_metadata(&current) {
+            Ok(meta) => meta,
+            // Not found is answered later
+            Err(_) => return true
+        };
+        if !meta.file_type().is_symlink() {
+            continue;
+        }
+        if let Symlinks::Deny = symlinks {
+            return false;
+        }
+        match fs::metadata(&current) {
+            Ok(target) if same_owner(&meta, &target) => {},
+            _ => return false
+        }
+    }
+    true
+
+}
+
+// Open a file checked by `symlinks_allowed`, a link put in its place since is not followed
+pub fn open(path: &str, symlinks: Symlinks) -> io::Result<File> {
+
+    let mut options = fs::OpenOptions::new();
+    options.read(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if let Symlinks::Deny = symlinks {
+            options.custom_flags(libc::O_NOFOLLOW);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = symlinks;
+    options.open(path)
+
+}
+
+#[cfg(unix)]
+fn same_owner(link: &fs::Metadata, target: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    link.uid() == target.uid()
+}
+
+#[cfg(not(unix))]
+fn same_owner(_: &fs::Metadata, _: &fs::Metadata) -> bool {
+    false
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::resolve::normalize;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("/"), Some(String::from("/")));
+        assert_eq!(normalize("/a/b.txt"), Some(String::from("/a/b.txt")));
+        assert_eq!(normalize("/a/b/"), Some(String::from("/a/b/")));
+        assert_eq!(normalize("//a///b"), Some(String::from("/a/b")));
+        assert_eq!(normalize("/a/./b/../c"), Some(String::from("/a/c")));
+        assert_eq!(normalize("/a/.."), Some(String::from("/")));
+        assert_eq!(normalize("/a/b/."), Some(String::from("/a/b/")));
+        assert_eq!(normalize("/a/b/.."), Some(String::from("/a/")));
+        assert_eq!(normalize("/..."), Some(String::from("/...")));
+        assert_eq!(normalize("/.."), None);
+        assert_eq!(normalize("/a/../../etc/passwd"), None);
+        assert_eq!(normalize("../etc/passwd"), None);
+        assert_eq!(normalize("*"), None);
+        assert_eq!(normalize("/a\0.txt"), None);
+    }
+
+}
@@ -1,15 +1,20 @@
 
 
 extern crate libflate;
+extern crate brotli;
 use crate::config::Header;
-use crate::config::CompressType;
+use crate::config::{CompressType, Cache};
+use crate::validator::{Validator, http_date};
+use crate::connection::Sink;
 use std::collections::HashMap;
 use std::fmt::Write as FmtWrite;
-use libflate::gzip;
-use std::net::TcpStream;
+use libflate::{gzip, zlib};
 use std::fs::File;
-use std::io::BufReader;
+use std::io;
 use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
+
+const MAX_AGE: u64 = 1 << 31;
 
 #[derive(Default, Debug)]
 pub struct Response {
@@ -17,42 +22,76 @@ pub struct Response {
     status: i32,
     header: HashMap<String, String>,
     body: Vec<u8>,
-    compress: CompressType
+    compress: CompressType,
+    ranges: Vec<(u64, u64)>
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum StatusCode {
     _200,
+    _206,
     _301,
     _302,
+    _304,
     _400,
     _401,
     _403,
     _404,
     _405,
-    _500
+    _406,
+    _408,
+    _412,
+    _413,
+    _414,
+    _416,
+    _417,
+    _431,
+    _500,
+    _501,
+    _503
 }
 
-const SERVER_NAME: &str = env!("CARGO_PKG_NAME");
-
-impl Response {
-
-    // HTTP response
-    pub fn new(status: StatusCode, headers: &Vec<Header>) -> Response {
-
-        let mut response = Response::default();
-
-        response.version = "HTTP/1.1";
+impl StatusCode {
 
-        response.status = match status {
+    pub fn code(&self) -> i32 {
+        match self {
             StatusCode::_200 => 200,
+            StatusCode::_206 => 206,
             StatusCode::_301 => 301,
             StatusCode::_302 => 302,
+            StatusCode::_304 => 304,
             StatusCode::_400 => 400,
             StatusCode::_401 => 401,
             StatusCode::_403 => 403,
             StatusCode::_404 => 404,
             StatusCode::_405 => 405,
-            StatusCode::_500 => 500
+            StatusCode::_406 => 406,
+            StatusCode::_408 => 408,
+            StatusCode::_412 => 412,
+            StatusCode::_413 => 413,
+            StatusCode::_414 => 414,
+            StatusCode::_416 => 416,
+            StatusCode::_417 => 417,
+            StatusCode::_431 => 431,
+            StatusCode::_500 => 500,
+            StatusCode::_501 => 501,
+            StatusCode::_503 => 503
+        }
+    }
+
+}
+
+const SERVER_NAME: &str = env!("CARGO_PKG_NAME");
+
+impl Response {
+
+    // HTTP response
+    pub fn new(status: StatusCode, headers: &[Header]) -> Response {
+
+        let mut response = Response {
+            version: "HTTP/1.1",
+            status: status.code(),
+            ..Response::default()
         };
 
         // Add service name
@@ -80,74 +119,74 @@ impl Response {
     // Set the content-type based on the file extension
     pub fn content_type(mut self, ext: &str) -> Response {
 
-        let value = match &ext.as_ref() {
-            &"aac" => "audio/aac",
-            &"abw" => "application/x-abiword",
-            &"arc" => "application/x-freearc",
-            &"avi" => "video/x-msvideo",
-            &"azw" => "application/vnd.amazon.ebook",
-            &"bin" => "application/octet-stream",
-            &"bmp" => "image/bmp",
-            &"bz" => "application/x-bzip",
-            &"bz2" => "application/x-bzip2",
-            &"csh" => "application/x-csh",
-            &"css" => "text/css",
-            &"csv" => "text/csv",
-            &"doc" => "application/msword",
-            &"docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
-            &"eot" => "application/vnd.ms-fontobject",
-            &"epub" => "application/epub+zip",
-            &"gif" => "image/gif",
-            &"htm" => "text/html",
-            &"html" => "text/html",
-            &"ico" => "image/vnd.microsoft.icon",
-            &"ics" => "text/calendar",
-            &"jar" => "application/java-archive",
-            &"jpeg" => "image/jpeg",
-            &"jpg" => "image/jpeg",
-            &"js" => "text/javascript",
-            &"json" => "application/json",
-            &"mjs" => "text/javascript",
-            &"mp3" => "audio/mpeg",
-            &"mpeg" => "video/mpeg",
-            &"mpkg" => "application/vnd.apple.installer+xml",
-            &"odp" => "application/vnd.oasis.opendocument.presentation",
-            &"ods" => "application/vnd.oasis.opendocument.spreadsheet",
-            &"odt" => "application/vnd.oasis.opendocument.text",
-            &"oga" => "audio/ogg",
-            &"ogv" => "video/ogg",
-            &"ogx" => "application/ogg",
-            &"otf" => "font/otf",
-            &"png" => "image/png",
-            &"pdf" => "application/pdf",
-            &"ppt" => "application/vnd.ms-powerpoint",
-            &"pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
-            &"rar" => "application/x-rar-compressed",
-            &"rtf" => "application/rtf",
-            &"sh" => "application/x-sh",
-            &"svg" => "image/svg+xml",
-            &"swf" => "application/x-shockwave-flash",
-            &"tar" => "application/x-tar",
-            &"tif" => "image/tiff",
-            &"tiff" => "image/tiff",
-            &"ttf" => "font/ttf",
-            &"txt" => "text/plain",
-            &"vsd" => "application/vnd.visio",
-            &"wav" => "audio/wav",
-            &"weba" => "audio/webm",
-            &"webm" => "video/webm",
-            &"webp" => "image/webp",
-            &"woff" => "font/woff",
-            &"woff2" => "font/woff2",
-            &"xhtml" => "application/xhtml+xml",
-            &"xls" => "application/vnd.ms-excel",
-            &"xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
-            &"xml" => "text/xml",  // application/xml
-            &"xul" => "application/vnd.mozilla.xul+xml",
-            &"zip" => "application/zip",
-            &"3gp" => "video/3gpp",  // audio/video
-            &"3g2" => "video/3gpp2",  // audio/3gpp2
-            &"7z" => "application/x-7z-compressed",
+        let value = match ext {
+            "aac" => "audio/aac",
+            "abw" => "application/x-abiword",
+            "arc" => "application/x-freearc",
+            "avi" => "video/x-msvideo",
+            "azw" => "application/vnd.amazon.ebook",
+            "bin" => "application/octet-stream",
+            "bmp" => "image/bmp",
+            "bz" => "application/x-bzip",
+            "bz2" => "application/x-bzip2",
+            "csh" => "application/x-csh",
+            "css" => "text/css",
+            "csv" => "text/csv",
+            "doc" => "application/msword",
+            "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+            "eot" => "application/vnd.ms-fontobject",
+            "epub" => "application/epub+zip",
+            "gif" => "image/gif",
+            "htm" => "text/html",
+            "html" => "text/html",
+            "ico" => "image/vnd.microsoft.icon",
+            "ics" => "text/calendar",
+            "jar" => "application/java-archive",
+            "jpeg" => "image/jpeg",
+            "jpg" => "image/jpeg",
+            "js" => "text/javascript",
+            "json" => "application/json",
+            "mjs" => "text/javascript",
+            "mp3" => "audio/mpeg",
+            "mpeg" => "video/mpeg",
+            "mpkg" => "application/vnd.apple.installer+xml",
+            "odp" => "application/vnd.oasis.opendocument.presentation",
+            "ods" => "application/vnd.oasis.opendocument.spreadsheet",
+            "odt" => "application/vnd.oasis.opendocument.text",
+            "oga" => "audio/ogg",
+            "ogv" => "video/ogg",
+            "ogx" => "application/ogg",
+            "otf" => "font/otf",
+            "png" => "image/png",
+            "pdf" => "application/pdf",
+            "ppt" => "application/vnd.ms-powerpoint",
+            "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+            "rar" => "application/x-rar-compressed",
+            "rtf" => "application/rtf",
+            "sh" => "application/x-sh",
+            "svg" => "image/svg+xml",
+            "swf" => "application/x-shockwave-flash",
+            "tar" => "application/x-tar",
+            "tif" => "image/tiff",
+            "tiff" => "image/tiff",
+            "ttf" => "font/ttf",
+            "txt" => "text/plain",
+            "vsd" => "application/vnd.visio",
+            "wav" => "audio/wav",
+            "weba" => "audio/webm",
+            "webm" => "video/webm",
+            "webp" => "image/webp",
+            "woff" => "font/woff",
+            "woff2" => "font/woff2",
+            "xhtml" => "application/xhtml+xml",
+            "xls" => "application/vnd.ms-excel",
+            "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            "xml" => "text/xml",  // application/xml
+            "xul" => "application/vnd.mozilla.xul+xml",
+            "zip" => "application/zip",
+            "3gp" => "video/3gpp",  // audio/video
+            "3g2" => "video/3gpp2",  // audio/3gpp2
+            "7z" => "application/x-7z-compressed",
             _ => "application/octet-stream"
         };
 
@@ -163,6 +202,71 @@ impl Response {
 
     }
 
+    // ETag and Last-Modified of the file
+    pub fn validator(mut self, validator: &Validator) -> Response {
+
+        if let Some(etag) = &validator.etag {
+            self.header.insert("ETag".to_string(), etag.to_string());
+        }
+        if let Some(date) = &validator.last_modified {
+            self.header.insert("Last-Modified".to_string(), date.to_string());
+        }
+        self
+
+    }
+
+    // The representation depends on Accept-Encoding, even when it isn't compressed
+    pub fn vary(mut self, vary: bool) -> Response {
+
+        if vary {
+            self.header.insert("Vary".to_string(), "Accept-Encoding".to_string());
+        }
+        self
+
+    }
+
+    // Cache-Control of the file, with Expires for HTTP/1.0 caches
+    pub fn cache(mut self, cache: Option<&Cache>) -> Response {
+
+        let cache = match cache {
+            Some(cache) => cache,
+            None => return self
+        };
+        self.header.insert("Cache-Control".to_string(), cache.control.to_string());
+
+        let control = cache.control.to_lowercase();
+        let expires = match cache.max_age() {
+            // Larger values are taken as 2^31 seconds, as RFC 9111 recommends
+            Some(seconds) => Some(SystemTime::now() + Duration::from_secs(seconds.min(MAX_AGE))),
+            None if control.contains("no-cache") || control.contains("no-store") => Some(UNIX_EPOCH),
+            None => None
+        };
+        if let Some(time) = expires {
+            self.header.insert("Expires".to_string(), http_date(time));
+        }
+        self
+
+    }
+
+    // Send only these inclusive byte ranges of the file
+    pub fn ranges(mut self, ranges: Vec<(u64, u64)>) -> Response {
+
+        self.ranges = ranges;
+        self
+
+    }
+
+    // The body is already compressed with `mode`
+    pub fn encoded(mut self, mode: CompressType) -> Response {
+
+        if let Some(encoding) = mode.encoding() {
+            self.header.insert("Content-Encoding".to_string(), encoding.to_string());
+            self.header.insert("Vary".to_string(), "Accept-Encoding".to_string());
+        }
+        self
+
+    }
+
     pub fn rewrite(mut self, location: String) -> Vec<u8> {
 
         self.header.insert("Location".to_string(), location);
@@ -170,6 +274,13 @@ impl Response {
 
     }
 
+    // Head only, a 304 has no body and no Content-Length of its own
+    pub fn empty(self) -> Vec<u8> {
+
+        self.head().into_bytes()
+
+    }
+
     pub fn text(mut self, text: &str) -> Vec<u8> {
 
         self.body = text.as_bytes().to_vec();
@@ -180,16 +291,38 @@ impl Response {
 
     pub fn html(mut self, html: String) -> Vec<u8> {
 
-        self.body = html.as_bytes().to_vec();
+        self.body = html.into_bytes();
+        if let Some(encoding) = self.compress.encoding() {
+            if let Ok(min) = compress_bytes(self.compress, &self.body) {
+                self.body = min;
+                self.header.insert("Content-Encoding".to_string(), encoding.to_string());
+                self.header.insert("Vary".to_string(), "Accept-Encoding".to_string());
+            }
+        }
         self.header.insert("Content-Type".to_string(), "text/html".to_string());
         self.build()
 
     }
 
+    // Body already in memory, e.g. a cached file
+    pub fn bytes(mut self, body: &[u8]) -> Vec<u8> {
+
+        self.body = body.to_vec();
+        self.build()
+
+    }
+
     fn build(mut self) -> Vec<u8> {
        
         self.header.insert("Content-Length".to_string(), self.body.len().to_string());
 
+        [self.head().as_bytes(), &self.body[..]].concat()
+
+    }
+
+    // Status line and headers
+    fn head(&self) -> String {
+
         let mut res = String::new();
 
         let _ = write!(res, "{} {}\r\n", self.version, self.status);
@@ -199,43 +332,104 @@ impl Response {
         }
 
         res.push_str("\r\n");
-
-        [&res.as_bytes()[..], &self.body[..]].concat()
+        res
 
     }
 
-    pub fn file(mut self, mut stream: &TcpStream, file: File) -> Vec<u8> {
+    pub fn file<W: Sink>(mut self, mut stream: W, file: File) -> Vec<u8> {
 
-//        if self.gzip {
-//            self.header.insert("Content-Encoding".to_string(), "gzip".to_string());
-//        }
-//        self.header.insert("Transfer-Encoding".to_string(), "chunked".to_string());
+        if !self.ranges.is_empty() {
+            return self.file_ranges(stream, file);
+        }
 
-        let meta = file.metadata().unwrap();
-        self.header.insert("Content-Length".to_string(), format!("{}", meta.len()));
+        let len = match file.metadata() {
+            Ok(meta) => meta.len(),
+            Err(_) => return vec![]
+        };
 
-        let mut res = String::new();
-        let _ = write!(res, "{} {}\r\n", self.version, self.status);
-        for (key, value) in self.header.iter() {
-            let _ = write!(res, "{}: {}\r\n", key, value);
+        if let Some(encoding) = self.compress.encoding() {
+            self.header.insert("Content-Encoding".to_string(), encoding.to_string());
+            self.header.insert("Vary".to_string(), "Accept-Encoding".to_string());
+            self.header.insert("Transfer-Encoding".to_string(), "chunked".to_string());
+            self.header.remove("Content-Length");
+        }else {
+            self.header.insert("Content-Length".to_string(), format!("{}", len));
         }
-        res.push_str("\r\n");
-        stream.write(res.as_bytes()).unwrap();
 
-        loop {
-            let mut render = BufReader::new(&file);
-            if let Ok(data) = render.fill_buf() {
-                if data.len() != 0 {
-                    if let Err(_) = stream.write(data) {
-                        break;
-                    }
-                }else {
-                    break;
-                }
-            }else {
-                break;
+        if stream.write_all(self.head().as_bytes()).is_err() {
+            return vec![];
+        }
+
+        if let CompressType::None = self.compress {
+            let _ = stream.send_file(&file, 0, len);
+        }else if let Ok(file) = CompressedFile::new(file, self.compress) {
+            // Compressed size is unknown in advance, send the body in chunks
+            let _ = stream.send_compressed(file);
+        }
+
+        vec![]
+
+    }
+
+    // 206 Partial Content, one range as is, several as multipart/byteranges
+    fn file_ranges<W: Sink>(mut self, mut stream: W, file: File) -> Vec<u8> {
+
+        let len = match file.metadata() {
+            Ok(meta) => meta.len(),
+            Err(_) => return vec![]
+        };
+        let ranges = std::mem::take(&mut self.ranges);
+
+        // Header of each part, the tail closes the multipart body
+        let mut parts = vec![];
+        let mut tail = String::new();
+
+        if ranges.len() == 1 {
+            let (start, end) = ranges[0];
+            self.header.insert("Content-Range".to_string(), format!("bytes {}-{}/{}", start, end, len));
+            self.header.insert("Content-Length".to_string(), (end - start + 1).to_string());
+            parts.push((String::new(), start, end));
+        }else {
+            let boundary = format!("see-{:x}", SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_nanos())
+                .unwrap_or(0));
+            let content_type = match self.header.get("Content-Type") {
+                Some(value) => value.to_string(),
+                None => "application/octet-stream".to_string()
+            };
+            let mut length = 0;
+            for (start, end) in ranges {
+                let head = format!(
+                    "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, content_type, start, end, len
+                );
+                length += head.len() as u64 + (end - start + 1) + 2;
+                parts.push((head, start, end));
             }
+            tail = format!("--{}--\r\n", boundary);
+            length += tail.len() as u64;
+            self.header.insert("Content-Type".to_string(), format!("multipart/byteranges; boundary={}", boundary));
+            self.header.insert("Content-Length".to_string(), length.to_string());
+        }
+
+        if stream.write_all(self.head().as_bytes()).is_err() {
+            return vec![];
+        }
+
+        for (head, start, end) in parts {
+            if stream.write_all(head.as_bytes()).is_err() {
+                return vec![];
+            }
+            if stream.send_file(&file, start, end - start + 1).is_err() {
+                return vec![];
+            }
+            if !tail.is_empty()
+                && stream.write_all(b"\r\n").is_err() {
+                    return vec![];
+                }
         }
+        let _ = stream.write_all(tail.as_bytes());
 
         vec![]
 
@@ -244,17 +438,177 @@ impl Response {
 }
 
 
-fn gzip_min(data: &[u8]) -> Result<Vec<u8>, ()> {
-    let mut encoder = match gzip::Encoder::new(Vec::new()) {
+const CHUNK_SIZE: usize = 8192;
+
+// Transfer-Encoding: chunked
+struct Chunked<W: Write> {
+    inner: W
+}
+
+impl<W: Write> Chunked<W> {
+
+    fn new(inner: W) -> Chunked<W> {
+        Chunked {
+            inner
+        }
+    }
+
+    // Write the last chunk
+    fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+}
+
+impl<W: Write> Write for Chunked<W> {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:X}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+}
+
+
+// Content-Encoding: gzip | deflate | br
+enum Encoder<W: Write> {
+    Gzip(gzip::Encoder<W>),
+    Deflate(zlib::Encoder<W>),
+    Br(Box<brotli::CompressorWriter<W>>)
+}
+
+impl<W: Write> Encoder<W> {
+
+    fn new(mode: CompressType, inner: W) -> io::Result<Encoder<W>> {
+        match mode {
+            CompressType::Gzip => Ok(Encoder::Gzip(gzip::Encoder::new(inner)?)),
+            // The "deflate" coding is the zlib format (RFC 7230 4.2.2)
+            CompressType::Deflate => Ok(Encoder::Deflate(zlib::Encoder::new(inner)?)),
+            CompressType::Br => Ok(Encoder::Br(Box::new(brotli::CompressorWriter::new(inner, CHUNK_SIZE, BROTLI_QUALITY, BROTLI_WINDOW)))),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported compression mode"))
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut W {
+        match self {
+            Encoder::Gzip(encoder) => encoder.as_inner_mut(),
+            Encoder::Deflate(encoder) => encoder.as_inner_mut(),
+            Encoder::Br(encoder) => encoder.get_mut()
+        }
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish().into_result(),
+            Encoder::Deflate(encoder) => encoder.finish().into_result(),
+            Encoder::Br(encoder) => Ok(encoder.into_inner())
+        }
+    }
+
+}
+
+impl<W: Write> Write for Encoder<W> {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Deflate(encoder) => encoder.write(buf),
+            Encoder::Br(encoder) => encoder.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Deflate(encoder) => encoder.flush(),
+            Encoder::Br(encoder) => encoder.flush()
+        }
+    }
+
+}
+
+// File bytes compressed at a time
+const COMPRESS_SIZE: usize = 65536;
+
+// A file compressed a piece at a time, each piece a chunk of Transfer-Encoding: chunked
+pub struct CompressedFile {
+    file: File,
+    // None after the last chunk
+    encoder: Option<Encoder<Vec<u8>>>
+}
+
+impl CompressedFile {
+
+    pub fn new(file: File, mode: CompressType) -> io::Result<CompressedFile> {
+        Ok(CompressedFile {
+            file,
+            encoder: Some(Encoder::new(mode, Vec::new())?)
+        })
+    }
+
+    pub fn finished(&self) -> bool {
+        self.encoder.is_none()
+    }
+
+    // The next chunk, it can be empty while the encoder holds on to what it was given
+    pub fn next_chunk(&mut self) -> io::Result<Vec<u8>> {
+
+        let mut encoder = match self.encoder.take() {
+            Some(encoder) => encoder,
+            None => return Ok(vec![])
+        };
+
+        let mut buf = vec![0; COMPRESS_SIZE];
+        let size = loop {
+            match self.file.read(&mut buf) {
+                Ok(size) => break size,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err)
+            }
+        };
+
+        let mut chunk = vec![];
+        if size == 0 {
+            Chunked::new(&mut chunk).write_all(&encoder.finish()?)?;
+            Chunked::new(&mut chunk).finish()?;
+            return Ok(chunk);
+        }
+
+        encoder.write_all(&buf[..size])?;
+        Chunked::new(&mut chunk).write_all(&std::mem::take(encoder.get_mut()))?;
+        self.encoder = Some(encoder);
+        Ok(chunk)
+
+    }
+
+}
+
+// Brotli quality 0-11, 5 is a good trade-off for on-the-fly compression
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+pub fn compress_bytes(mode: CompressType, data: &[u8]) -> Result<Vec<u8>, ()> {
+    let mut encoder = match Encoder::new(mode, Vec::new()) {
         Ok(encoder) => encoder,
         Err(_) => {
             return Err(());
         }
     };
-    if let Err(_) = encoder.write_all(data) {
+    if encoder.write_all(data).is_err() {
         return Err(());
     }
-    if let Ok(min) = encoder.finish().into_result() {
+    if let Ok(min) = encoder.finish() {
         Ok(min)
     }else {
         Err(())
@@ -262,19 +616,185 @@ fn gzip_min(data: &[u8]) -> Result<Vec<u8>, ()> {
 }
 
 
-//#[cfg(test)]
-//mod tests {
-//
-//    use crate::response::Response;
-//    use crate::response::StatusCode;
-//    use crate::response::gzip_min;
-//
-//    #[test]
-//    fn test_build_response() {
-//        let res = Response::new(StatusCode::_200)
-//            .header("hello", "world")
-//            .body(b"200");
-//    }
-//
-//}
+#[cfg(test)]
+mod tests {
+
+    use crate::response::{Response, StatusCode, Chunked, compress_bytes};
+    use crate::config::{CompressType, Cache};
+    use libflate::{gzip, zlib};
+    use std::io::prelude::*;
+    use std::fs::File;
+
+    fn split_response(res: &[u8]) -> (String, Vec<u8>) {
+        let end = res.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        (
+            String::from_utf8_lossy(&res[..end]).to_string(),
+            res[end + 4..].to_vec()
+        )
+    }
+
+    fn dechunk(mut body: &[u8]) -> Vec<u8> {
+        let mut data = vec![];
+        loop {
+            let line = body.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size = usize::from_str_radix(std::str::from_utf8(&body[..line]).unwrap(), 16).unwrap();
+            body = &body[line + 2..];
+            if size == 0 {
+                assert_eq!(body, b"\r\n");
+                return data;
+            }
+            data.extend_from_slice(&body[..size]);
+            assert_eq!(&body[size..size + 2], b"\r\n");
+            body = &body[size + 2..];
+        }
+    }
+
+    fn temp_file(name: &str, content: &[u8]) -> File {
+        let path = std::env::temp_dir().join(name);
+        File::create(&path).unwrap().write_all(content).unwrap();
+        File::open(&path).unwrap()
+    }
+
+    #[test]
+    fn test_chunked() {
+        let mut chunked = Chunked::new(vec![]);
+        chunked.write_all(b"hello").unwrap();
+        chunked.write_all(b"").unwrap();
+        chunked.write_all(&[b'x'; 16]).unwrap();
+        let body = chunked.finish().unwrap();
+        assert!(body.starts_with(b"5\r\nhello\r\n10\r\n"));
+        assert_eq!(dechunk(&body), [&b"hello"[..], &[b'x'; 16][..]].concat());
+    }
+
+    #[test]
+    fn test_compress_bytes() {
+        let text = "see ".repeat(1000);
+        let min = compress_bytes(CompressType::Gzip, text.as_bytes()).unwrap();
+        let mut out = String::new();
+        gzip::Decoder::new(&min[..]).unwrap().read_to_string(&mut out).unwrap();
+        assert_eq!(out, text);
+
+        let min = compress_bytes(CompressType::Deflate, text.as_bytes()).unwrap();
+        let mut out = String::new();
+        zlib::Decoder::new(&min[..]).unwrap().read_to_string(&mut out).unwrap();
+        assert_eq!(out, text);
+
+        let min = compress_bytes(CompressType::Br, text.as_bytes()).unwrap();
+        let mut out = String::new();
+        brotli::Decompressor::new(&min[..], 4096).read_to_string(&mut out).unwrap();
+        assert_eq!(out, text);
+
+        assert!(compress_bytes(CompressType::None, text.as_bytes()).is_err());
+    }
 
+    #[test]
+    fn test_file_gzip() {
+        let text = "<p>see</p>".repeat(2000);
+        let file = temp_file("see_test_file_gzip.html", text.as_bytes());
+        let mut res = vec![];
+        Response::new(StatusCode::_200, &[])
+            .content_type("html")
+            .compress(CompressType::Gzip)
+            .file(&mut res, file);
+        let (head, body) = split_response(&res);
+        assert!(head.contains("Content-Encoding: gzip"));
+        assert!(head.contains("Vary: Accept-Encoding"));
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert!(!head.contains("Content-Length"));
+        let mut out = String::new();
+        gzip::Decoder::new(&dechunk(&body)[..]).unwrap().read_to_string(&mut out).unwrap();
+        assert_eq!(out, text);
+    }
+
+    #[test]
+    fn test_file_identity() {
+        let file = temp_file("see_test_file_identity.txt", b"hello world");
+        let mut res = vec![];
+        Response::new(StatusCode::_200, &[])
+            .content_type("txt")
+            .file(&mut res, file);
+        let (head, body) = split_response(&res);
+        assert!(head.starts_with("HTTP/1.1 200\r\n"));
+        assert!(head.contains("Content-Length: 11"));
+        assert!(!head.contains("Content-Encoding"));
+        assert_eq!(body, b"hello world");
+    }
+
+    #[test]
+    fn test_html_br() {
+        let html = "<li>see</li>".repeat(500);
+        let res = Response::new(StatusCode::_200, &[])
+            .compress(CompressType::Br)
+            .html(html.clone());
+        let (head, body) = split_response(&res);
+        assert!(head.contains("Content-Encoding: br"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        let mut out = String::new();
+        brotli::Decompressor::new(&body[..], 4096).read_to_string(&mut out).unwrap();
+        assert_eq!(out, html);
+    }
+
+    #[test]
+    fn test_file_range() {
+        let file = temp_file("see_test_file_range.txt", b"0123456789");
+        let mut res = vec![];
+        Response::new(StatusCode::_206, &[])
+            .content_type("txt")
+            .ranges(vec![(2, 5)])
+            .file(&mut res, file);
+        let (head, body) = split_response(&res);
+        assert!(head.starts_with("HTTP/1.1 206\r\n"));
+        assert!(head.contains("Content-Range: bytes 2-5/10"));
+        assert!(head.contains("Content-Length: 4"));
+        assert_eq!(body, b"2345");
+    }
+
+    #[test]
+    fn test_file_multipart_ranges() {
+        let file = temp_file("see_test_file_multipart_ranges.txt", b"0123456789");
+        let mut res = vec![];
+        Response::new(StatusCode::_206, &[])
+            .content_type("txt")
+            .ranges(vec![(0, 1), (8, 9)])
+            .file(&mut res, file);
+        let (head, body) = split_response(&res);
+        let boundary = head
+            .split("multipart/byteranges; boundary=")
+            .nth(1)
+            .unwrap()
+            .split("\r\n")
+            .next()
+            .unwrap()
+            .to_string();
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            format!(
+                "--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+                --{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n\
+                --{0}--\r\n",
+                boundary
+            )
+        );
+    }
+
+    #[test]
+    fn test_cache() {
+        let expires = |control: &str| {
+            let cache = Cache::new("txt", control).unwrap();
+            let res = Response::new(StatusCode::_200, &[])
+                .cache(Some(&cache))
+                .text("");
+            let (head, _) = split_response(&res);
+            head.split("\r\n")
+                .find_map(|line| line.strip_prefix("Expires: "))
+                .map(|date| date.to_string())
+        };
+        assert_eq!(expires("no-store").unwrap(), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(expires("public"), None);
+        // Far in the future, but not past what a date can hold
+        let year = |date: String| date.split(' ').nth(3).unwrap().parse::<u32>().unwrap();
+        assert!(year(expires("max-age=18446744073709551615").unwrap()) < 2200);
+    }
+
+}
@@ -0,0 +1,99 @@
+use std::io;
+use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// Requests being answered when the server stops are given this long
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// No new connections, no more keep-alive
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+// Requests being answered on every listener
+static BUSY: AtomicUsize = AtomicUsize::new(0);
+
+pub enum Signal {
+    // SIGTERM, SIGINT
+    Stop,
+    // SIGHUP
+    Reload,
+    // SIGUSR1
+    Reopen
+}
+
+// The signals the process receives, in order
+#[cfg(unix)]
+pub fn signals() -> io::Result<impl Iterator<Item = Signal>> {
+
+    use signal_hook::consts::{SIGTERM, SIGINT, SIGHUP, SIGUSR1};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP, SIGUSR1])?;
+    Ok(std::iter::from_fn(move || {
+        signals.forever().next().map(|signal| match signal {
+            SIGHUP => Signal::Reload,
+            SIGUSR1 => Signal::Reopen,
+            _ => Signal::Stop
+        })
+    }))
+
+}
+
+#[cfg(not(unix))]
+pub fn signals() -> io::Result<std::iter::Empty<Signal>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "signals are not supported"))
+}
+
+pub fn stop() {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+// A request being answered, the server waits for it before stopping
+pub struct Busy(());
+
+pub fn busy() -> Busy {
+    BUSY.fetch_add(1, Ordering::SeqCst);
+    Busy(())
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        BUSY.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Wait for the requests being answered, false if some are still going at the deadline
+pub fn drain(timeout: Duration) -> bool {
+
+    let deadline = Instant::now() + timeout;
+    while BUSY.load(Ordering::SeqCst) != 0 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::signal::{busy, drain};
+    use std::time::Duration;
+
+    #[test]
+    fn test_drain() {
+        let request = busy();
+        assert!(!drain(Duration::from_millis(50)));
+        drop(request);
+        // Requests of other tests come and go
+        assert!(drain(Duration::from_secs(10)));
+    }
+
+}
@@ -0,0 +1,74 @@
+use std::net::{SocketAddr, TcpStream, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use crate::config::ServerConfig;
+use crate::tls;
+
+// The servers of one address, replaced as a whole on reload
+pub struct Site {
+    pub configs: Arc<Vec<ServerConfig>>,
+    pub tls: Option<Arc<rustls::ServerConfig>>
+}
+
+impl Site {
+
+    pub fn new(configs: Arc<Vec<ServerConfig>>) -> Result<Arc<Site>, String> {
+        let tls = tls::server_config(&configs)?;
+        Ok(Arc::new(Site {
+            configs,
+            tls
+        }))
+    }
+
+}
+
+// An address being listened on.
+// A connection keeps the site it was accepted with until it ends
+pub struct Address {
+    // Where the listener is bound
+    pub local: SocketAddr,
+    site: RwLock<Arc<Site>>,
+    closed: AtomicBool
+}
+
+impl Address {
+
+    pub fn new(local: SocketAddr, site: Arc<Site>) -> Arc<Address> {
+        Arc::new(Address {
+            local,
+            site: RwLock::new(site),
+            closed: AtomicBool::new(false)
+        })
+    }
+
+    pub fn site(&self) -> Arc<Site> {
+        self.site.read().unwrap().clone()
+    }
+
+    // For the connections accepted from now on
+    pub fn replace(&self, site: Arc<Site>) {
+        *self.site.write().unwrap() = site;
+    }
+
+    // Stop accepting, a listener waiting in accept() is woken up with a connection
+    pub fn close(&self) {
+
+        self.closed.store(true, Ordering::SeqCst);
+
+        let mut local = self.local;
+        if local.ip().is_unspecified() {
+            match local {
+                SocketAddr::V4(_) => local.set_ip(Ipv4Addr::LOCALHOST.into()),
+                SocketAddr::V6(_) => local.set_ip(Ipv6Addr::LOCALHOST.into())
+            }
+        }
+        let _ = TcpStream::connect_timeout(&local, Duration::from_secs(1));
+
+    }
+
+    pub fn closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+}
@@ -0,0 +1,205 @@
+use std::env;
+use std::io;
+use std::thread;
+use std::process;
+use std::net::{TcpListener, SocketAddr};
+use std::time::Duration;
+use crate::config::listen_address;
+
+// The first socket passed by the service manager
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+// A listening socket opened by systemd, named with FileDescriptorName=
+pub struct Inherited {
+    pub name: String,
+    pub listener: TcpListener
+}
+
+// The sockets of a socket unit (LISTEN_FDS), meant for this process only
+#[cfg(unix)]
+pub fn inherited() -> Vec<Inherited> {
+
+    use std::os::unix::io::FromRawFd;
+
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<i32>().ok());
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    // Not passed on to anything started from here
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let fds = match (pid, fds) {
+        (Some(pid), Some(fds)) if pid == process::id() => fds,
+        _ => return vec![]
+    };
+    let names = names.split(':').collect::<Vec<&str>>();
+
+    let mut sockets = vec![];
+    for (i, fd) in (LISTEN_FDS_START..LISTEN_FDS_START + fds).enumerate() {
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        // Only TCP sockets have an address like that
+        if let Err(err) = listener.local_addr() {
+            eprintln!("Socket {} from systemd isn't usable, {}", fd, err);
+            continue;
+        }
+        sockets.push(Inherited {
+            name: names.get(i).unwrap_or(&"").to_string(),
+            listener
+        });
+    }
+    sockets
+
+}
+
+#[cfg(not(unix))]
+pub fn inherited() -> Vec<Inherited> {
+    vec![]
+}
+
+// The socket for a "listen" entry, by its name ("8080", "127.0.0.1:8080") or else its address
+pub fn take(sockets: &mut Vec<Inherited>, listen: SocketAddr) -> Option<io::Result<TcpListener>> {
+
+    let found = sockets.iter().position(|socket| listen_address(&socket.name) == Some(listen))
+        .or_else(|| sockets.iter().position(|socket| socket.listener.local_addr().ok() == Some(listen)))?;
+    let listener = sockets.remove(found).listener;
+    // The engines set it as they need
+    Some(listener.set_nonblocking(false).map(|_| listener))
+
+}
+
+// Tell systemd about the state of the service (NOTIFY_SOCKET), if it's listening
+pub fn notify(state: &str) {
+    if let Ok(path) = env::var("NOTIFY_SOCKET") {
+        if let Err(err) = send(&path, state) {
+            eprintln!("Notifying systemd failed, {}", err);
+        }
+    }
+}
+
+pub fn ready() {
+    notify(&format!("READY=1\nMAINPID={}", process::id()));
+}
+
+// Type=notify-reload also needs the time it started
+pub fn reloading() {
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic_usec()));
+}
+
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+// Keep-alive pings at half of WatchdogSec=
+pub fn watchdog() {
+
+    let usec = env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok());
+    let pid = env::var("WATCHDOG_PID").ok().map(|pid| pid.parse::<u32>().ok());
+    let usec = match (usec, pid) {
+        (Some(usec), None) => usec,
+        (Some(usec), Some(Some(pid))) if pid == process::id() => usec,
+        _ => return
+    };
+    if usec == 0 {
+        return;
+    }
+
+    let interval = Duration::from_micros(usec / 2);
+    thread::spawn(move || loop {
+        notify("WATCHDOG=1");
+        thread::sleep(interval);
+    });
+
+}
+
+#[cfg(unix)]
+fn send(path: &str, state: &str) -> io::Result<()> {
+
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    // An abstract socket on Linux
+    if let Some(name) = path.strip_prefix('@') {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+            return Ok(());
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        return Err(io::Error::new(io::ErrorKind::Unsupported, format!("abstract socket @{}", name)));
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+
+}
+
+#[cfg(not(unix))]
+fn send(_: &str, _: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "NOTIFY_SOCKET is not supported"))
+}
+
+#[cfg(unix)]
+fn monotonic_usec() -> u64 {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
+    }
+    time.tv_sec as u64 * 1_000_000 + time.tv_nsec as u64 / 1_000
+}
+
+#[cfg(not(unix))]
+fn monotonic_usec() -> u64 {
+    0
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::systemd::{take, Inherited};
+    use std::net::{TcpListener, SocketAddr};
+
+    #[test]
+    fn test_take() {
+        let socket = |name: &str| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            Inherited { name: name.to_string(), listener }
+        };
+        let mut sockets = vec![socket("web"), socket("8080"), socket("")];
+        let by_address = sockets[0].listener.local_addr().unwrap();
+
+        let listener = take(&mut sockets, SocketAddr::from(([0, 0, 0, 0], 8080))).unwrap().unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), 8080);
+        assert_eq!(take(&mut sockets, by_address).unwrap().unwrap().local_addr().unwrap(), by_address);
+        assert!(take(&mut sockets, SocketAddr::from(([127, 0, 0, 1], 8081))).is_none());
+        assert_eq!(sockets.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_notify() {
+        use crate::systemd::send;
+        use std::os::unix::net::UnixDatagram;
+        use std::{env, fs};
+
+        let path = env::temp_dir().join("see_test_notify.sock");
+        let _ = fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+
+        send(path.to_str().unwrap(), "READY=1").unwrap();
+        send(path.to_str().unwrap(), "STOPPING=1").unwrap();
+        let mut buf = [0; 64];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"STOPPING=1");
+        let _ = fs::remove_file(&path);
+    }
+
+}
@@ -0,0 +1,127 @@
+extern crate rustls;
+extern crate rustls_pemfile;
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::collections::HashMap;
+use rustls::crypto::ring;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use crate::config::ServerConfig;
+
+// Load a PEM certificate chain and its private key
+pub fn certified_key(cert: &str, key: &str) -> Result<Arc<CertifiedKey>, String> {
+
+    let file = match File::open(cert) {
+        Ok(file) => file,
+        Err(err) => {
+            return Err(format!("{} {}", cert, err));
+        }
+    };
+    let mut certs = vec![];
+    for item in rustls_pemfile::certs(&mut BufReader::new(file)) {
+        match item {
+            Ok(item) => certs.push(item),
+            Err(err) => {
+                return Err(format!("{} {}", cert, err));
+            }
+        }
+    }
+    if certs.is_empty() {
+        return Err(format!("{} has no certificate", cert));
+    }
+
+    let file = match File::open(key) {
+        Ok(file) => file,
+        Err(err) => {
+            return Err(format!("{} {}", key, err));
+        }
+    };
+    let der = match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+        Ok(Some(der)) => der,
+        Ok(None) => {
+            return Err(format!("{} has no private key", key));
+        },
+        Err(err) => {
+            return Err(format!("{} {}", key, err));
+        }
+    };
+    let signing = match ring::sign::any_supported_type(&der) {
+        Ok(signing) => signing,
+        Err(err) => {
+            return Err(format!("{} {}", key, err));
+        }
+    };
+
+    Ok(Arc::new(CertifiedKey::new(certs, signing)))
+
+}
+
+// Select the certificate by the server name the client sent (SNI),
+// servers without "host" and clients without SNI get the default one
+#[derive(Debug)]
+struct Resolver {
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>
+}
+
+impl ResolvesServerCert for Resolver {
+
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        if let Some(name) = hello.server_name() {
+            if let Some(key) = self.hosts.get(&name.to_lowercase()) {
+                return Some(key.clone());
+            }
+        }
+        Some(self.default.clone())
+    }
+
+}
+
+// TLS settings of the servers sharing a port, None if the port is plain HTTP
+pub fn server_config(configs: &[ServerConfig]) -> Result<Option<Arc<rustls::ServerConfig>>, String> {
+
+    let mut hosts = HashMap::new();
+    // Like the Host header, a server without "host" takes the other names
+    let (mut first, mut default) = (None, None);
+
+    for config in configs {
+        let tls = match &config.tls {
+            Some(tls) => tls,
+            None => continue
+        };
+        match &config.hosts {
+            Some(names) => {
+                for name in names {
+                    hosts.entry(name.to_lowercase())
+                        .or_insert_with(|| tls.certified_key.clone());
+                }
+                if first.is_none() {
+                    first = Some(tls.certified_key.clone());
+                }
+            },
+            None => {
+                if default.is_none() {
+                    default = Some(tls.certified_key.clone());
+                }
+            }
+        }
+    }
+
+    let resolver = match default.or(first) {
+        Some(default) => Resolver { hosts, default },
+        None => return Ok(None)
+    };
+
+    let builder = rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| format!("TLS of {} failed, {}", configs[0].listen, err))?;
+    let mut tls = builder
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(Some(Arc::new(tls)))
+
+}
@@ -0,0 +1,257 @@
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+use chrono::{DateTime, Utc};
+use crate::config::{CompressType, ETagType};
+use crate::request::Request;
+use crate::response::StatusCode;
+
+// IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+// Validators of a file, ETag and Last-Modified (RFC 7232)
+#[derive(Debug, Clone)]
+pub struct Validator {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    modified: Option<u64>
+}
+
+impl Validator {
+
+    // The entity tag is derived from the size, modification time and inode of the file
+    pub fn new(meta: &Metadata, kind: ETagType) -> Validator {
+
+        let modified = meta.modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+
+        let mut etag = format!("{:x}", meta.len());
+        if let Some(time) = modified {
+            etag.push_str(&format!("-{:x}.{:x}", time.as_secs(), time.subsec_nanos()));
+        }
+        if let Some(inode) = inode(meta) {
+            etag.push_str(&format!("-{:x}", inode));
+        }
+
+        let etag = match kind {
+            ETagType::Strong => Some(format!("\"{}\"", etag)),
+            ETagType::Weak => Some(format!("W/\"{}\"", etag)),
+            ETagType::None => None
+        };
+
+        Validator {
+            etag,
+            last_modified: modified.map(|time| http_date(UNIX_EPOCH + time)),
+            modified: modified.map(|time| time.as_secs())
+        }
+
+    }
+
+    // A compressed representation needs its own entity tag
+    pub fn encoded(&self, mode: CompressType) -> Validator {
+        let etag = match (&self.etag, mode.encoding()) {
+            (Some(etag), Some(encoding)) => Some(format!("{}-{}\"", &etag[..etag.len() - 1], encoding)),
+            (etag, _) => etag.clone()
+        };
+        Validator {
+            etag,
+            last_modified: self.last_modified.clone(),
+            modified: self.modified
+        }
+    }
+
+    // The file is unchanged since the client got the value of If-Range,
+    // only strong validators qualify (RFC 7233 3.2)
+    pub fn if_range(&self, value: &str) -> bool {
+
+        let value = value.trim();
+        if value.starts_with("\"") {
+            return self.etag.as_deref() == Some(value);
+        }
+        if value.starts_with("W/") {
+            return false;
+        }
+        match (parse_http_date(value), self.modified) {
+            (Some(date), Some(modified)) => date == modified,
+            _ => false
+        }
+
+    }
+
+    // Evaluate the preconditions in the order of RFC 7232 6,
+    // Some(304) or Some(412) if the request should not be served as usual
+    pub fn condition(&self, request: &Request) -> Option<StatusCode> {
+
+        let headers = &request.headers;
+        let safe = request.method == "GET" || request.method == "HEAD";
+
+        if let Some(value) = headers.get("if-match") {
+            if !self.matches(value, true) {
+                return Some(StatusCode::_412);
+            }
+        }else if let Some(value) = headers.get("if-unmodified-since") {
+            if let (Some(date), Some(modified)) = (parse_http_date(value), self.modified) {
+                if modified > date {
+                    return Some(StatusCode::_412);
+                }
+            }
+        }
+
+        if let Some(value) = headers.get("if-none-match") {
+            if self.matches(value, false) {
+                return Some(if safe { StatusCode::_304 } else { StatusCode::_412 });
+            }
+        }else if safe {
+            if let Some(value) = headers.get("if-modified-since") {
+                if let (Some(date), Some(modified)) = (parse_http_date(value), self.modified) {
+                    if modified <= date {
+                        return Some(StatusCode::_304);
+                    }
+                }
+            }
+        }
+
+        None
+
+    }
+
+    // Compare with a list of entity tags, weak comparison ignores the W/ prefix
+    fn matches(&self, value: &str, strong: bool) -> bool {
+
+        // The file exists
+        if value.trim() == "*" {
+            return true;
+        }
+        let etag = match &self.etag {
+            Some(etag) => etag,
+            None => return false
+        };
+
+        value.split(',').any(|tag| {
+            let tag = tag.trim();
+            if strong {
+                !tag.starts_with("W/") && !etag.starts_with("W/") && tag == etag
+            }else {
+                tag.trim_start_matches("W/") == etag.trim_start_matches("W/")
+            }
+        })
+
+    }
+
+}
+
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.ino())
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &Metadata) -> Option<u64> {
+    None
+}
+
+pub fn http_date(time: SystemTime) -> String {
+    let datetime: DateTime<Utc> = DateTime::from(time);
+    datetime.format(HTTP_DATE).to_string()
+}
+
+// Seconds since the epoch
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    match DateTime::parse_from_rfc2822(value.trim()) {
+        Ok(date) if date.timestamp() >= 0 => Some(date.timestamp() as u64),
+        _ => None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::validator::{Validator, http_date, parse_http_date};
+    use crate::config::{CompressType, ETagType};
+    use crate::request::Request;
+    use crate::response::StatusCode;
+    use std::time::{UNIX_EPOCH, Duration};
+    use std::fs;
+
+    #[test]
+    fn test_http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_validator() {
+        let path = std::env::temp_dir().join("see_test_validator.txt");
+        fs::write(&path, "hello").unwrap();
+        let validator = Validator::new(&fs::metadata(&path).unwrap(), ETagType::Strong);
+        let etag = validator.etag.clone().unwrap();
+        let last_modified = validator.last_modified.clone().unwrap();
+
+        assert!(etag.starts_with("\"5-"));
+        assert!(validator.if_range(&etag));
+        assert!(!validator.if_range(&format!("W/{}", etag)));
+        assert!(!validator.if_range("\"abc\""));
+        assert!(validator.if_range(&last_modified));
+        assert!(!validator.if_range("Sun, 06 Nov 1994 08:49:37 GMT"));
+
+        let gzip = validator.encoded(CompressType::Gzip);
+        assert_eq!(gzip.etag.unwrap(), format!("{}-gzip\"", &etag[..etag.len() - 1]));
+        assert_eq!(validator.encoded(CompressType::None).etag.unwrap(), etag);
+
+        let weak = Validator::new(&fs::metadata(&path).unwrap(), ETagType::Weak);
+        assert_eq!(weak.etag.clone().unwrap(), format!("W/{}", etag));
+        assert!(!weak.if_range(&weak.etag.clone().unwrap()));
+        assert_eq!(Validator::new(&fs::metadata(&path).unwrap(), ETagType::None).etag, None);
+
+        fs::write(&path, "hello world").unwrap();
+        let changed = Validator::new(&fs::metadata(&path).unwrap(), ETagType::Strong);
+        assert!(!changed.if_range(&etag));
+    }
+
+    fn condition(validator: &Validator, method: &str, header: &str) -> Option<StatusCode> {
+        let buff = format!("{} / HTTP/1.1\r\n{}\r\n\r\n", method, header);
+        validator.condition(&Request::new(buff.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn test_condition() {
+        let path = std::env::temp_dir().join("see_test_condition.txt");
+        fs::write(&path, "hello").unwrap();
+        let strong = Validator::new(&fs::metadata(&path).unwrap(), ETagType::Strong);
+        let weak = Validator::new(&fs::metadata(&path).unwrap(), ETagType::Weak);
+        let etag = strong.etag.clone().unwrap();
+        let date = strong.last_modified.clone().unwrap();
+        let old = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+        assert_eq!(condition(&strong, "GET", "Accept: */*"), None);
+
+        assert_eq!(condition(&strong, "GET", &format!("If-None-Match: {}", etag)), Some(StatusCode::_304));
+        assert_eq!(condition(&strong, "HEAD", &format!("If-None-Match: \"a\", W/{}", etag)), Some(StatusCode::_304));
+        assert_eq!(condition(&weak, "GET", &format!("If-None-Match: {}", etag)), Some(StatusCode::_304));
+        assert_eq!(condition(&strong, "GET", "If-None-Match: *"), Some(StatusCode::_304));
+        assert_eq!(condition(&strong, "GET", "If-None-Match: \"a\""), None);
+        assert_eq!(condition(&strong, "PUT", &format!("If-None-Match: {}", etag)), Some(StatusCode::_412));
+
+        assert_eq!(condition(&strong, "GET", &format!("If-Modified-Since: {}", date)), Some(StatusCode::_304));
+        assert_eq!(condition(&strong, "GET", &format!("If-Modified-Since: {}", old)), None);
+        assert_eq!(condition(&strong, "GET", "If-Modified-Since: garbage"), None);
+        assert_eq!(condition(&strong, "POST", &format!("If-Modified-Since: {}", date)), None);
+        // If-None-Match takes precedence over If-Modified-Since
+        assert_eq!(condition(&strong, "GET", &format!("If-None-Match: \"a\"\r\nIf-Modified-Since: {}", date)), None);
+
+        assert_eq!(condition(&strong, "PUT", &format!("If-Match: {}", etag)), None);
+        assert_eq!(condition(&strong, "PUT", "If-Match: *"), None);
+        assert_eq!(condition(&strong, "PUT", "If-Match: \"a\""), Some(StatusCode::_412));
+        assert_eq!(condition(&weak, "PUT", &format!("If-Match: {}", weak.etag.clone().unwrap())), Some(StatusCode::_412));
+        assert_eq!(condition(&strong, "DELETE", &format!("If-Unmodified-Since: {}", date)), None);
+        assert_eq!(condition(&strong, "DELETE", &format!("If-Unmodified-Since: {}", old)), Some(StatusCode::_412));
+        // If-Match takes precedence over If-Unmodified-Since
+        assert_eq!(condition(&strong, "PUT", &format!("If-Match: {}\r\nIf-Unmodified-Since: {}", etag, old)), None);
+    }
+
+}